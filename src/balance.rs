@@ -0,0 +1,300 @@
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use image::Rgb;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::base;
+use crate::conversion::{Bounds, InputImage, RgbBuffer, Sample, crop_frame};
+use crate::histogram::histogram_rgb;
+use crate::tone::{srgb_decode, srgb_encode};
+
+/// Rec. 709 luminance of linear values
+const LUMA_WEIGHTS: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+/// Gain of the red and blue channels at a temperature of ±100, in stops
+const MAX_TEMPERATURE_STOPS: f32 = 0.5;
+/// Gain of the green channel at a tint of ±100, in stops
+const MAX_TINT_STOPS: f32 = 0.5;
+const MAX_GAIN: f32 = 8.0;
+
+/// Size of the area sampled around a neutral point, as a share of the shorter
+/// side of the image
+const NEUTRAL_SAMPLE_PCT: f32 = 0.005;
+
+/// Share of the brightest (unclipped) values that white patch balancing ignores
+const WHITE_PATCH_PCT: f32 = 0.005;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AutoBalance {
+    #[default]
+    None,
+    /// Balances the average color of the frame to gray
+    GrayWorld,
+    /// Balances the brightest color of the frame to white
+    WhitePatch,
+}
+
+/// White balance after inversion, for casts left over by the film backing
+/// balance
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BalanceOptions {
+    /// From -100 (cooler) to 100 (warmer)
+    pub temperature: f32,
+    /// From -100 (greener) to 100 (more magenta)
+    pub tint: f32,
+    /// Point of the output that should be neutral gray. Replaces `auto`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub neutral_point: Option<Point>,
+    pub auto: AutoBalance,
+}
+
+impl BalanceOptions {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A point given as `x,y`. If both values are at most 1, they are fractions of
+/// the image's width and height. Otherwise, they are pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(from = "[f32; 2]", into = "[f32; 2]")]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Point {
+    /// Resolves the point to pixels, clamped to the image
+    pub fn to_pixels(&self, width: u32, height: u32) -> (u32, u32) {
+        let (x, y) = if self.x <= 1.0 && self.y <= 1.0 {
+            (self.x * width as f32, self.y * height as f32)
+        } else {
+            (self.x, self.y)
+        };
+        (
+            (x.max(0.0) as u32).min(width.saturating_sub(1)),
+            (y.max(0.0) as u32).min(height.saturating_sub(1)),
+        )
+    }
+
+    /// The point in the image scaled by `factor`. Fractions stay the same.
+    pub fn scaled(&self, factor: f32) -> Self {
+        if self.x <= 1.0 && self.y <= 1.0 {
+            return *self;
+        }
+        Self {
+            x: self.x * factor,
+            y: self.y * factor,
+        }
+    }
+}
+
+impl From<[f32; 2]> for Point {
+    fn from([x, y]: [f32; 2]) -> Self {
+        Self { x, y }
+    }
+}
+
+impl From<Point> for [f32; 2] {
+    fn from(point: Point) -> Self {
+        [point.x, point.y]
+    }
+}
+
+impl FromStr for Point {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<f32> = s
+            .split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("invalid point \"{}\": {}", s, e))?;
+
+        match values[..] {
+            [x, y] if x >= 0.0 && y >= 0.0 => Ok(Self { x, y }),
+            _ => Err(format!(
+                "invalid point \"{}\": expected two non-negative values as x,y",
+                s
+            )),
+        }
+    }
+}
+
+/// Finds the gain of each channel, applied to linear values. The neutral point
+/// refers to all of `image`, and automatic balancing is measured from `frame`.
+pub fn find_gains(image: &InputImage, frame: Bounds, options: &BalanceOptions) -> Option<[f32; 3]> {
+    if options.is_identity() {
+        return None;
+    }
+
+    let neutral = match (options.neutral_point, options.auto) {
+        (Some(point), _) => Some(sample_point(image, point)),
+        (None, AutoBalance::GrayWorld) => gray_world(&crop_frame(image, frame)),
+        (None, AutoBalance::WhitePatch) => Some(white_patch(&crop_frame(image, frame))),
+        (None, AutoBalance::None) => Some([1.0; 3]),
+    };
+    let neutral_gains = match neutral {
+        Some(color) if color.iter().all(|&v| v > 0.0) => {
+            let luma = luminance(color);
+            color.map(|v| luma / v)
+        }
+        _ => {
+            println!("Warning: unable to find a neutral color, only applying temperature and tint");
+            [1.0; 3]
+        }
+    };
+
+    let warm = options.temperature / 100.0 * MAX_TEMPERATURE_STOPS;
+    let magenta = options.tint / 100.0 * MAX_TINT_STOPS;
+    let shift = [warm.exp2(), (-magenta).exp2(), (-warm).exp2()];
+    let shift_luma = luminance(shift);
+
+    Some([0, 1, 2].map(|channel| {
+        (neutral_gains[channel] * shift[channel] / shift_luma).clamp(1.0 / MAX_GAIN, MAX_GAIN)
+    }))
+}
+
+pub fn apply_gains_mut<S: Sample>(image: &mut RgbBuffer<S>, gains: [f32; 3]) {
+    S::map_channels_mut(image, |channel, value| {
+        srgb_encode(srgb_decode(value) * gains[channel])
+    });
+}
+
+fn luminance(color: [f32; 3]) -> f32 {
+    (0..3)
+        .map(|channel| LUMA_WEIGHTS[channel] * color[channel])
+        .sum()
+}
+
+fn linear(color: Rgb<u16>) -> [f32; 3] {
+    color.0.map(|v| srgb_decode(v as f32 / u16::MAX as f32))
+}
+
+/// Robust average of a small area around `point`
+fn sample_point(image: &InputImage, point: Point) -> [f32; 3] {
+    let (width, height) = image.dimensions();
+    let (x, y) = point.to_pixels(width, height);
+    let radius = ((width.min(height) as f32 * NEUTRAL_SAMPLE_PCT) as u32).max(2);
+    let bounds = [
+        x.saturating_sub(radius),
+        y.saturating_sub(radius),
+        (x + radius + 1).min(width),
+        (y + radius + 1).min(height),
+    ];
+    linear(base::sample_region(image, bounds))
+}
+
+/// Average linear color of every pixel that isn't clipped
+fn gray_world(frame: &InputImage) -> Option<[f32; 3]> {
+    let (sum, count) = frame
+        .par_pixels()
+        .filter(|p| p.0.iter().all(|&v| v > 0 && v < u16::MAX))
+        .map(|p| (linear(*p).map(|v| v as f64), 1_usize))
+        .reduce(
+            || ([0.0; 3], 0),
+            |(a, count_a), (b, count_b)| {
+                ([a[0] + b[0], a[1] + b[1], a[2] + b[2]], count_a + count_b)
+            },
+        );
+
+    (count > 0).then(|| sum.map(|v| (v / count as f64) as f32))
+}
+
+/// Brightest value of each channel, ignoring clipped values and a few of the
+/// brightest ones, e.g. specular highlights
+fn white_patch(frame: &InputImage) -> [f32; 3] {
+    let hist = histogram_rgb(frame, u16::MAX as usize + 1);
+    [0, 1, 2].map(|channel| {
+        let unclipped = &hist[channel][..u16::MAX as usize];
+        let total: usize = unclipped.iter().sum();
+        let skip = (total as f32 * WHITE_PATCH_PCT) as usize;
+
+        let mut count = 0;
+        let value = unclipped
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|&(_, &bin)| {
+                count += bin;
+                bin > 0 && count > skip
+            })
+            .map(|(value, _)| value)
+            .unwrap_or(0);
+        srgb_decode(value as f32 / u16::MAX as f32)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_points() {
+        assert_eq!(
+            "0.5, 0.25".parse::<Point>().unwrap(),
+            Point { x: 0.5, y: 0.25 }
+        );
+        assert!("0.5".parse::<Point>().is_err());
+        assert!("1,2,3".parse::<Point>().is_err());
+        assert!("-1,2".parse::<Point>().is_err());
+        assert!("left,2".parse::<Point>().is_err());
+    }
+
+    #[test]
+    fn resolves_fractions_and_pixels() {
+        assert_eq!(Point { x: 0.5, y: 0.25 }.to_pixels(400, 200), (200, 50));
+        assert_eq!(Point { x: 1.0, y: 1.0 }.to_pixels(400, 200), (399, 199));
+        assert_eq!(Point { x: 30.0, y: 40.0 }.to_pixels(400, 200), (30, 40));
+        assert_eq!(Point { x: 500.0, y: 40.0 }.to_pixels(400, 200), (399, 40));
+    }
+
+    #[test]
+    fn scales_pixels_but_not_fractions() {
+        let fraction = Point { x: 0.5, y: 0.25 };
+        assert_eq!(fraction.scaled(0.5), fraction);
+        assert_eq!(
+            Point { x: 30.0, y: 40.0 }.scaled(0.5),
+            Point { x: 15.0, y: 20.0 }
+        );
+    }
+
+    #[test]
+    fn identity_has_no_gains() {
+        let image = InputImage::new(8, 8);
+        assert!(find_gains(&image, (0, 0, 8, 8), &BalanceOptions::default()).is_none());
+    }
+
+    #[test]
+    fn temperature_keeps_luminance() {
+        let image = InputImage::new(8, 8);
+        let options = BalanceOptions {
+            temperature: 50.0,
+            ..BalanceOptions::default()
+        };
+        let gains = find_gains(&image, (0, 0, 8, 8), &options).unwrap();
+        assert!(gains[0] > 1.0 && gains[2] < 1.0);
+        assert!((luminance(gains) - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn neutral_point_becomes_gray() {
+        let image = InputImage::from_pixel(64, 64, Rgb([40000, 30000, 20000]));
+        let options = BalanceOptions {
+            neutral_point: Some(Point { x: 0.5, y: 0.5 }),
+            ..BalanceOptions::default()
+        };
+        let gains = find_gains(&image, (0, 0, 64, 64), &options).unwrap();
+        let balanced: Vec<f32> = linear(Rgb([40000, 30000, 20000]))
+            .iter()
+            .zip(gains)
+            .map(|(v, gain)| v * gain)
+            .collect();
+        assert!((balanced[0] - balanced[1]).abs() < 1e-3);
+        assert!((balanced[1] - balanced[2]).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,374 @@
+use std::str::FromStr;
+
+use image::{GenericImageView, Rgb};
+use serde::{Deserialize, Serialize};
+
+use crate::conversion::InputImage;
+
+/// Share of the lowest and highest values dropped from each channel before averaging
+const TRIM_PCT: f32 = 0.1;
+
+/// Values further than this many (scaled) median absolute deviations from the
+/// median are treated as outliers, e.g. dust or rebate text
+const OUTLIER_MADS: f32 = 3.0;
+
+/// Number of color clusters that candidate border pixels are split into. The
+/// largest one is assumed to be the film backing.
+const CLUSTERS: usize = 3;
+const CLUSTER_ITERATIONS: usize = 10;
+const MAX_CLUSTER_SAMPLES: usize = 4096;
+
+/// Spread of the backing color, relative to its brightness, at which the
+/// confidence drops to 0
+const MAX_RELATIVE_SPREAD: f32 = 0.1;
+
+/// Estimated color of the film backing
+#[derive(Clone, Debug)]
+pub struct Estimate {
+    pub color: Rgb<u16>,
+    /// From 0 to 1. Low when the candidate pixels disagree, e.g. when the
+    /// border is narrow, or covered with edge markings or light leaks.
+    pub confidence: f32,
+    /// Indices of the candidate colors that the estimate is based on
+    pub inliers: Vec<usize>,
+}
+
+/// A rectangle given as `x,y,width,height`. If every value is at most 1, the
+/// values are fractions of the image's width and height. Otherwise, they are
+/// pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(from = "[f32; 4]", into = "[f32; 4]")]
+pub struct Region {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Region {
+    fn is_fractional(&self) -> bool {
+        [self.x, self.y, self.width, self.height]
+            .iter()
+            .all(|&v| v <= 1.0)
+    }
+
+    /// Resolves the region to `[min_x, min_y, max_x, max_y]` pixels, clamped
+    /// to the image
+    pub fn to_bounds(&self, width: u32, height: u32) -> [u32; 4] {
+        let (scale_x, scale_y) = if self.is_fractional() {
+            (width as f32, height as f32)
+        } else {
+            (1.0, 1.0)
+        };
+
+        let min_x = (self.x * scale_x).max(0.0) as u32;
+        let min_y = (self.y * scale_y).max(0.0) as u32;
+        let max_x = ((self.x + self.width) * scale_x).max(0.0) as u32;
+        let max_y = ((self.y + self.height) * scale_y).max(0.0) as u32;
+
+        [
+            min_x.min(width),
+            min_y.min(height),
+            max_x.min(width),
+            max_y.min(height),
+        ]
+    }
+
+    /// The region in the image scaled by `factor`. Fractions stay the same.
+    pub fn scaled(&self, factor: f32) -> Self {
+        if self.is_fractional() {
+            return *self;
+        }
+        Self {
+            x: self.x * factor,
+            y: self.y * factor,
+            width: self.width * factor,
+            height: self.height * factor,
+        }
+    }
+}
+
+impl From<[f32; 4]> for Region {
+    fn from([x, y, width, height]: [f32; 4]) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+impl From<Region> for [f32; 4] {
+    fn from(region: Region) -> Self {
+        [region.x, region.y, region.width, region.height]
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<f32> = s
+            .split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("invalid region \"{}\": {}", s, e))?;
+
+        match values[..] {
+            [x, y, width, height] if values.iter().all(|&v| v >= 0.0) => Ok(Self {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(format!(
+                "invalid region \"{}\": expected four non-negative values as x,y,width,height",
+                s
+            )),
+        }
+    }
+}
+
+/// Measures the film backing color inside `[min_x, min_y, max_x, max_y]`
+pub fn sample_region(img: &InputImage, [min_x, min_y, max_x, max_y]: [u32; 4]) -> Rgb<u16> {
+    let region = img.view(min_x, min_y, max_x - min_x, max_y - min_y);
+    robust_average(region.pixels().map(|(_, _, p)| p).collect())
+}
+
+/// Estimates the film backing color from candidate border pixels.
+///
+/// Candidates are clustered by color, and the largest cluster is averaged with
+/// `robust_average`. Returns `None` if there are no candidates.
+pub fn estimate(colors: &[Rgb<u16>]) -> Option<Estimate> {
+    if colors.is_empty() {
+        return None;
+    }
+
+    let step = colors.len().div_ceil(MAX_CLUSTER_SAMPLES);
+    let samples: Vec<usize> = (0..colors.len()).step_by(step).collect();
+    let points: Vec<[f32; 3]> = samples
+        .iter()
+        .map(|&i| colors[i].0.map(|v| v as f32))
+        .collect();
+
+    let centers = cluster_centers(&points, CLUSTERS);
+    let labels: Vec<usize> = points.iter().map(|p| nearest(&centers, p)).collect();
+
+    let mut sizes = vec![0; centers.len()];
+    for &label in labels.iter() {
+        sizes[label] += 1;
+    }
+    let largest = (0..centers.len()).max_by_key(|&i| sizes[i])?;
+
+    let members: Vec<usize> = samples
+        .iter()
+        .zip(labels.iter())
+        .filter(|&(_, &label)| label == largest)
+        .map(|(&i, _)| i)
+        .collect();
+    let member_colors: Vec<Rgb<u16>> = members.iter().map(|&i| colors[i]).collect();
+
+    let inliers: Vec<usize> = inlier_indices(&member_colors)
+        .into_iter()
+        .map(|i| members[i])
+        .collect();
+    let color = average(inliers.iter().map(|&i| &colors[i]).collect());
+
+    let share = inliers.len() as f32 / samples.len() as f32;
+    let spread = (inliers
+        .iter()
+        .map(|&i| distance_squared(&colors[i].0.map(|v| v as f32), &color.0.map(|v| v as f32)))
+        .sum::<f32>()
+        / inliers.len() as f32)
+        .sqrt();
+    let brightness = color.0.iter().map(|&v| v as f32).sum::<f32>() / 3.0;
+    let relative_spread = spread / brightness.max(1.0);
+
+    Some(Estimate {
+        color,
+        confidence: share * (1.0 - (relative_spread / MAX_RELATIVE_SPREAD).min(1.0)),
+        inliers,
+    })
+}
+
+/// Averages colors after rejecting outliers, which is less sensitive to dust,
+/// light leaks and edge markings than a plain mean.
+///
+/// Colors that are far from the per-channel median are rejected first, and a
+/// trimmed mean is taken of the remaining values.
+pub fn robust_average(colors: Vec<Rgb<u16>>) -> Rgb<u16> {
+    if colors.is_empty() {
+        return Rgb([0, 0, 0]);
+    }
+
+    let inliers = inlier_indices(&colors);
+    if inliers.is_empty() {
+        return Rgb(medians(&colors).map(|m| m as u16));
+    }
+
+    average(inliers.iter().map(|&i| &colors[i]).collect())
+}
+
+/// Trimmed mean of each channel
+fn average(colors: Vec<&Rgb<u16>>) -> Rgb<u16> {
+    Rgb([0, 1, 2].map(|channel| {
+        let mut values: Vec<u16> = colors.iter().map(|c| c.0[channel]).collect();
+        trimmed_mean_mut(&mut values)
+    }))
+}
+
+fn medians(colors: &[Rgb<u16>]) -> [f32; 3] {
+    [0, 1, 2].map(|channel| {
+        let mut values: Vec<f32> = colors.iter().map(|c| c.0[channel] as f32).collect();
+        median_mut(&mut values)
+    })
+}
+
+/// Indices of the colors that are within `OUTLIER_MADS` of the median in every channel
+fn inlier_indices(colors: &[Rgb<u16>]) -> Vec<usize> {
+    if colors.is_empty() {
+        return vec![];
+    }
+
+    let medians = medians(colors);
+    let max_deviations = [0, 1, 2].map(|channel| {
+        let mut deviations: Vec<f32> = colors
+            .iter()
+            .map(|c| (c.0[channel] as f32 - medians[channel]).abs())
+            .collect();
+        // 1.4826 scales the MAD to a standard deviation for normally distributed values
+        let mad = 1.4826 * median_mut(&mut deviations);
+        OUTLIER_MADS * mad.max(1.0)
+    });
+
+    colors
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| {
+            (0..3).all(|channel| {
+                (c.0[channel] as f32 - medians[channel]).abs() <= max_deviations[channel]
+            })
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// k-means, seeded with the brightest point and then the points furthest from
+/// the existing centers, so that outliers end up in clusters of their own
+fn cluster_centers(points: &[[f32; 3]], k: usize) -> Vec<[f32; 3]> {
+    let brightest = points
+        .iter()
+        .max_by(|a, b| a.iter().sum::<f32>().total_cmp(&b.iter().sum::<f32>()))
+        .unwrap();
+    let mut centers = vec![*brightest];
+
+    while centers.len() < k.min(points.len()) {
+        let furthest = points
+            .iter()
+            .max_by(|a, b| {
+                let da = distance_squared(a, &centers[nearest(&centers, a)]);
+                let db = distance_squared(b, &centers[nearest(&centers, b)]);
+                da.total_cmp(&db)
+            })
+            .unwrap();
+        if centers.contains(furthest) {
+            break;
+        }
+        centers.push(*furthest);
+    }
+
+    for _ in 0..CLUSTER_ITERATIONS {
+        let mut sums = vec![[0.0_f32; 3]; centers.len()];
+        let mut counts = vec![0; centers.len()];
+        for p in points.iter() {
+            let label = nearest(&centers, p);
+            for channel in 0..3 {
+                sums[label][channel] += p[channel];
+            }
+            counts[label] += 1;
+        }
+        for (center, (sum, count)) in centers.iter_mut().zip(sums.iter().zip(counts)) {
+            if count > 0 {
+                *center = sum.map(|v| v / count as f32);
+            }
+        }
+    }
+
+    centers
+}
+
+fn nearest(centers: &[[f32; 3]], point: &[f32; 3]) -> usize {
+    (0..centers.len())
+        .min_by(|&a, &b| {
+            distance_squared(point, &centers[a]).total_cmp(&distance_squared(point, &centers[b]))
+        })
+        .unwrap_or(0)
+}
+
+fn distance_squared(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (0..3)
+        .map(|channel| (a[channel] - b[channel]).powi(2))
+        .sum()
+}
+
+fn median_mut(values: &mut [f32]) -> f32 {
+    let mid = values.len() / 2;
+    *values.select_nth_unstable_by(mid, |a, b| a.total_cmp(b)).1
+}
+
+fn trimmed_mean_mut(values: &mut [u16]) -> u16 {
+    values.sort_unstable();
+    let trim = (values.len() as f32 * TRIM_PCT) as usize;
+    let kept = &values[trim..values.len() - trim];
+    (kept.iter().map(|&v| v as u64).sum::<u64>() / kept.len() as u64) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_regions() {
+        assert_eq!(
+            " 0.1, 0.2,0.3 ,0.4".parse::<Region>().unwrap(),
+            Region::from([0.1, 0.2, 0.3, 0.4])
+        );
+        assert!("1,2,3".parse::<Region>().is_err());
+        assert!("1,2,3,4,5".parse::<Region>().is_err());
+        assert!("1,-2,3,4".parse::<Region>().is_err());
+        assert!("1,2,3,wide".parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn resolves_fractions_and_pixels() {
+        let fractions = Region::from([0.25, 0.5, 0.5, 0.25]);
+        assert_eq!(fractions.to_bounds(400, 200), [100, 100, 300, 150]);
+
+        let pixels = Region::from([10.0, 20.0, 30.0, 40.0]);
+        assert_eq!(pixels.to_bounds(400, 200), [10, 20, 40, 60]);
+        // clamped to the image
+        assert_eq!(pixels.to_bounds(25, 50), [10, 20, 25, 50]);
+    }
+
+    #[test]
+    fn scales_pixels_but_not_fractions() {
+        let fractions = Region::from([0.25, 0.5, 0.5, 0.25]);
+        assert_eq!(fractions.scaled(0.5), fractions);
+
+        let pixels = Region::from([10.0, 20.0, 30.0, 40.0]);
+        assert_eq!(pixels.scaled(0.5), Region::from([5.0, 10.0, 15.0, 20.0]));
+    }
+
+    #[test]
+    fn estimate_ignores_smaller_clusters() {
+        let mut colors = vec![Rgb([40000, 20000, 10000]); 90];
+        colors.extend(vec![Rgb([2000, 2000, 2000]); 10]);
+        let backing = estimate(&colors).unwrap();
+        assert_eq!(backing.color, Rgb([40000, 20000, 10000]));
+        // only as confident as the share of candidates in the cluster
+        assert!((backing.confidence - 0.9).abs() < 1e-4);
+        assert_eq!(backing.inliers.len(), 90);
+        assert!(estimate(&[]).is_none());
+    }
+}
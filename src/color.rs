@@ -0,0 +1,317 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::conversion::{RgbBuffer, Sample};
+use crate::tone::{srgb_decode, srgb_encode};
+
+/// OKLab chroma of a fairly saturated color. Vibrance barely affects colors
+/// above it.
+const VIBRANT_CHROMA: f32 = 0.2;
+const GAMUT_ITERATIONS: usize = 10;
+/// Width of a hue shift in degrees, if none is given
+const DEFAULT_HUE_WIDTH: f32 = 60.0;
+
+/// Approximate OKLCh hues of common colors, in degrees
+const NAMED_HUES: [(&str, f32); 8] = [
+    ("red", 29.0),
+    ("orange", 55.0),
+    ("yellow", 110.0),
+    ("green", 142.0),
+    ("aqua", 195.0),
+    ("blue", 264.0),
+    ("purple", 300.0),
+    ("magenta", 328.0),
+];
+
+/// Saturation and hue adjustments, applied in OKLCh so that they don't change
+/// the brightness of colors
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColorOptions {
+    /// From -100 (grayscale) to 100 (twice the chroma)
+    pub saturation: f32,
+    /// From -100 to 100. Like saturation, but mostly affects muted colors.
+    pub vibrance: f32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hue_shifts: Vec<HueShift>,
+}
+
+impl ColorOptions {
+    pub fn is_identity(&self) -> bool {
+        self.saturation == 0.0 && self.vibrance == 0.0 && self.hue_shifts.is_empty()
+    }
+
+    fn adjust(&self, [l, c, h]: [f32; 3]) -> [f32; 3] {
+        let vibrance = self.vibrance / 100.0 * (1.0 - (c / VIBRANT_CHROMA).min(1.0));
+        let c = c * (1.0 + self.saturation / 100.0) * (1.0 + vibrance);
+        let h = self
+            .hue_shifts
+            .iter()
+            .fold(h, |h, shift| h + shift.amount(h));
+        [l, c.max(0.0), h.rem_euclid(360.0)]
+    }
+}
+
+/// Rotates hues near `hue` by up to `shift` degrees, given as
+/// `hue:shift[:width]`. The hue is in degrees or a color name (red, orange,
+/// yellow, green, aqua, blue, purple, magenta), and hues further than
+/// `width / 2` from it aren't affected.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct HueShift {
+    pub hue: f32,
+    pub shift: f32,
+    pub width: f32,
+}
+
+impl HueShift {
+    /// Rotation for a hue, which falls off smoothly away from the center
+    fn amount(&self, hue: f32) -> f32 {
+        let distance = ((hue - self.hue + 180.0).rem_euclid(360.0) - 180.0).abs();
+        if distance >= self.width / 2.0 {
+            return 0.0;
+        }
+        self.shift * 0.5 * (1.0 + (2.0 * PI * distance / self.width).cos())
+    }
+}
+
+impl FromStr for HueShift {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("invalid hue shift \"{}\": {}", s, reason);
+        let parts: Vec<&str> = s.split(':').map(|part| part.trim()).collect();
+        let (hue, shift, width) = match parts[..] {
+            [hue, shift] => (hue, shift, None),
+            [hue, shift, width] => (hue, shift, Some(width)),
+            _ => return Err(invalid("expected hue:shift or hue:shift:width")),
+        };
+
+        let hue = match NAMED_HUES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(hue))
+        {
+            Some(&(_, degrees)) => degrees,
+            None => hue
+                .parse::<f32>()
+                .map_err(|_| invalid("the hue should be in degrees, or a color name"))?,
+        };
+        let shift = shift.parse::<f32>().map_err(|e| invalid(&e.to_string()))?;
+        let width = match width {
+            Some(width) => width.parse::<f32>().map_err(|e| invalid(&e.to_string()))?,
+            None => DEFAULT_HUE_WIDTH,
+        };
+        if !(0.0..=360.0).contains(&width) || width == 0.0 {
+            return Err(invalid(
+                "the width should be above 0 and at most 360 degrees",
+            ));
+        }
+
+        Ok(Self {
+            hue: hue.rem_euclid(360.0),
+            shift,
+            width,
+        })
+    }
+}
+
+impl TryFrom<String> for HueShift {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<HueShift> for String {
+    fn from(shift: HueShift) -> Self {
+        format!("{}:{}:{}", shift.hue, shift.shift, shift.width)
+    }
+}
+
+// matrices from the reference implementation of OKLab
+#[allow(clippy::excessive_precision)]
+fn linear_srgb_to_oklab([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+#[allow(clippy::excessive_precision)]
+fn oklab_to_linear_srgb([l, a, b]: [f32; 3]) -> [f32; 3] {
+    let l_ = (l + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m_ = (l - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s_ = (l - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+    [
+        4.0767416621 * l_ - 3.3077115913 * m_ + 0.2309699292 * s_,
+        -1.2684380046 * l_ + 2.6097574011 * m_ - 0.3413193965 * s_,
+        -0.0041960863 * l_ - 0.7034186147 * m_ + 1.7076147010 * s_,
+    ]
+}
+
+fn lab_to_lch([l, a, b]: [f32; 3]) -> [f32; 3] {
+    [l, a.hypot(b), b.atan2(a).to_degrees().rem_euclid(360.0)]
+}
+
+fn lch_to_lab([l, c, h]: [f32; 3]) -> [f32; 3] {
+    let h = h.to_radians();
+    [l, c * h.cos(), c * h.sin()]
+}
+
+/// Whether `rgb` has no negative values, and none above 1 if `clips`
+fn in_gamut(rgb: [f32; 3], clips: bool) -> bool {
+    let max = if clips { 1.0 + 1e-4 } else { f32::INFINITY };
+    rgb.iter().all(|v| (-1e-4..=max).contains(v))
+}
+
+/// Converts back to linear sRGB, reducing the chroma of colors that don't fit
+/// until they do, so that their lightness and hue are kept. Unless `clips`,
+/// values above 1 fit, e.g. for float images.
+fn lch_to_linear_srgb([l, c, h]: [f32; 3], clips: bool) -> [f32; 3] {
+    let rgb = oklab_to_linear_srgb(lch_to_lab([l, c, h]));
+    if in_gamut(rgb, clips) {
+        return rgb;
+    }
+
+    let (mut low, mut high) = (0.0, c);
+    for _ in 0..GAMUT_ITERATIONS {
+        let mid = (low + high) / 2.0;
+        if in_gamut(oklab_to_linear_srgb(lch_to_lab([l, mid, h])), clips) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    oklab_to_linear_srgb(lch_to_lab([l, low, h]))
+}
+
+pub fn apply_color_mut<S: Sample>(image: &mut RgbBuffer<S>, options: &ColorOptions) {
+    image.par_pixels_mut().for_each(|pixel| {
+        let linear = S::values(pixel).map(|v| srgb_decode(v.to_unit()));
+        let lch = options.adjust(lab_to_lch(linear_srgb_to_oklab(linear)));
+        *pixel = lch_to_linear_srgb(lch, S::CLIPS)
+            .map(|v| S::from_unit(srgb_encode(v)))
+            .into();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+    use crate::conversion::{FloatImage, InputImage};
+
+    const EPSILON: f32 = 1e-4;
+
+    #[test]
+    fn parses_hue_shifts() {
+        assert_eq!(
+            "Orange:-10".parse::<HueShift>().unwrap(),
+            HueShift {
+                hue: 55.0,
+                shift: -10.0,
+                width: DEFAULT_HUE_WIDTH,
+            }
+        );
+        assert_eq!(
+            "-20 : 15 : 90".parse::<HueShift>().unwrap(),
+            HueShift {
+                hue: 340.0,
+                shift: 15.0,
+                width: 90.0,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_hue_shifts() {
+        let error = |shift: &str| shift.parse::<HueShift>().unwrap_err();
+        assert!(error("red").contains("expected hue:shift"));
+        assert!(error("red:1:2:3").contains("expected hue:shift"));
+        assert!(error("teal:10").contains("in degrees, or a color name"));
+        assert!(error("red:a lot").contains("invalid hue shift"));
+        assert!(error("red:10:0").contains("above 0 and at most 360"));
+        assert!(error("red:10:400").contains("above 0 and at most 360"));
+    }
+
+    #[test]
+    fn hue_shifts_round_trip_through_strings() {
+        let shift: HueShift = "blue:12.5:45".parse().unwrap();
+        assert_eq!(
+            String::from(shift.clone()).parse::<HueShift>().unwrap(),
+            shift
+        );
+    }
+
+    #[test]
+    fn hue_shifts_fall_off_from_the_center() {
+        let shift: HueShift = "350:20:60".parse().unwrap();
+        assert!((shift.amount(350.0) - 20.0).abs() < EPSILON);
+        // distances wrap around 0 degrees
+        assert!((shift.amount(5.0) - shift.amount(335.0)).abs() < EPSILON);
+        assert!(shift.amount(5.0) > 0.0 && shift.amount(5.0) < 20.0);
+        assert_eq!(shift.amount(20.0), 0.0);
+        assert_eq!(shift.amount(180.0), 0.0);
+    }
+
+    #[test]
+    fn oklab_round_trips() {
+        for rgb in [[0.0; 3], [1.0; 3], [0.8, 0.2, 0.1], [0.05, 0.4, 0.9]] {
+            let back = oklab_to_linear_srgb(lch_to_lab(lab_to_lch(linear_srgb_to_oklab(rgb))));
+            for (a, b) in rgb.iter().zip(back) {
+                assert!((a - b).abs() < 1e-3, "{:?} became {:?}", rgb, back);
+            }
+        }
+    }
+
+    #[test]
+    fn full_desaturation_is_gray() {
+        let options = ColorOptions {
+            saturation: -100.0,
+            ..ColorOptions::default()
+        };
+        let lch = options.adjust(lab_to_lch(linear_srgb_to_oklab([0.8, 0.2, 0.1])));
+        let [r, g, b] = lch_to_linear_srgb(lch, true);
+        assert!((r - g).abs() < 1e-3 && (g - b).abs() < 1e-3);
+    }
+
+    #[test]
+    fn out_of_gamut_colors_are_brought_into_gamut() {
+        let [l, c, h] = lab_to_lch(linear_srgb_to_oklab([0.1, 0.9, 0.1]));
+        let clipped = lch_to_linear_srgb([l, c * 3.0, h], true);
+        assert!(in_gamut(clipped, true));
+        // floats only need to lose enough chroma to not be negative
+        let float = lch_to_linear_srgb([l, c * 3.0, h], false);
+        assert!(in_gamut(float, false));
+        let chroma = |rgb| lab_to_lch(linear_srgb_to_oklab(rgb))[1];
+        assert!(chroma(float) >= chroma(clipped));
+    }
+
+    #[test]
+    fn floats_above_1_keep_their_color() {
+        let mut image = FloatImage::from_pixel(1, 1, Rgb([2.0, 1.2, 0.8]));
+        let options = ColorOptions {
+            saturation: 10.0,
+            ..ColorOptions::default()
+        };
+        apply_color_mut(&mut image, &options);
+        let [r, g, b] = image.get_pixel(0, 0).0;
+        assert!(r > 2.0, "{:?} lost its highlights", [r, g, b]);
+        assert!(r > g && g > b);
+
+        // 16-bit values can't go above 1, so they're brought into gamut
+        let mut image = InputImage::from_pixel(1, 1, Rgb([u16::MAX, 40000, 20000]));
+        apply_color_mut(&mut image, &options);
+        let [r, g, b] = image.get_pixel(0, 0).0;
+        assert!(r > g && g > b);
+    }
+}
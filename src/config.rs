@@ -0,0 +1,571 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgAction, Command};
+use toml::{Table, Value};
+
+/// Name of the config file of a project, read from the current directory
+pub const PROJECT_FILE_NAME: &str = "yancy.toml";
+/// Key of the table of named presets in a config file
+const PRESETS_KEY: &str = "presets";
+/// Key of the list of jobs in a job file
+const JOBS_KEY: &str = "jobs";
+/// Presets are chosen on the command line, not in config files
+const PRESET_ARG: &str = "preset";
+/// Prefix of the flags that turn off flags set in config files, e.g.
+/// `--no-half-frame`
+pub const NEGATION_PREFIX: &str = "no-";
+
+/// Settings read from a config file. Top-level keys are used on every run, and
+/// each table under `[presets]` is a named set of settings. Keys are the long
+/// names of command line options, e.g. `half-frame = true`.
+#[derive(Clone, Debug)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    pub defaults: Table,
+    pub presets: BTreeMap<String, Table>,
+}
+
+impl ConfigFile {
+    /// Reads the config file at `path`, if there is one
+    pub fn load(path: &Path) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read config {}: {}", path.display(), e))?;
+        let mut defaults: Table = toml::from_str(&contents)
+            .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+
+        let presets = match defaults.remove(PRESETS_KEY) {
+            Some(Value::Table(presets)) => presets
+                .into_iter()
+                .map(|(name, preset)| match preset {
+                    Value::Table(preset) => Ok((name, preset)),
+                    _ => Err(format!(
+                        "Invalid config {}: preset {} should be a table",
+                        path.display(),
+                        name
+                    )),
+                })
+                .collect::<Result<_, _>>()?,
+            Some(_) => {
+                return Err(format!(
+                    "Invalid config {}: {} should be a table",
+                    path.display(),
+                    PRESETS_KEY
+                )
+                .into());
+            }
+            None => BTreeMap::new(),
+        };
+
+        Ok(Some(Self {
+            path: path.to_path_buf(),
+            defaults,
+            presets,
+        }))
+    }
+}
+
+/// Conversions listed in a job file. Top-level keys are used by every job, and
+/// each table in `[[jobs]]` is one conversion, with the same keys as a config
+/// file plus its inputs and `preset`.
+#[derive(Clone, Debug)]
+pub struct JobFile {
+    pub path: PathBuf,
+    pub defaults: Table,
+    pub jobs: Vec<Table>,
+}
+
+impl JobFile {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read job file {}: {}", path.display(), e))?;
+        let mut defaults: Table = toml::from_str(&contents)
+            .map_err(|e| format!("Invalid job file {}: {}", path.display(), e))?;
+
+        let invalid = || {
+            format!(
+                "Invalid job file {}: expected [[{}]] tables",
+                path.display(),
+                JOBS_KEY
+            )
+        };
+        let jobs = match defaults.remove(JOBS_KEY) {
+            Some(Value::Array(jobs)) => jobs
+                .into_iter()
+                .map(|job| match job {
+                    Value::Table(job) => Ok(job),
+                    _ => Err(invalid()),
+                })
+                .collect::<Result<_, _>>()?,
+            _ => return Err(invalid().into()),
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            defaults,
+            jobs,
+        })
+    }
+
+    /// The command line arguments of the job at `index`, for `command`. Its
+    /// keys replace the top-level ones, and its `preset` is chosen with
+    /// `--preset`.
+    pub fn args(&self, index: usize, command: &Command) -> Result<Vec<OsString>, String> {
+        let mut settings = BTreeMap::new();
+        let mut preset = None;
+        let layers = [
+            (&self.defaults, Source::File(self.path.clone())),
+            (&self.jobs[index], Source::Job(index + 1, self.path.clone())),
+        ];
+        for (table, source) in layers {
+            let mut table = table.clone();
+            match table.remove(PRESET_ARG) {
+                Some(Value::String(name)) => preset = Some(name),
+                Some(_) => {
+                    return Err(format!(
+                        "Invalid setting {} in {}: should be the name of a preset",
+                        PRESET_ARG, source
+                    ));
+                }
+                None => {}
+            }
+            add(&mut settings, &table, command, command, source)?;
+        }
+
+        let mut args: Vec<OsString> = settings
+            .into_values()
+            .flat_map(|setting| setting.args)
+            .collect();
+        if let Some(name) = preset {
+            args.push(format!("--{}", PRESET_ARG).into());
+            args.push(name.into());
+        }
+        Ok(args)
+    }
+}
+
+/// Path of the user's config file, `~/.config/yancy/config.toml` unless
+/// `XDG_CONFIG_HOME` is set
+pub fn user_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("yancy").join("config.toml"))
+}
+
+/// Where a setting in effect came from
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    CommandLine,
+    /// A preset, and the config file that it's in
+    Preset(String, PathBuf),
+    File(PathBuf),
+    /// The number of a job, from 1, and the job file that it's in
+    Job(usize, PathBuf),
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CommandLine => write!(f, "command line"),
+            Self::Preset(name, path) => write!(f, "preset {} in {}", name, path.display()),
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Job(number, path) => write!(f, "job {} in {}", number, path.display()),
+            Self::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// A setting from a config file, as the command line arguments it stands for
+#[derive(Clone, Debug)]
+pub struct Setting {
+    pub args: Vec<OsString>,
+    pub source: Source,
+}
+
+/// Layers the settings of config `files`, which are in increasing precedence,
+/// and then those of `preset` over them. The preset is chosen for the run, so
+/// it takes precedence over the top-level keys of every file, including files
+/// with higher precedence than the one it's defined in. Presets of the same
+/// name are layered in the order of the files.
+///
+/// Settings are keyed by the id of their argument of `command`, and replace
+/// the ones that they conflict with. Settings that are only options of other
+/// subcommands of `root` are skipped.
+pub fn resolve(
+    files: &[ConfigFile],
+    preset: Option<&str>,
+    command: &Command,
+    root: &Command,
+) -> Result<BTreeMap<String, Setting>, String> {
+    // inputs are only parsed from the command line, before config files are read
+    let add = |settings: &mut BTreeMap<String, Setting>, table: &Table, source: Source| {
+        if let Some(key) = table.keys().find(|key| {
+            let long = key.replace('_', "-");
+            command.get_arguments().any(|arg| {
+                arg.get_long() == Some(long.as_str()) && is_input(command, arg.get_id().as_str())
+            })
+        }) {
+            return Err(format!(
+                "Invalid setting {} in {}: inputs are given on the command line or in job files",
+                key, source
+            ));
+        }
+        add(settings, table, command, root, source)
+    };
+
+    let mut settings = BTreeMap::new();
+    for file in files {
+        add(
+            &mut settings,
+            &file.defaults,
+            Source::File(file.path.clone()),
+        )?;
+    }
+
+    if let Some(name) = preset {
+        let mut found = false;
+        for file in files {
+            if let Some(table) = file.presets.get(name) {
+                let source = Source::Preset(name.to_owned(), file.path.clone());
+                add(&mut settings, table, source)?;
+                found = true;
+            }
+        }
+        if !found {
+            let names: BTreeSet<&str> = files
+                .iter()
+                .flat_map(|file| file.presets.keys().map(String::as_str))
+                .collect();
+            let names: Vec<&str> = names.into_iter().collect();
+            return Err(match names[..] {
+                [] => format!("Unknown preset {}, no presets are defined", name),
+                _ => format!(
+                    "Unknown preset {}, expected one of {}",
+                    name,
+                    names.join(", ")
+                ),
+            });
+        }
+    }
+
+    Ok(settings)
+}
+
+/// Adds a hidden `--no-<flag>` to each flag of `command` and its subcommands
+/// that config files can set, which turns it off again
+pub fn with_negations(command: Command) -> Command {
+    let names: Vec<String> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_owned())
+        .collect();
+    let mut command = names.iter().fold(command, |command, name| {
+        command.mut_subcommand(name, with_negations)
+    });
+    if command
+        .get_arguments()
+        .all(|arg| arg.get_id() != PRESET_ARG)
+    {
+        return command;
+    }
+
+    let flags: Vec<(String, String)> = command
+        .get_arguments()
+        .filter(|arg| matches!(arg.get_action(), ArgAction::SetTrue))
+        .filter_map(|arg| Some((arg.get_id().to_string(), arg.get_long()?.to_owned())))
+        .collect();
+    for (id, long) in flags {
+        let negation = negation(&id);
+        command = command
+            .mut_arg(&id, |arg| arg.overrides_with(negation.clone()))
+            .arg(
+                Arg::new(negation.clone())
+                    .long(format!("{}{}", NEGATION_PREFIX, long))
+                    .action(ArgAction::SetTrue)
+                    .help(format!(
+                        "Turns off --{}, e.g. when set in a config file",
+                        long
+                    ))
+                    .hide(true)
+                    .overrides_with(id),
+            );
+    }
+    command
+}
+
+/// Id of the flag that turns off the flag with id `id`
+pub fn negation(id: &str) -> String {
+    format!("{}{}", NEGATION_PREFIX, id)
+}
+
+/// Whether the argument with id `id` is one of the inputs of `command`, which
+/// are required
+fn is_input(command: &Command, id: &str) -> bool {
+    command
+        .get_arguments()
+        .any(|arg| arg.get_id() == id && arg.is_required_set())
+        || command
+            .get_groups()
+            .filter(|group| group.is_required_set())
+            .any(|group| group.get_args().any(|arg| arg == id))
+}
+
+/// Whether the arguments with ids `a` and `b` can't be used together
+pub fn conflicts(command: &Command, a: &str, b: &str) -> bool {
+    let find = |id: &str| command.get_arguments().find(|arg| arg.get_id() == id);
+    let (Some(arg_a), Some(arg_b)) = (find(a), find(b)) else {
+        return false;
+    };
+    command
+        .get_arg_conflicts_with(arg_a)
+        .iter()
+        .any(|arg| arg.get_id() == b)
+        || command
+            .get_arg_conflicts_with(arg_b)
+            .iter()
+            .any(|arg| arg.get_id() == a)
+}
+
+/// Whether `long` is the name of an option of `command` or its subcommands
+fn is_option(command: &Command, long: &str) -> bool {
+    command
+        .get_arguments()
+        .any(|arg| arg.get_long() == Some(long))
+        || command
+            .get_subcommands()
+            .any(|subcommand| is_option(subcommand, long))
+}
+
+fn add(
+    settings: &mut BTreeMap<String, Setting>,
+    table: &Table,
+    command: &Command,
+    root: &Command,
+    source: Source,
+) -> Result<(), String> {
+    for (key, value) in table {
+        let invalid = |reason: &str| format!("Invalid setting {} in {}: {}", key, source, reason);
+
+        let long = key.replace('_', "-");
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+        else {
+            if is_option(root, &long) {
+                continue;
+            }
+            return Err(format!("Unknown setting {} in {}", key, source));
+        };
+        let id = arg.get_id().as_str();
+        if id == PRESET_ARG {
+            return Err(invalid("presets are chosen with --preset"));
+        }
+
+        let option = OsString::from(format!("--{}", long));
+        let negated = command
+            .get_arguments()
+            .any(|arg| arg.get_id() == negation(id).as_str());
+        let args = match (arg.get_action(), value) {
+            (ArgAction::SetTrue, Value::Boolean(true)) => vec![option],
+            (ArgAction::SetTrue, Value::Boolean(false)) if negated => {
+                vec![format!("--{}{}", NEGATION_PREFIX, long).into()]
+            }
+            (ArgAction::SetTrue, Value::Boolean(false)) => vec![],
+            (ArgAction::SetTrue, _) => return Err(invalid("should be true or false")),
+            (ArgAction::Append, Value::Array(values)) => {
+                let mut args = vec![];
+                for value in values {
+                    args.push(option.clone());
+                    args.push(scalar(value).map_err(|e| invalid(&e))?.into());
+                }
+                args
+            }
+            (_, Value::Array(_)) => return Err(invalid("can only be given once")),
+            (_, value) => vec![option, scalar(value).map_err(|e| invalid(&e))?.into()],
+        };
+
+        settings.retain(|other, _| !conflicts(command, id, other));
+        settings.insert(
+            id.to_owned(),
+            Setting {
+                args,
+                source: source.clone(),
+            },
+        );
+    }
+    Ok(())
+}
+
+/// The command line value of a single value from a config file
+fn scalar(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(String::from("should be a string, number or boolean")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::ArgGroup;
+
+    use super::*;
+
+    fn command() -> Command {
+        let mut command = with_negations(
+            Command::new("yancy")
+                .arg(Arg::new("dir").long("dir"))
+                .group(ArgGroup::new("input").arg("dir").required(true))
+                .arg(Arg::new("preset").long("preset"))
+                .arg(Arg::new("output_format").long("output-format"))
+                .arg(Arg::new("quality").long("quality"))
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .action(ArgAction::Append)
+                        .conflicts_with("output_format"),
+                )
+                .arg(
+                    Arg::new("half_frame")
+                        .long("half-frame")
+                        .action(ArgAction::SetTrue),
+                )
+                .subcommand(Command::new("preview").arg(Arg::new("size").long("size"))),
+        );
+        command.build();
+        command
+    }
+
+    fn file(path: &str, contents: &str) -> ConfigFile {
+        let mut defaults: Table = toml::from_str(contents).unwrap();
+        let presets = match defaults.remove(PRESETS_KEY) {
+            Some(Value::Table(presets)) => presets
+                .into_iter()
+                .map(|(name, preset)| (name, preset.as_table().unwrap().clone()))
+                .collect(),
+            _ => BTreeMap::new(),
+        };
+        ConfigFile {
+            path: PathBuf::from(path),
+            defaults,
+            presets,
+        }
+    }
+
+    fn args(settings: &BTreeMap<String, Setting>, id: &str) -> Vec<String> {
+        settings[id]
+            .args
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn project_file_takes_precedence_over_user_file() {
+        let command = command();
+        let files = [
+            file("user.toml", "output-format = \"tiff\"\nquality = 80"),
+            file("yancy.toml", "output-format = \"png\""),
+        ];
+        let settings = resolve(&files, None, &command, &command).unwrap();
+
+        assert_eq!(args(&settings, "output_format"), ["--output-format", "png"]);
+        assert_eq!(
+            settings["output_format"].source,
+            Source::File(PathBuf::from("yancy.toml"))
+        );
+        assert_eq!(args(&settings, "quality"), ["--quality", "80"]);
+    }
+
+    #[test]
+    fn preset_takes_precedence_over_every_file() {
+        let command = command();
+        let files = [
+            file(
+                "user.toml",
+                "[presets.web]\noutput-format = \"jpeg\"\nquality = 80",
+            ),
+            file(
+                "yancy.toml",
+                "output-format = \"png\"\n[presets.web]\nquality = 90",
+            ),
+        ];
+        let settings = resolve(&files, Some("web"), &command, &command).unwrap();
+
+        assert_eq!(
+            args(&settings, "output_format"),
+            ["--output-format", "jpeg"]
+        );
+        assert_eq!(
+            settings["output_format"].source,
+            Source::Preset(String::from("web"), PathBuf::from("user.toml"))
+        );
+        assert_eq!(args(&settings, "quality"), ["--quality", "90"]);
+    }
+
+    #[test]
+    fn settings_replace_the_ones_they_conflict_with() {
+        let command = command();
+        let files = [
+            file("user.toml", "output-format = \"tiff\""),
+            file("yancy.toml", "output = [\"jpeg\", \"png\"]"),
+        ];
+        let settings = resolve(&files, None, &command, &command).unwrap();
+
+        assert!(!settings.contains_key("output_format"));
+        assert_eq!(
+            args(&settings, "output"),
+            ["--output", "jpeg", "--output", "png"]
+        );
+    }
+
+    #[test]
+    fn false_turns_off_a_flag() {
+        let command = command();
+        let files = [
+            file("user.toml", "half-frame = true"),
+            file("yancy.toml", "half-frame = false"),
+        ];
+        let settings = resolve(&files, None, &command, &command).unwrap();
+
+        assert_eq!(args(&settings, "half_frame"), ["--no-half-frame"]);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let command = command();
+        let resolve = |contents: &str, preset: Option<&str>| {
+            resolve(&[file("yancy.toml", contents)], preset, &command, &command)
+        };
+
+        assert!(resolve("size = 512", None).unwrap().is_empty());
+        assert!(resolve("sizes = 512", None).is_err());
+        assert!(resolve("dir = \"roll\"", None).is_err());
+        assert!(resolve("preset = \"web\"", None).is_err());
+        assert!(resolve("half-frame = 1", None).is_err());
+        assert!(resolve("quality = [80, 90]", None).is_err());
+        assert_eq!(
+            resolve("[presets.web]\n[presets.print]", Some("film")).unwrap_err(),
+            "Unknown preset film, expected one of print, web"
+        );
+    }
+
+    #[test]
+    fn conflicts_go_both_ways() {
+        let command = command();
+        assert!(conflicts(&command, "output", "output_format"));
+        assert!(conflicts(&command, "output_format", "output"));
+        assert!(!conflicts(&command, "output", "quality"));
+        assert!(!conflicts(&command, "output", "unknown"));
+    }
+}
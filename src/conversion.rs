@@ -1,45 +1,362 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
 use std::u16;
 
+use clap::ValueEnum;
+use image::error::{ParameterError, ParameterErrorKind};
 use image::imageops::{self, contrast, crop_imm};
-use image::{DynamicImage, GrayImage, ImageBuffer, ImageError, Luma, Rgb};
+use image::{DynamicImage, GrayImage, ImageBuffer, ImageError, Luma, Pixel, Primitive, Rgb};
 use imageproc::contours::find_contours;
-use imageproc::drawing::{draw_filled_circle_mut, draw_line_segment_mut};
+use imageproc::drawing::{draw_filled_circle_mut, draw_hollow_rect_mut, draw_line_segment_mut};
 use imageproc::edges::canny;
 use imageproc::filter::median_filter;
+use imageproc::geometric_transformations::{Interpolation, rotate_about_center};
 use imageproc::geometry::min_area_rect;
-use imageproc::map::{map_colors, map_colors_mut};
+use imageproc::map::map_colors;
 use imageproc::point::Point;
+use imageproc::rect::Rect;
+use rayon::prelude::*;
 
-use crate::histogram::{normalize_histogram_mut, stretch_channels_mut};
+use crate::balance::{BalanceOptions, apply_gains_mut, find_gains};
+use crate::base::{self, Region};
+use crate::color::{ColorOptions, apply_color_mut};
+use crate::denoise::{DenoiseOptions, apply_denoise_mut};
+use crate::dust::{self, DustOptions};
+use crate::histogram::{
+    Cutoffs, HistogramRgb, Levels, StretchMode, StretchOptions, apply_levels_mut, find_cutoffs,
+    histogram_rgb, normalize_histogram_mut,
+};
 use crate::io;
+use crate::lut::{Lut, LutInterpolation, apply_lut_mut};
+use crate::sharpen::{self, SharpenOptions, apply_sharpen_mut};
+use crate::sidecar::{Sidecar, Steps};
+use crate::sprockets::{self, Orientation, Sprockets};
+use crate::tone::{ToneOptions, apply_tone_mut};
 
 const BLACK_BORDER_THRESHOLD: u8 = 20;
 const WHITE_LIGHT_THRESHOLD: u8 = 240;
+/// Longest side of the images that borders (and dust) are first looked for in
+const ANALYSIS_SIZE: u32 = 500;
 
 pub type InputImage = ImageBuffer<Rgb<u16>, Vec<u16>>;
 
-type Bounds = (u32, u32, u32, u32);
+/// Frames converted with `Options::float`
+pub type FloatImage = ImageBuffer<Rgb<f32>, Vec<f32>>;
+
+pub type RgbBuffer<S> = ImageBuffer<<S as Sample>::Pixel, Vec<S>>;
+
+/// Channel values that frames are adjusted in: 16-bit values, or 32-bit
+/// floats that are neither rounded nor clipped between steps. Both are scaled
+/// so that 0 to 1 is the full range.
+pub trait Sample: Primitive + Send + Sync + 'static {
+    /// `Rgb<Self>`. The image crate only implements `Pixel` for some types of
+    /// channels, so `Rgb<S>` can't be used for any `S`.
+    type Pixel: Pixel<Subpixel = Self> + From<[Self; 3]> + Send + Sync + 'static;
+
+    /// Whether `from_unit` clips values to 0 to 1
+    const CLIPS: bool;
+
+    fn to_unit(self) -> f32;
+
+    /// Clips and rounds to the type, if it can't hold `value` as it is
+    fn from_unit(value: f32) -> Self;
+
+    /// Maps every value of `image` with `f`, which is given the channel and
+    /// the value from 0 to 1
+    fn map_channels_mut(image: &mut RgbBuffer<Self>, f: impl Fn(usize, f32) -> f32 + Sync);
+
+    /// `image` in 16-bit values, for the steps that only support them
+    fn fixed(image: &RgbBuffer<Self>) -> Cow<'_, InputImage>;
+
+    fn values(pixel: &Self::Pixel) -> [Self; 3] {
+        let channels = pixel.channels();
+        [channels[0], channels[1], channels[2]]
+    }
+}
+
+impl Sample for u16 {
+    type Pixel = Rgb<u16>;
+
+    const CLIPS: bool = true;
+
+    fn to_unit(self) -> f32 {
+        self as f32 / u16::MAX as f32
+    }
+
+    fn from_unit(value: f32) -> Self {
+        (value.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16
+    }
+
+    /// Looks values up in a table per channel, instead of calling `f` for
+    /// every pixel
+    fn map_channels_mut(image: &mut InputImage, f: impl Fn(usize, f32) -> f32 + Sync) {
+        let luts = [0, 1, 2].map(|channel| {
+            (0..=u16::MAX)
+                .into_par_iter()
+                .map(|value| Self::from_unit(f(channel, value.to_unit())))
+                .collect::<Vec<u16>>()
+        });
+
+        image.par_pixels_mut().for_each(|pixel| {
+            for (channel, value) in pixel.0.iter_mut().enumerate() {
+                *value = luts[channel][*value as usize];
+            }
+        });
+    }
+
+    fn fixed(image: &InputImage) -> Cow<'_, InputImage> {
+        Cow::Borrowed(image)
+    }
+}
+
+impl Sample for f32 {
+    type Pixel = Rgb<f32>;
+
+    const CLIPS: bool = false;
+
+    fn to_unit(self) -> f32 {
+        self
+    }
+
+    fn from_unit(value: f32) -> Self {
+        value
+    }
+
+    fn map_channels_mut(image: &mut FloatImage, f: impl Fn(usize, f32) -> f32 + Sync) {
+        image.par_pixels_mut().for_each(|pixel| {
+            for (channel, value) in pixel.0.iter_mut().enumerate() {
+                *value = f(channel, *value);
+            }
+        });
+    }
+
+    fn fixed(image: &FloatImage) -> Cow<'_, InputImage> {
+        Cow::Owned(convert_samples(image))
+    }
+}
+
+/// Converts between 16-bit and float images
+pub fn convert_samples<S: Sample, T: Sample>(image: &RgbBuffer<S>) -> RgbBuffer<T> {
+    let values = image
+        .as_raw()
+        .par_iter()
+        .map(|&value| T::from_unit(value.to_unit()))
+        .collect();
+    ImageBuffer::from_raw(image.width(), image.height(), values)
+        .expect("converted image should have as many values as the original")
+}
+
+pub type Bounds = (u32, u32, u32, u32);
 
 struct Border {
     bounds: Bounds,
-    points: Vec<(u32, u32)>,
+    /// Positions of pixels that are likely to be the film backing
+    candidates: Vec<(u32, u32)>,
+    sprockets: Option<Sprockets>,
+}
+
+/// Settings shared by every frame
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The expected aspect ratio as width/height
+    pub aspect_ratio: f32,
+    /// Amount of additional crop after border removal, as a percentage of the
+    /// original image's width and height
+    pub crop_percentage: f32,
+    /// Region to sample the film backing color from, instead of the border
+    pub base_region: Option<Region>,
+    /// Film backing color of every frame, e.g. measured on the first frame of
+    /// the roll, instead of detecting it
+    pub base_color: Option<Rgb<u16>>,
+    /// Film backing color to use when it can't be detected confidently, e.g.
+    /// from the rest of the roll or a profile
+    pub fallback_base_color: Option<Rgb<u16>>,
+    /// Detected film backing colors below this confidence are replaced with
+    /// `fallback_base_color`
+    pub min_base_confidence: f32,
+    /// Detect sprocket holes and the film rebate, for scans of the full width
+    /// of 35mm film
+    pub detect_sprockets: bool,
+    pub crop_mode: CropMode,
+    /// Remove dust from the negative before inverting it
+    pub dust: Option<DustOptions>,
+    /// Adjust frames in 32-bit float after removing dust, so that values
+    /// aren't rounded between steps, and ones outside of the range are kept
+    pub float: bool,
+    pub stretch: StretchOptions,
+    /// Levels shared by every frame of the roll, instead of each frame's own
+    pub roll_levels: Option<RollLevels>,
+    pub balance: BalanceOptions,
+    /// Noise reduction after stretching, which amplifies noise in thin negatives
+    pub denoise: DenoiseOptions,
+    pub tone: ToneOptions,
+    pub color: ColorOptions,
+    /// Look applied after everything else
+    pub lut: Option<Lut>,
+    pub lut_interpolation: LutInterpolation,
+    /// Sharpening of each output, after scaling it
+    pub sharpen: SharpenOptions,
+    /// Measure histograms of the frame before stretching and after all adjustments
+    pub collect_histograms: bool,
+}
+
+impl Options {
+    /// The options for frames scaled by `factor`, e.g. for a preview
+    pub fn scaled(&self, factor: f32) -> Self {
+        let mut options = self.clone();
+        options.base_region = self.base_region.map(|r| r.scaled(factor));
+        options.balance.neutral_point = self.balance.neutral_point.map(|p| p.scaled(factor));
+        if let Some(dust) = &mut options.dust {
+            dust.max_size = ((dust.max_size as f32 * factor).round() as u32).max(1);
+        }
+        options
+    }
+}
+
+/// Levels shared by every frame of a roll
+#[derive(Clone, Debug)]
+pub struct RollLevels {
+    pub levels: Levels,
+    /// How far each frame's own levels may deviate from the roll's, as a share
+    /// of the full range
+    pub max_deviation: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum CropMode {
+    /// Keep the whole image
+    None,
+    /// Crop to the frame
+    Frame,
+    /// Keep the film rebate and sprocket holes (cropped to the edges of the
+    /// film, if found), but only use the frame to stretch levels
+    FullBleed,
+}
+
+pub struct Conversion {
+    pub image: InputImage,
+    /// The image before it was rounded to `image`, if `Options::float` is
+    /// set. Values outside of 0 to 1 are kept.
+    pub float: Option<FloatImage>,
+    /// Bounds of the frame, in pixels of the (rotated) image
+    pub crop: Bounds,
+    /// Bounds of the frame within `image`
+    pub frame: Bounds,
+    pub base_color: Rgb<u16>,
+    /// Confidence of the detected film backing color, if it was detected
+    pub base_confidence: Option<f32>,
+    /// Clockwise rotation in degrees that was applied before anything else
+    pub rotation: Option<f32>,
+    /// Levels that the image was stretched with
+    pub levels: Option<Levels>,
+    /// Gain of each channel from white balance after inversion, applied to
+    /// linear values
+    pub gains: Option<[f32; 3]>,
+    /// Tone adjustments that were applied
+    pub tone: Option<ToneOptions>,
+    /// Saturation and hue adjustments that were applied
+    pub color: Option<ColorOptions>,
+    /// Sharpening that is applied by [render], once the size is known
+    pub sharpen: Option<SharpenOptions>,
+    /// Cutoffs measured from this frame, if its levels weren't taken from the
+    /// roll as they are
+    pub cutoffs: Option<Cutoffs>,
+    /// Histograms of the frame, if `Options::collect_histograms` is set
+    pub histograms: Option<Histograms>,
+}
+
+/// 256-bin histograms of the frame, before stretching and after all adjustments
+#[derive(Clone, Debug)]
+pub struct Histograms {
+    pub before: HistogramRgb,
+    pub after: HistogramRgb,
+}
+
+impl Conversion {
+    /// The part of `image` inside the frame
+    pub fn frame_image(&self) -> InputImage {
+        crop_frame(&self.image, self.frame)
+    }
 }
 
 pub fn convert(
     original: &InputImage,
-    aspect_ratio: f32,
-    crop_percentage: f32,
+    options: &Options,
+    sidecar: &Sidecar,
     debug_file_path: Option<&str>,
     debug_dir_suffix: &Option<String>,
-) -> Result<InputImage, ImageError> {
-    let Border {
-        bounds,
-        points: border_points,
-    } = identify_border(&original, debug_file_path, debug_dir_suffix)?;
+) -> Result<Conversion, ImageError> {
+    let rotation = match sidecar.rotation {
+        Some(degrees) => Some(degrees),
+        None if options.detect_sprockets => infer_rotation(original),
+        None => None,
+    };
+
+    let rotated;
+    let original = match rotation {
+        Some(degrees) => {
+            rotated = rotate(original, degrees);
+            &rotated
+        }
+        None => original,
+    };
+
+    let base_region = sidecar.base_region.or(options.base_region);
+    // a frame's own base region takes precedence over the roll's color
+    let base_color = match sidecar.base_region {
+        Some(_) => sidecar.base_color.map(Rgb),
+        None => sidecar.base_color.map(Rgb).or(options.base_color),
+    };
+
+    let find_frame = sidecar.crop.is_none() && options.crop_mode != CropMode::None;
+    let find_base = base_color.is_none() && base_region.is_none();
 
-    let (min_x, min_y, max_x, max_y) =
-        determine_crop_inset_bounds(original, bounds, aspect_ratio, crop_percentage);
+    // only detect the border if the sidecar doesn't already pin everything
+    let border = if find_frame || find_base {
+        Some(identify_border(
+            original,
+            options.detect_sprockets,
+            debug_file_path,
+            debug_dir_suffix,
+        )?)
+    } else {
+        None
+    };
+
+    let whole_image = (0, 0, original.width(), original.height());
+
+    let (min_x, min_y, max_x, max_y) = match (sidecar.crop, &border) {
+        (Some(crop), _) => validate_bounds(original, crop)?,
+        _ if options.crop_mode == CropMode::None => whole_image,
+        (None, Some(border)) => determine_crop_inset_bounds(
+            original,
+            border.bounds,
+            options.aspect_ratio,
+            options.crop_percentage,
+        ),
+        (None, None) => unreachable!("border is identified when the crop is not set"),
+    };
+
+    let (avg_border_color, base_confidence, base_points) = match (base_color, base_region, &border)
+    {
+        (Some(color), _, _) => (color, None, vec![]),
+        (None, Some(region), _) => {
+            let bounds = region.to_bounds(original.width(), original.height());
+            validate_bounds(original, bounds)?;
+            (base::sample_region(original, bounds), None, vec![])
+        }
+        (None, None, Some(border)) => {
+            let estimate = estimate_base(original, border, options)?;
+            let points = estimate
+                .inliers
+                .iter()
+                .map(|&i| border.candidates[i])
+                .collect();
+            (estimate.color, Some(estimate.confidence), points)
+        }
+        (None, None, None) => {
+            unreachable!("border is identified when the base color is not set")
+        }
+    };
 
     if let Some(path) = debug_file_path {
         let mut img = original.clone();
@@ -57,62 +374,527 @@ pub fn convert(
             draw_line_segment_mut(&mut img, p0, p1, Rgb([0, u16::MAX, u16::MAX]));
         }
 
-        for &(x, y) in border_points.iter() {
+        for &(x, y) in base_points.iter() {
             draw_filled_circle_mut(&mut img, (x as i32, y as i32), 10, Rgb([u16::MAX, 0, 0]));
         }
 
+        if let Some(sprockets) = border.as_ref().and_then(|b| b.sprockets.as_ref()) {
+            for &(min_x, min_y, max_x, max_y) in sprockets.holes.iter() {
+                let rect = Rect::at(min_x as i32, min_y as i32)
+                    .of_size((max_x - min_x).max(1), (max_y - min_y).max(1));
+                draw_hollow_rect_mut(&mut img, rect, Rgb([0, 0, u16::MAX]));
+            }
+        }
+
         io::save_image(path, &debug_dir_suffix, "border", "jpeg", img)?;
     }
 
-    let border_colors: Vec<&Rgb<u16>> = border_points
-        .iter()
-        .map(|&(x, y)| original.get_pixel(x, y))
-        .collect();
+    let output_bounds = match (options.crop_mode, &border) {
+        _ if !sidecar.steps.crop => whole_image,
+        (CropMode::Frame, _) => (min_x, min_y, max_x, max_y),
+        (CropMode::FullBleed, Some(border)) => full_bleed_bounds(original, border),
+        (CropMode::FullBleed, None) | (CropMode::None, _) => whole_image,
+    };
+
+    let mut output = if output_bounds == whole_image {
+        original.clone()
+    } else {
+        let (out_min_x, out_min_y, out_max_x, out_max_y) = output_bounds;
+        crop_border(original, out_min_x, out_min_y, out_max_x, out_max_y)
+    };
+
+    if let Some(dust_options) = options.dust.as_ref().filter(|_| sidecar.steps.dust) {
+        let mask = dust::detect(&output, ANALYSIS_SIZE, dust_options);
+        dust::inpaint_mut(&mut output, &mask);
 
-    let avg_border_color = Rgb([
-        rms(border_colors.iter().map(|c| c.0[0]).collect()),
-        rms(border_colors.iter().map(|c| c.0[1]).collect()),
-        rms(border_colors.iter().map(|c| c.0[2]).collect()),
-    ]);
+        if let Some(path) = debug_file_path {
+            io::save_image(path, debug_dir_suffix, "dust", "png", mask)?;
+        }
+    }
 
-    let mut output = crop_border(original, min_x, min_y, max_x, max_y);
+    let frame = relative_bounds(output_bounds, (min_x, min_y, max_x, max_y), &output);
 
-    white_balance(&mut output, avg_border_color);
+    let (image, float, adjustments) = if options.float {
+        let mut float = convert_samples(&output);
+        let adjustments = adjust_mut(
+            &mut float,
+            frame,
+            avg_border_color,
+            options,
+            sidecar,
+            debug_file_path,
+            debug_dir_suffix,
+        )?;
+        (convert_samples(&float), Some(float), adjustments)
+    } else {
+        let adjustments = adjust_mut(
+            &mut output,
+            frame,
+            avg_border_color,
+            options,
+            sidecar,
+            debug_file_path,
+            debug_dir_suffix,
+        )?;
+        (output, None, adjustments)
+    };
 
-    invert_mut(&mut output);
+    let sharpen = sidecar.sharpen.as_ref().unwrap_or(&options.sharpen);
+    let applied_sharpen =
+        (sidecar.steps.sharpen && !sharpen.is_identity()).then(|| sharpen.clone());
+
+    Ok(Conversion {
+        image,
+        float,
+        crop: (min_x, min_y, max_x, max_y),
+        frame,
+        base_color: avg_border_color,
+        base_confidence,
+        rotation,
+        levels: adjustments.levels,
+        gains: adjustments.gains,
+        tone: adjustments.tone,
+        color: adjustments.color,
+        sharpen: applied_sharpen,
+        cutoffs: adjustments.cutoffs,
+        histograms: adjustments.histograms,
+    })
+}
+
+/// What `adjust_mut` measured and applied
+struct Adjustments {
+    levels: Option<Levels>,
+    gains: Option<[f32; 3]>,
+    tone: Option<ToneOptions>,
+    color: Option<ColorOptions>,
+    cutoffs: Option<Cutoffs>,
+    histograms: Option<Histograms>,
+}
+
+/// Inverts the cropped negative and adjusts it, from white balancing it with
+/// the film backing color through to the LUT
+fn adjust_mut<S: Sample>(
+    output: &mut RgbBuffer<S>,
+    frame: Bounds,
+    base_color: Rgb<u16>,
+    options: &Options,
+    sidecar: &Sidecar,
+    debug_file_path: Option<&str>,
+    debug_dir_suffix: &Option<String>,
+) -> Result<Adjustments, ImageError> {
+    if sidecar.steps.white_balance {
+        white_balance(output, base_color);
+    }
+
+    if sidecar.steps.invert {
+        invert_mut(output);
+    }
 
     if let Some(path) = debug_file_path {
-        io::save_image(path, &debug_dir_suffix, "inverted", "jpeg", output.clone())?;
+        io::save_image(
+            path,
+            debug_dir_suffix,
+            "inverted",
+            "jpeg",
+            S::fixed(output).into_owned(),
+        )?;
+    }
+
+    let stretch = sidecar.steps.stretch && options.stretch.mode != StretchMode::None;
+    // only use the frame for levels, so that the border doesn't skew them
+    let frame_before = (stretch || options.collect_histograms).then(|| crop_frame(output, frame));
+    let before = frame_before
+        .as_ref()
+        .filter(|_| options.collect_histograms)
+        .map(|img| histogram_rgb(img, 256));
+
+    let mut applied_levels = None;
+    let mut cutoffs = None;
+    if let Some(img) = frame_before.filter(|_| stretch) {
+        let levels = match &options.roll_levels {
+            Some(roll) if roll.max_deviation <= 0.0 => Some(roll.levels.clone()),
+            roll => {
+                cutoffs = find_cutoffs(&img, &options.stretch);
+                cutoffs.as_ref().map(|c| {
+                    let levels = c.levels(&options.stretch);
+                    match roll {
+                        Some(roll) => levels.limit_to(&roll.levels, roll.max_deviation),
+                        None => levels,
+                    }
+                })
+            }
+        };
+        if let Some(levels) = &levels {
+            apply_levels_mut(output, levels);
+        }
+        applied_levels = levels;
+    }
+
+    let balance = sidecar.balance.as_ref().unwrap_or(&options.balance);
+    let gains = if sidecar.steps.balance && !balance.is_identity() {
+        find_gains(&S::fixed(output), frame, balance)
+    } else {
+        None
+    };
+    if let Some(gains) = gains {
+        apply_gains_mut(output, gains);
+    }
+
+    let denoise = sidecar.denoise.as_ref().unwrap_or(&options.denoise);
+    if sidecar.steps.denoise && !denoise.is_identity() {
+        apply_denoise_mut(output, denoise);
+    }
+
+    let tone = sidecar.tone.as_ref().unwrap_or(&options.tone);
+    let applied_tone = (sidecar.steps.tone && !tone.is_identity()).then(|| tone.clone());
+    if let Some(tone) = &applied_tone {
+        apply_tone_mut(output, tone);
     }
 
-    stretch_channels_mut(&mut output);
+    let color = sidecar.color.as_ref().unwrap_or(&options.color);
+    let applied_color = (sidecar.steps.color && !color.is_identity()).then(|| color.clone());
+    if let Some(color) = &applied_color {
+        apply_color_mut(output, color);
+    }
+
+    if let Some(lut) = options.lut.as_ref().filter(|_| sidecar.steps.lut) {
+        apply_lut_mut(output, lut, options.lut_interpolation);
+    }
 
-    Ok(output)
+    let histograms = before.map(|before| Histograms {
+        before,
+        after: histogram_rgb(&crop_frame(output, frame), 256),
+    });
+
+    Ok(Adjustments {
+        levels: applied_levels,
+        gains,
+        tone: applied_tone,
+        color: applied_color,
+        cutoffs,
+        histograms,
+    })
 }
 
-fn identify_border(
-    original: &InputImage,
+/// Scales the converted image down to at most `long_edge` pixels on its
+/// longest side, and sharpens it for that size
+pub fn render(
+    conversion: &Conversion,
+    long_edge: Option<u32>,
     debug_file_path: Option<&str>,
     debug_dir_suffix: &Option<String>,
-) -> Result<Border, ImageError> {
+) -> Result<InputImage, ImageError> {
+    render_image(
+        &conversion.image,
+        conversion.sharpen.as_ref(),
+        long_edge,
+        debug_file_path,
+        debug_dir_suffix,
+    )
+}
+
+/// Like [render], but keeps values outside of 0 to 1. Frames that weren't
+/// converted with `Options::float` are rendered from their 16-bit values.
+pub fn render_float(
+    conversion: &Conversion,
+    long_edge: Option<u32>,
+    debug_file_path: Option<&str>,
+    debug_dir_suffix: &Option<String>,
+) -> Result<FloatImage, ImageError> {
+    let converted;
+    let image = match &conversion.float {
+        Some(float) => float,
+        None => {
+            converted = convert_samples(&conversion.image);
+            &converted
+        }
+    };
+    render_image(
+        image,
+        conversion.sharpen.as_ref(),
+        long_edge,
+        debug_file_path,
+        debug_dir_suffix,
+    )
+}
+
+fn render_image<S: Sample>(
+    image: &RgbBuffer<S>,
+    sharpen: Option<&SharpenOptions>,
+    long_edge: Option<u32>,
+    debug_file_path: Option<&str>,
+    debug_dir_suffix: &Option<String>,
+) -> Result<RgbBuffer<S>, ImageError> {
+    let (width, height) = image.dimensions();
+    let mut image = match long_edge {
+        Some(size) if size < width.max(height) => resize(image, size),
+        _ => image.clone(),
+    };
+
+    if let Some(sharpen) = sharpen {
+        let mask = sharpen.edge_mask.then(|| {
+            let radius = sharpen.radius_for(image.width(), image.height());
+            sharpen::edge_mask(&image, radius)
+        });
+        if let (Some(path), Some(mask)) = (debug_file_path, &mask) {
+            let name = match long_edge {
+                Some(size) if size < width.max(height) => format!("sharpen-{}", size),
+                _ => String::from("sharpen"),
+            };
+            io::save_image(path, debug_dir_suffix, &name, "png", mask.clone())?;
+        }
+        apply_sharpen_mut(&mut image, sharpen, mask.as_ref());
+    }
+
+    Ok(image)
+}
+
+/// Scales `image` down to `size` pixels on its longest side. The image crate
+/// clips floats to 0 to 1 when resizing, so values outside of that range are
+/// scaled into it first.
+fn resize<S: Sample>(image: &RgbBuffer<S>, size: u32) -> RgbBuffer<S> {
+    let (width, height) = image.dimensions();
+    let scale = size as f32 / width.max(height) as f32;
+    let resize = |image: &RgbBuffer<S>| {
+        imageops::resize(
+            image,
+            ((width as f32 * scale).round() as u32).max(1),
+            ((height as f32 * scale).round() as u32).max(1),
+            imageops::FilterType::Lanczos3,
+        )
+    };
+
+    let (min, max) = image
+        .as_raw()
+        .par_iter()
+        .map(|&value| (value.to_unit(), value.to_unit()))
+        .reduce(
+            || (0.0_f32, 1.0_f32),
+            |(min_a, max_a), (min_b, max_b)| (min_a.min(min_b), max_a.max(max_b)),
+        );
+    if min >= 0.0 && max <= 1.0 {
+        return resize(image);
+    }
+
+    let range = max - min;
+    let mut scaled = image.clone();
+    S::map_channels_mut(&mut scaled, |_, value| (value - min) / range);
+    let mut resized = resize(&scaled);
+    S::map_channels_mut(&mut resized, |_, value| value * range + min);
+    resized
+}
+
+/// Applies the color adjustments of `conversion` to `image`, without measuring
+/// anything again, e.g. to sample them into a LUT. `steps` should be the ones
+/// that `conversion` was made with.
+pub fn apply_conversion_mut(image: &mut InputImage, conversion: &Conversion, steps: &Steps) {
+    if steps.white_balance {
+        white_balance(image, conversion.base_color);
+    }
+    if steps.invert {
+        invert_mut(image);
+    }
+    if let Some(levels) = &conversion.levels {
+        apply_levels_mut(image, levels);
+    }
+    if let Some(gains) = conversion.gains {
+        apply_gains_mut(image, gains);
+    }
+    if let Some(tone) = &conversion.tone {
+        apply_tone_mut(image, tone);
+    }
+    if let Some(color) = &conversion.color {
+        apply_color_mut(image, color);
+    }
+}
+
+/// Translates `frame` into the pixels of `output`, which was cropped to
+/// `output_bounds`
+fn relative_bounds<S: Sample>(
+    output_bounds: Bounds,
+    frame: Bounds,
+    output: &RgbBuffer<S>,
+) -> Bounds {
+    let (out_min_x, out_min_y, _, _) = output_bounds;
+    let (min_x, min_y, max_x, max_y) = frame;
+    (
+        min_x.saturating_sub(out_min_x).min(output.width()),
+        min_y.saturating_sub(out_min_y).min(output.height()),
+        max_x.saturating_sub(out_min_x).min(output.width()),
+        max_y.saturating_sub(out_min_y).min(output.height()),
+    )
+}
+
+/// The part of `image` inside `frame`, or all of it if `frame` is empty
+pub fn crop_frame<S: Sample>(image: &RgbBuffer<S>, frame: Bounds) -> RgbBuffer<S> {
+    let (min_x, min_y, max_x, max_y) = frame;
+    let covers_image = frame == (0, 0, image.width(), image.height());
+    if covers_image || min_x >= max_x || min_y >= max_y {
+        image.clone()
+    } else {
+        crop_border(image, min_x, min_y, max_x, max_y)
+    }
+}
+
+/// Rotates vertical film so that it runs horizontally, if sprocket holes can
+/// be found
+fn infer_rotation(original: &InputImage) -> Option<f32> {
+    let img = analysis_image(original);
+    match sprockets::detect(&img, WHITE_LIGHT_THRESHOLD)?.orientation {
+        Orientation::Horizontal => None,
+        Orientation::Vertical => Some(90.0),
+    }
+}
+
+/// Crops to the edges of the film if sprocket holes were found, or to the
+/// border otherwise
+fn full_bleed_bounds(original: &InputImage, border: &Border) -> Bounds {
+    let (min_x, min_y, max_x, max_y) = border.bounds;
+    match &border.sprockets {
+        Some(sprockets) => {
+            let (film_min, film_max) = sprockets.film_area;
+            match sprockets.orientation {
+                Orientation::Horizontal => {
+                    (min_x, film_min, max_x, film_max.min(original.height()))
+                }
+                Orientation::Vertical => (film_min, min_y, film_max.min(original.width()), max_y),
+            }
+        }
+        None => border.bounds,
+    }
+}
+
+/// Estimates the film backing color from the border, falling back to
+/// `options.fallback_base_color` if the estimate is unreliable.
+fn estimate_base(
+    original: &InputImage,
+    border: &Border,
+    options: &Options,
+) -> Result<base::Estimate, ImageError> {
+    let candidates: Vec<Rgb<u16>> = border
+        .candidates
+        .iter()
+        .map(|&(x, y)| *original.get_pixel(x, y))
+        .collect();
+
+    match (base::estimate(&candidates), options.fallback_base_color) {
+        (Some(estimate), Some(fallback)) if estimate.confidence < options.min_base_confidence => {
+            println!(
+                "Warning: low confidence in the detected film base color ({:.2}), using the fallback color instead",
+                estimate.confidence
+            );
+            Ok(base::Estimate {
+                color: fallback,
+                inliers: vec![],
+                ..estimate
+            })
+        }
+        (Some(estimate), None) if estimate.confidence < options.min_base_confidence => {
+            println!(
+                "Warning: low confidence in the detected film base color ({:.2}). Consider setting --base-color, --base-profile or --base-region",
+                estimate.confidence
+            );
+            Ok(estimate)
+        }
+        (Some(estimate), _) => Ok(estimate),
+        (None, Some(fallback)) => {
+            println!("Warning: unable to detect the film base color, using the fallback color instead");
+            Ok(base::Estimate {
+                color: fallback,
+                confidence: 0.0,
+                inliers: vec![],
+            })
+        }
+        (None, None) => Err(parameter_error(
+            "unable to detect the film base color. Set --base-color, --base-profile or --base-region"
+                .to_owned(),
+        )),
+    }
+}
+
+/// Rotates clockwise by `degrees`. Multiples of 90 degrees are lossless.
+fn rotate(img: &InputImage, degrees: f32) -> InputImage {
+    match degrees.rem_euclid(360.0) {
+        0.0 => img.clone(),
+        90.0 => imageops::rotate90(img),
+        180.0 => imageops::rotate180(img),
+        270.0 => imageops::rotate270(img),
+        degrees => rotate_about_center(
+            img,
+            degrees.to_radians(),
+            Interpolation::Bilinear,
+            Rgb([0, 0, 0]),
+        ),
+    }
+}
+
+/// Checks that `[min_x, min_y, max_x, max_y]` is a non-empty rectangle inside `img`
+fn validate_bounds(
+    img: &InputImage,
+    [min_x, min_y, max_x, max_y]: [u32; 4],
+) -> Result<Bounds, ImageError> {
+    if min_x >= max_x || min_y >= max_y || max_x > img.width() || max_y > img.height() {
+        return Err(parameter_error(format!(
+            "rectangle [{}, {}, {}, {}] is empty or outside of the {}x{} image",
+            min_x,
+            min_y,
+            max_x,
+            max_y,
+            img.width(),
+            img.height()
+        )));
+    }
+
+    Ok((min_x, min_y, max_x, max_y))
+}
+
+fn parameter_error(message: String) -> ImageError {
+    ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::Generic(
+        message,
+    )))
+}
+
+/// A smaller, grayscale version of the image with a normalized histogram, for
+/// faster and more consistent analysis
+fn analysis_image(original: &InputImage) -> GrayImage {
     let mut img: DynamicImage = original.clone().into();
 
-    if original.width() > 500 || original.height() > 500 {
+    if original.width() > ANALYSIS_SIZE || original.height() > ANALYSIS_SIZE {
         // use a smaller image for faster processing
-        img = img.resize(500, 500, imageops::FilterType::Triangle);
+        img = img.resize(ANALYSIS_SIZE, ANALYSIS_SIZE, imageops::FilterType::Triangle);
     }
 
     // convert to grayscale
     let mut img: GrayImage = img.to_luma8();
 
-    // 1. normalize histogram for more consistent black/white values
+    // normalize histogram for more consistent black/white values
     normalize_histogram_mut(&mut img);
 
+    img
+}
+
+fn identify_border(
+    original: &InputImage,
+    detect_sprockets: bool,
+    debug_file_path: Option<&str>,
+    debug_dir_suffix: &Option<String>,
+) -> Result<Border, ImageError> {
+    // 1. downsample, convert to grayscale, and normalize
+    let mut img = analysis_image(original);
+    let normalized = img.clone();
+
     if let Some(path) = debug_file_path {
         io::save_image(path, &debug_dir_suffix, "grayscale", "jpeg", img.clone())?;
     }
 
-    // 2. zero out any black borders or light from sprocket holes
+    let sprockets = if detect_sprockets {
+        sprockets::detect(&img, WHITE_LIGHT_THRESHOLD)
+    } else {
+        None
+    };
+
+    // 2. zero out any black borders, light from sprocket holes, and the rest of
+    // the rebate if it was found
     img = map_colors(&img, |p| {
         if p.0[0] < BLACK_BORDER_THRESHOLD || p.0[0] > WHITE_LIGHT_THRESHOLD {
             Luma([0])
@@ -120,6 +902,13 @@ fn identify_border(
             p
         }
     });
+    if let Some(sprockets) = &sprockets {
+        for (x, y, p) in img.enumerate_pixels_mut() {
+            if sprockets.outside_image_area(x, y) {
+                *p = Luma([0]);
+            }
+        }
+    }
 
     // 3. the brightest values should now mainly be from the film backing.
     // re-normalize these values, since they should be brighter now
@@ -186,6 +975,10 @@ fn identify_border(
         })
         .collect();
 
+    if points.is_empty() {
+        return Err(parameter_error("unable to find the film border".to_owned()));
+    }
+
     let corners: [Point<i32>; 4] = min_area_rect(&points);
 
     let min_x = corners.map(|c| c.x).into_iter().min().unwrap().max(0) as u32;
@@ -193,7 +986,12 @@ fn identify_border(
     let max_x = corners.map(|c| c.x).into_iter().max().unwrap().max(0) as u32;
     let max_y = corners.map(|c| c.y).into_iter().max().unwrap().max(0) as u32;
 
-    let points = identify_border_points(min_x, min_y, max_x, max_y, &borderless);
+    let candidates = match &sprockets {
+        Some(sprockets) => {
+            identify_rebate_candidates(min_x, min_y, max_x, max_y, sprockets, &normalized)
+        }
+        None => identify_border_candidates(min_x, min_y, max_x, max_y, &borderless),
+    };
 
     let scale_x = |x: u32| (x as f32 * original.width() as f32 / img.width() as f32) as u32;
     let scale_y = |y: u32| (y as f32 * original.height() as f32 / img.height() as f32) as u32;
@@ -205,51 +1003,59 @@ fn identify_border(
             scale_x(max_x),
             scale_y(max_y),
         ),
-        points: points
+        candidates: candidates
             .into_iter()
             .map(|(x, y)| (scale_x(x), scale_y(y)))
             .collect(),
+        sprockets: sprockets.map(|sprockets| sprockets.scale(scale_x, scale_y)),
     })
 }
 
-fn identify_border_points(
+/// Finds pixels near the corners of the border, excluding the black border
+/// and sprocket holes masked out by `identify_border`
+fn identify_border_candidates(
     min_x: u32,
     min_y: u32,
     max_x: u32,
     max_y: u32,
     img: &GrayImage,
 ) -> Vec<(u32, u32)> {
-    let mut pixel_positions: HashMap<u8, Vec<(u32, u32)>> = HashMap::new();
-    let mut hist = vec![0u8; 256];
-
     let gap_x = (img.width() as f32 * 0.05) as u32;
     let gap_y = (img.height() as f32 * 0.05) as u32;
 
-    let mut i = 0;
-    for &p in img.iter() {
-        let x = i % img.width();
-        let y = i / img.width();
-        if (x < min_x + gap_x || x > max_x - gap_x)
-            && (y < min_y + gap_y || y > max_y - gap_y)
-            && p != 255
-        {
-            hist[p as usize] += 1;
-            pixel_positions
-                .entry(p)
-                .and_modify(|positions| positions.push((x, y)))
-                .or_insert(vec![(x, y)]);
-        }
-        i += 1;
-    }
-
-    let border_value = hist
-        .iter()
-        .enumerate()
-        .max_by(|(_, a), (_, b)| a.cmp(b))
-        .map(|(i, _)| i)
-        .unwrap() as u8;
+    img.enumerate_pixels()
+        .filter(|&(x, y, p)| {
+            (x < min_x + gap_x || x > max_x.saturating_sub(gap_x))
+                && (y < min_y + gap_y || y > max_y.saturating_sub(gap_y))
+                && p.0[0] != 255
+        })
+        .map(|(x, y, _)| (x, y))
+        .collect()
+}
 
-    pixel_positions.remove(&border_value).unwrap()
+/// Finds pixels in the rebate alongside the frame, excluding sprocket holes
+/// and the black border
+fn identify_rebate_candidates(
+    min_x: u32,
+    min_y: u32,
+    max_x: u32,
+    max_y: u32,
+    sprockets: &Sprockets,
+    img: &GrayImage,
+) -> Vec<(u32, u32)> {
+    img.enumerate_pixels()
+        .filter(|&(x, y, p)| {
+            let alongside = match sprockets.orientation {
+                Orientation::Horizontal => (min_x..=max_x).contains(&x),
+                Orientation::Vertical => (min_y..=max_y).contains(&y),
+            };
+            alongside
+                && sprockets.in_rebate(x, y)
+                && p.0[0] >= BLACK_BORDER_THRESHOLD
+                && p.0[0] <= WHITE_LIGHT_THRESHOLD
+        })
+        .map(|(x, y, _)| (x, y))
+        .collect()
 }
 
 /// root mean square
@@ -258,41 +1064,32 @@ fn rms(values: Vec<u16>) -> u16 {
     (sum as f32 / values.len() as f32).sqrt() as u16
 }
 
-fn crop_border(img: &InputImage, min_x: u32, min_y: u32, max_x: u32, max_y: u32) -> InputImage {
+fn crop_border<S: Sample>(
+    img: &RgbBuffer<S>,
+    min_x: u32,
+    min_y: u32,
+    max_x: u32,
+    max_y: u32,
+) -> RgbBuffer<S> {
     crop_imm(img, min_x, min_y, max_x - min_x, max_y - min_y).to_image()
 }
 
 /// Combines these approaches:
 /// https://stackoverflow.com/questions/54470148/white-balance-a-photo-from-a-known-point
 /// https://stackoverflow.com/questions/596216/formula-to-determine-perceived-brightness-of-rgb-color
-fn white_balance(img: &mut InputImage, white_color: Rgb<u16>) {
+fn white_balance<S: Sample>(img: &mut RgbBuffer<S>, white_color: Rgb<u16>) {
     let lum = rms(vec![
         (0.299_f32.sqrt() * white_color.0[0] as f32) as u16,
         (0.587_f32.sqrt() * white_color.0[1] as f32) as u16,
         (0.114_f32.sqrt() * white_color.0[2] as f32) as u16,
     ]) as f32;
 
-    let ratio_r = lum / white_color.0[0] as f32;
-    let ratio_g = lum / white_color.0[1] as f32;
-    let ratio_b = lum / white_color.0[2] as f32;
-
-    map_colors_mut(img, |p| {
-        Rgb([
-            (p.0[0] as f32 * ratio_r) as u16,
-            (p.0[1] as f32 * ratio_g) as u16,
-            (p.0[2] as f32 * ratio_b) as u16,
-        ])
-    })
+    let ratios = white_color.0.map(|value| lum / value as f32);
+    S::map_channels_mut(img, |channel, value| value * ratios[channel]);
 }
 
-fn invert_mut(img: &mut InputImage) {
-    map_colors_mut(img, |p| {
-        Rgb([
-            (u16::MAX - p.0[0]),
-            (u16::MAX - p.0[1]),
-            (u16::MAX - p.0[2]),
-        ])
-    });
+fn invert_mut<S: Sample>(img: &mut RgbBuffer<S>) {
+    S::map_channels_mut(img, |_, value| 1.0 - value);
 }
 
 pub fn split_image(img: InputImage) -> [InputImage; 2] {
@@ -0,0 +1,290 @@
+use std::ops::Range;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::conversion::{RgbBuffer, Sample};
+
+/// Range sigma at a strength of 100, in values from 0 to 1
+const MAX_LUMA_SIGMA: f32 = 0.08;
+const MAX_CHROMA_SIGMA: f32 = 0.15;
+/// Luma difference that keeps colors from spreading across edges
+const CHROMA_EDGE_SIGMA: f32 = 0.1;
+/// Color noise is blotchier than luma noise, so chroma is averaged over a
+/// larger area, sampling every few pixels to keep it fast
+const CHROMA_RADIUS_SCALE: u32 = 2;
+const MAX_RADIUS: u32 = 16;
+/// Resolution of the range weight tables, which cover 4 sigma
+const RANGE_STEPS: usize = 1024;
+
+/// Noise reduction after stretching, with an edge-preserving (bilateral)
+/// filter. Luma and chroma are filtered separately, so that color noise can be
+/// removed while keeping film grain.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DenoiseOptions {
+    /// From 0 to 100. Film grain is luma noise, so low values keep it.
+    pub luma: f32,
+    /// From 0 to 100. Film grain has little color noise, so this mostly
+    /// removes sensor noise.
+    pub chroma: f32,
+    /// Radius of the neighborhood that luma is averaged over, in pixels.
+    /// Chroma uses a larger one.
+    pub radius: u32,
+}
+
+impl Default for DenoiseOptions {
+    fn default() -> Self {
+        Self {
+            luma: 0.0,
+            chroma: 0.0,
+            radius: 2,
+        }
+    }
+}
+
+impl DenoiseOptions {
+    pub fn is_identity(&self) -> bool {
+        self.luma <= 0.0 && self.chroma <= 0.0
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=100.0).contains(&self.luma) || !(0.0..=100.0).contains(&self.chroma) {
+            return Err(format!(
+                "denoise strengths ({} luma, {} chroma) should be from 0 to 100",
+                self.luma, self.chroma
+            ));
+        }
+        if !(1..=MAX_RADIUS).contains(&self.radius) {
+            return Err(format!(
+                "denoise radius ({}) should be from 1 to {}",
+                self.radius, MAX_RADIUS
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Weights of the neighbors within `radius` steps of `stride` pixels, by
+/// distance
+fn kernel(radius: u32, stride: u32) -> Vec<(i32, i32, f32)> {
+    let sigma = radius as f32 / 2.0;
+    let steps = radius as i32;
+    let mut offsets = vec![];
+    for dy in -steps..=steps {
+        for dx in -steps..=steps {
+            let distance_sq = (dx * dx + dy * dy) as f32;
+            if distance_sq <= (steps * steps) as f32 {
+                let weight = (-distance_sq / (2.0 * sigma * sigma)).exp();
+                offsets.push((dx * stride as i32, dy * stride as i32, weight));
+            }
+        }
+    }
+    offsets
+}
+
+/// Gaussian weights of differences up to 4 sigma, looked up instead of
+/// computed for every pair of pixels
+struct RangeWeights {
+    table: Vec<f32>,
+    scale: f32,
+}
+
+impl RangeWeights {
+    fn new(sigma: f32) -> Self {
+        let max = 4.0 * sigma;
+        let table = (0..RANGE_STEPS)
+            .map(|i| {
+                let difference = i as f32 / RANGE_STEPS as f32 * max;
+                (-difference * difference / (2.0 * sigma * sigma)).exp()
+            })
+            .collect();
+        Self {
+            table,
+            scale: RANGE_STEPS as f32 / max,
+        }
+    }
+
+    fn weight(&self, difference: f32) -> f32 {
+        self.table
+            .get((difference * self.scale) as usize)
+            .copied()
+            .unwrap_or(0.0)
+    }
+}
+
+/// Luma and two color differences of (gamma encoded) values
+fn to_ycc<S: Sample>(rgb: [S; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(|v| v.to_unit());
+    let y = 0.25 * r + 0.5 * g + 0.25 * b;
+    [y, b - y, r - y]
+}
+
+fn from_ycc<S: Sample>([y, cb, cr]: [f32; 3]) -> [S; 3] {
+    let r = y + cr;
+    let b = y + cb;
+    let g = (y - 0.25 * r - 0.25 * b) / 0.5;
+    [r, g, b].map(S::from_unit)
+}
+
+/// Averages `channels` of each pixel with its neighbors in `kernel`, weighted
+/// by how `similar` they are to it
+fn bilateral(
+    ycc: &[[f32; 3]],
+    width: usize,
+    kernel: &[(i32, i32, f32)],
+    channels: Range<usize>,
+    similar: impl Fn(&[f32; 3], &[f32; 3]) -> f32 + Sync,
+) -> Vec<[f32; 3]> {
+    let height = (ycc.len() / width) as i32;
+    let mut filtered = ycc.to_vec();
+
+    filtered
+        .par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let center = &ycc[y * width + x];
+                let mut sum = [0.0; 3];
+                let mut total = 0.0;
+                for &(dx, dy, spatial) in kernel {
+                    let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                    if nx < 0 || ny < 0 || nx >= width as i32 || ny >= height {
+                        continue;
+                    }
+                    let neighbor = &ycc[ny as usize * width + nx as usize];
+                    let weight = spatial * similar(center, neighbor);
+                    for channel in channels.clone() {
+                        sum[channel] += weight * neighbor[channel];
+                    }
+                    total += weight;
+                }
+                // the pixel itself always has a weight of 1
+                for channel in channels.clone() {
+                    pixel[channel] = sum[channel] / total;
+                }
+            }
+        });
+
+    filtered
+}
+
+pub fn apply_denoise_mut<S: Sample>(image: &mut RgbBuffer<S>, options: &DenoiseOptions) {
+    let width = image.width() as usize;
+    let mut ycc: Vec<[f32; 3]> = image.par_pixels().map(|p| to_ycc(S::values(p))).collect();
+
+    if options.luma > 0.0 {
+        let range = RangeWeights::new(options.luma / 100.0 * MAX_LUMA_SIGMA);
+        ycc = bilateral(&ycc, width, &kernel(options.radius, 1), 0..1, |a, b| {
+            range.weight((a[0] - b[0]).abs())
+        });
+    }
+
+    if options.chroma > 0.0 {
+        let range = RangeWeights::new(options.chroma / 100.0 * MAX_CHROMA_SIGMA);
+        let edges = RangeWeights::new(CHROMA_EDGE_SIGMA);
+        let kernel = kernel(options.radius, CHROMA_RADIUS_SCALE);
+        ycc = bilateral(&ycc, width, &kernel, 1..3, |a, b| {
+            range.weight((a[1] - b[1]).hypot(a[2] - b[2])) * edges.weight((a[0] - b[0]).abs())
+        });
+    }
+
+    image
+        .par_pixels_mut()
+        .zip(ycc.par_iter())
+        .for_each(|(pixel, &color)| *pixel = from_ycc::<S>(color).into());
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+    use crate::conversion::FloatImage;
+
+    fn denoise(luma: f32, chroma: f32) -> DenoiseOptions {
+        DenoiseOptions {
+            luma,
+            chroma,
+            ..DenoiseOptions::default()
+        }
+    }
+
+    /// A gray image with noise from -0.02 to 0.02 in `channel`
+    fn noisy(channel: usize) -> FloatImage {
+        FloatImage::from_fn(32, 32, |x, y| {
+            let mut pixel = [0.5; 3];
+            pixel[channel] += ((x * 73 + y * 151) % 17) as f32 / 400.0 - 0.02;
+            Rgb(pixel)
+        })
+    }
+
+    fn deviation(image: &FloatImage, channel: usize) -> f32 {
+        let count = image.pixels().len() as f32;
+        let mean = image.pixels().map(|p| p.0[channel]).sum::<f32>() / count;
+        let variance = image
+            .pixels()
+            .map(|p| (p.0[channel] - mean).powi(2))
+            .sum::<f32>()
+            / count;
+        variance.sqrt()
+    }
+
+    #[test]
+    fn validates_options() {
+        assert!(denoise(30.0, 60.0).validate().is_ok());
+        assert!(denoise(-1.0, 0.0).validate().is_err());
+        assert!(denoise(0.0, 101.0).validate().is_err());
+        let radius = |radius| DenoiseOptions {
+            radius,
+            ..denoise(30.0, 0.0)
+        };
+        assert!(radius(0).validate().unwrap_err().contains("radius"));
+        assert!(
+            radius(MAX_RADIUS + 1)
+                .validate()
+                .unwrap_err()
+                .contains("radius")
+        );
+        assert!(denoise(0.0, 0.0).is_identity());
+    }
+
+    #[test]
+    fn ycc_round_trips() {
+        for rgb in [[0.0, 0.0, 0.0], [1.0, 0.5, 0.25], [0.1, 0.9, 0.4]] {
+            let back: [f32; 3] = from_ycc(to_ycc(rgb));
+            for (a, b) in rgb.iter().zip(back) {
+                assert!((a - b).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn smooths_noise() {
+        let mut image = noisy(1);
+        apply_denoise_mut(&mut image, &denoise(100.0, 100.0));
+        assert!(deviation(&image, 1) < deviation(&noisy(1), 1) / 4.0);
+    }
+
+    #[test]
+    fn chroma_only_keeps_grain() {
+        // the same noise in every channel is luma, like film grain
+        let grain = FloatImage::from_fn(32, 32, |x, y| {
+            Rgb([if (x + y) % 2 == 0 { 0.52 } else { 0.48 }; 3])
+        });
+        let mut image = grain.clone();
+        apply_denoise_mut(&mut image, &denoise(0.0, 100.0));
+        for (a, b) in image.pixels().zip(grain.pixels()) {
+            assert!((a.0[0] - b.0[0]).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn keeps_edges() {
+        let edge = FloatImage::from_fn(32, 32, |x, _| Rgb([if x < 16 { 0.1 } else { 0.9 }; 3]));
+        let mut image = edge.clone();
+        apply_denoise_mut(&mut image, &denoise(100.0, 100.0));
+        assert!((image.get_pixel(15, 16).0[0] - 0.1).abs() < 1e-3);
+        assert!((image.get_pixel(16, 16).0[0] - 0.9).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::io::{BufReader, Seek, Write};
+use std::path::Path;
+
+use image::imageops::{self, FilterType};
+use rayon::prelude::*;
+use tiff::decoder::Decoder;
+use tiff::encoder::{DirectoryEncoder, Ifd, Rational, SRational, TiffEncoder, TiffKindStandard};
+use tiff::tags::Tag;
+
+use crate::conversion::InputImage;
+use crate::output::{dither, write_atomically};
+use crate::raw_processor::Capture;
+use crate::tone::srgb_decode;
+
+/// Software tag of the DNGs that yancy saves, which tells them apart from RAW
+/// inputs
+const SOFTWARE: &str = "yancy";
+
+/// Longest side of the preview that is shown by readers that don't render
+/// the image themselves
+const PREVIEW_SIZE: u32 = 1024;
+
+const DNG_VERSION: [u8; 4] = [1, 4, 0, 0];
+/// Readers need to support DNG 1.1 for linear images
+const DNG_BACKWARD_VERSION: [u8; 4] = [1, 1, 0, 0];
+
+const PHOTOMETRIC_RGB: u16 = 2;
+const PHOTOMETRIC_LINEAR_RAW: u16 = 34892;
+const ILLUMINANT_D65: u16 = 21;
+const PREVIEW_COLOR_SPACE_SRGB: u32 = 1;
+
+/// Maps XYZ to linear sRGB, which is what the image holds, under D65
+const COLOR_MATRIX: [f64; 9] = [
+    3.2404542, -1.5371385, -0.4985314, //
+    -0.9692660, 1.8760108, 0.0415560, //
+    0.0556434, -0.2040259, 1.0572252,
+];
+/// Maps white balanced linear sRGB to XYZ under D50, adapted with Bradford
+const FORWARD_MATRIX: [f64; 9] = [
+    0.4360747, 0.3850649, 0.1430804, //
+    0.2225045, 0.7168786, 0.0606169, //
+    0.0139322, 0.0971045, 0.7141733,
+];
+
+// tags that the tiff crate doesn't name
+const TAG_EXPOSURE_TIME: u16 = 33434;
+const TAG_F_NUMBER: u16 = 33437;
+const TAG_ISO: u16 = 34855;
+const TAG_DATE_TIME_ORIGINAL: u16 = 36867;
+const TAG_FOCAL_LENGTH: u16 = 37386;
+const TAG_LENS_MODEL: u16 = 42036;
+const TAG_DNG_VERSION: u16 = 50706;
+const TAG_DNG_BACKWARD_VERSION: u16 = 50707;
+const TAG_UNIQUE_CAMERA_MODEL: u16 = 50708;
+const TAG_BLACK_LEVEL: u16 = 50714;
+const TAG_WHITE_LEVEL: u16 = 50717;
+const TAG_COLOR_MATRIX_1: u16 = 50721;
+const TAG_AS_SHOT_NEUTRAL: u16 = 50728;
+const TAG_BASELINE_EXPOSURE: u16 = 50730;
+const TAG_CALIBRATION_ILLUMINANT_1: u16 = 50778;
+const TAG_FORWARD_MATRIX_1: u16 = 50964;
+const TAG_PREVIEW_COLOR_SPACE: u16 = 50970;
+
+type Directory<'a, W> = DirectoryEncoder<'a, W, TiffKindStandard>;
+
+/// Saves the converted image as a linear DNG, so that raw editors treat it
+/// as scene-referred. The main IFD holds an sRGB preview and the capture
+/// settings, and the full image is in a sub-IFD, with color matrices for
+/// linear sRGB.
+pub fn save(
+    path: &str,
+    image: &InputImage,
+    capture: &Capture,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (width, height) = image.dimensions();
+    let decode: Vec<u16> = (0..=u16::MAX)
+        .map(|value| (srgb_decode(value as f32 / u16::MAX as f32) * u16::MAX as f32).round() as u16)
+        .collect();
+    let linear: Vec<u16> = image
+        .as_raw()
+        .par_iter()
+        .map(|&value| decode[value as usize])
+        .collect();
+    let scale = PREVIEW_SIZE as f32 / width.max(height) as f32;
+    let preview = if scale < 1.0 {
+        imageops::resize(
+            image,
+            ((width as f32 * scale).round() as u32).max(1),
+            ((height as f32 * scale).round() as u32).max(1),
+            FilterType::Triangle,
+        )
+    } else {
+        image.clone()
+    };
+    let preview = dither(&preview);
+
+    write_atomically(path, |writer| {
+        let mut tiff = TiffEncoder::new(writer)?;
+
+        let mut raw = tiff.extra_directory()?;
+        write_strip(&mut raw, width, height, 16, linear.as_slice())?;
+        raw.write_tag(Tag::NewSubfileType, 0_u32)?;
+        raw.write_tag(Tag::PhotometricInterpretation, PHOTOMETRIC_LINEAR_RAW)?;
+        raw.write_tag(Tag::Unknown(TAG_BLACK_LEVEL), [0_u16; 3])?;
+        raw.write_tag(Tag::Unknown(TAG_WHITE_LEVEL), [u16::MAX; 3])?;
+        let raw_offset = raw.finish_with_offsets()?.offset;
+
+        let exif_offset = {
+            let mut exif = tiff.extra_directory()?;
+            if let Some(time) = capture.exposure_time {
+                exif.write_tag(Tag::Unknown(TAG_EXPOSURE_TIME), exposure_rational(time))?;
+            }
+            if let Some(f_number) = capture.f_number {
+                exif.write_tag(Tag::Unknown(TAG_F_NUMBER), rational(f_number as f64))?;
+            }
+            if let Some(iso) = capture.iso {
+                exif.write_tag(
+                    Tag::Unknown(TAG_ISO),
+                    iso.round().min(u16::MAX as f32) as u16,
+                )?;
+            }
+            if let Some(date_time) = &capture.date_time {
+                exif.write_tag(Tag::Unknown(TAG_DATE_TIME_ORIGINAL), date_time.as_str())?;
+            }
+            if let Some(focal_length) = capture.focal_length {
+                exif.write_tag(
+                    Tag::Unknown(TAG_FOCAL_LENGTH),
+                    rational(focal_length as f64),
+                )?;
+            }
+            if !capture.lens.is_empty() {
+                exif.write_tag(Tag::Unknown(TAG_LENS_MODEL), capture.lens.as_str())?;
+            }
+            exif.write_tag(Tag::ExifVersion, b"0231".as_slice())?;
+            exif.finish_with_offsets()?.offset
+        };
+
+        let mut main = tiff.image_directory()?;
+        write_strip(
+            &mut main,
+            preview.width(),
+            preview.height(),
+            8,
+            preview.as_raw().as_slice(),
+        )?;
+        main.write_tag(Tag::NewSubfileType, 1_u32)?;
+        main.write_tag(Tag::PhotometricInterpretation, PHOTOMETRIC_RGB)?;
+        main.write_tag(Tag::SubIfd, Ifd(raw_offset))?;
+        main.write_tag(Tag::ExifDirectory, Ifd(exif_offset))?;
+        main.write_tag(Tag::Software, SOFTWARE)?;
+        if !capture.make.is_empty() {
+            main.write_tag(Tag::Make, capture.make.as_str())?;
+        }
+        if !capture.model.is_empty() {
+            main.write_tag(Tag::Model, capture.model.as_str())?;
+        }
+        if let Some(date_time) = &capture.date_time {
+            main.write_tag(Tag::DateTime, date_time.as_str())?;
+        }
+
+        let camera = format!("{} {}", capture.make, capture.model);
+        let camera = match camera.trim() {
+            "" => "yancy",
+            camera => camera,
+        };
+        main.write_tag(Tag::Unknown(TAG_DNG_VERSION), DNG_VERSION.as_slice())?;
+        main.write_tag(
+            Tag::Unknown(TAG_DNG_BACKWARD_VERSION),
+            DNG_BACKWARD_VERSION.as_slice(),
+        )?;
+        main.write_tag(Tag::Unknown(TAG_UNIQUE_CAMERA_MODEL), camera)?;
+        main.write_tag(
+            Tag::Unknown(TAG_COLOR_MATRIX_1),
+            matrix(&COLOR_MATRIX).as_slice(),
+        )?;
+        main.write_tag(
+            Tag::Unknown(TAG_FORWARD_MATRIX_1),
+            matrix(&FORWARD_MATRIX).as_slice(),
+        )?;
+        main.write_tag(Tag::Unknown(TAG_CALIBRATION_ILLUMINANT_1), ILLUMINANT_D65)?;
+        // the image is already white balanced
+        let neutral = [1, 1, 1].map(|n| Rational { n, d: 1 });
+        main.write_tag(Tag::Unknown(TAG_AS_SHOT_NEUTRAL), neutral.as_slice())?;
+        main.write_tag(
+            Tag::Unknown(TAG_BASELINE_EXPOSURE),
+            SRational { n: 0, d: 1 },
+        )?;
+        main.write_tag(
+            Tag::Unknown(TAG_PREVIEW_COLOR_SPACE),
+            PREVIEW_COLOR_SPACE_SRGB,
+        )?;
+        main.finish()?;
+        Ok(())
+    })?;
+
+    println!("Saved (16-bit linear DNG) {}", path);
+    Ok(())
+}
+
+/// Whether the file at `path` is a DNG that yancy saved, e.g. an output of a
+/// previous run, rather than a RAW file
+pub fn is_own_output(path: &Path) -> bool {
+    if !path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("dng"))
+    {
+        return false;
+    }
+    File::open(path)
+        .ok()
+        .and_then(|file| Decoder::new(BufReader::new(file)).ok())
+        .and_then(|mut decoder| decoder.get_tag_ascii_string(Tag::Software).ok())
+        .is_some_and(|software| software.trim_end_matches('\0') == SOFTWARE)
+}
+
+/// Writes `data` as a single uncompressed strip of RGB pixels, along with the
+/// tags that describe it
+fn write_strip<W: Write + Seek, T>(
+    dir: &mut Directory<W>,
+    width: u32,
+    height: u32,
+    bits: u16,
+    data: &[T],
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    [T]: tiff::encoder::TiffValue,
+{
+    let offset = dir.write_data(data)?;
+    dir.write_tag(Tag::ImageWidth, width)?;
+    dir.write_tag(Tag::ImageLength, height)?;
+    dir.write_tag(Tag::BitsPerSample, [bits; 3])?;
+    dir.write_tag(Tag::Compression, 1_u16)?;
+    dir.write_tag(Tag::SamplesPerPixel, 3_u16)?;
+    dir.write_tag(Tag::PlanarConfiguration, 1_u16)?;
+    dir.write_tag(Tag::RowsPerStrip, height)?;
+    dir.write_tag(Tag::StripOffsets, u32::try_from(offset)?)?;
+    let byte_count = width as u64 * height as u64 * 3 * bits as u64 / 8;
+    dir.write_tag(Tag::StripByteCounts, u32::try_from(byte_count)?)?;
+    Ok(())
+}
+
+fn rational(value: f64) -> Rational {
+    Rational {
+        n: (value * 100.0).round() as u32,
+        d: 100,
+    }
+}
+
+/// Exposure times are written as fractions of a second, e.g. 1/250
+fn exposure_rational(seconds: f32) -> Rational {
+    if seconds < 1.0 {
+        Rational {
+            n: 1,
+            d: (1.0 / seconds).round() as u32,
+        }
+    } else {
+        rational(seconds as f64)
+    }
+}
+
+fn matrix(values: &[f64; 9]) -> Vec<SRational> {
+    const DENOMINATOR: i32 = 10000;
+    values
+        .iter()
+        .map(|&v| SRational {
+            n: (v * DENOMINATOR as f64).round() as i32,
+            d: DENOMINATOR,
+        })
+        .collect()
+}
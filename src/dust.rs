@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+
+use image::{GrayImage, Luma, Rgb};
+use imageproc::definitions::Image;
+use imageproc::distance_transform::Norm;
+use imageproc::morphology::{Mask, dilate, grayscale_close};
+use imageproc::region_labelling::{Connectivity, connected_components};
+
+use crate::conversion::{Bounds, InputImage};
+
+/// Contrast that a defect needs at a sensitivity of 0 and 1, as 8-bit values
+/// after normalizing each window
+const MIN_CONTRAST: f32 = 72.0;
+const MAX_CONTRAST: f32 = 8.0;
+/// The analysis image is noisier, so it takes less contrast to be a candidate
+/// there
+const ANALYSIS_CONTRAST_SCALE: f32 = 0.5;
+const MAX_RADIUS: u32 = 127;
+
+/// Removal of dust and other small defects, which block light and show up as
+/// dark specks on the negative
+#[derive(Clone, Debug)]
+pub struct DustOptions {
+    /// From 0 to 1. Higher values find fainter defects, but are more likely
+    /// to remove details.
+    pub sensitivity: f32,
+    /// Largest defect that is removed, in pixels of the original
+    pub max_size: u32,
+}
+
+impl DustOptions {
+    fn contrast(&self) -> u8 {
+        let sensitivity = self.sensitivity.clamp(0.0, 1.0);
+        (MIN_CONTRAST + (MAX_CONTRAST - MIN_CONTRAST) * sensitivity) as u8
+    }
+}
+
+/// Finds defects in a negative, and returns a mask that is white where they
+/// are.
+///
+/// Candidates are found in a smaller version of `image`, with
+/// `analysis_size` pixels on its longest side, and refined at full resolution
+/// around each of them.
+pub fn detect(image: &InputImage, analysis_size: u32, options: &DustOptions) -> GrayImage {
+    let (width, height) = image.dimensions();
+    let analysis = analysis_image(image, analysis_size);
+    let scale_x = width as f32 / analysis.width() as f32;
+    let scale_y = height as f32 / analysis.height() as f32;
+
+    let analysis_max_size = (options.max_size as f32 / scale_x.max(scale_y)).ceil() as u32;
+    let analysis_contrast = (options.contrast() as f32 * ANALYSIS_CONTRAST_SCALE) as u8;
+    let candidates = find_defects(&analysis, analysis_max_size.max(1), analysis_contrast);
+
+    let margin = options.max_size;
+    let mut mask = GrayImage::new(width, height);
+    for (min_x, min_y, max_x, max_y) in candidates {
+        let window = (
+            ((min_x as f32 * scale_x) as u32).saturating_sub(margin),
+            ((min_y as f32 * scale_y) as u32).saturating_sub(margin),
+            (((max_x + 1) as f32 * scale_x) as u32 + margin).min(width),
+            (((max_y + 1) as f32 * scale_y) as u32 + margin).min(height),
+        );
+        let luma = window_luma(image, window);
+        for (x, y, p) in defect_mask(&luma, options.max_size, options.contrast()).enumerate_pixels()
+        {
+            if p.0[0] > 0 {
+                mask.put_pixel(window.0 + x, window.1 + y, Luma([u8::MAX]));
+            }
+        }
+    }
+
+    mask
+}
+
+/// Smaller grayscale version of `image`, which keeps the darkest pixel of each
+/// block so that specks don't disappear when they're smaller than a block
+fn analysis_image(image: &InputImage, size: u32) -> GrayImage {
+    let (width, height) = image.dimensions();
+    let block = width.max(height).div_ceil(size).max(1);
+    let luma = window_luma(image, (0, 0, width, height));
+
+    GrayImage::from_fn(width.div_ceil(block), height.div_ceil(block), |bx, by| {
+        let darkest = (by * block..((by + 1) * block).min(height))
+            .flat_map(|y| (bx * block..((bx + 1) * block).min(width)).map(move |x| (x, y)))
+            .map(|(x, y)| luma.get_pixel(x, y).0[0])
+            .min()
+            .unwrap_or(0);
+        Luma([darkest])
+    })
+}
+
+/// Grayscale version of part of `image`, stretched to the full 8-bit range so
+/// that faint defects keep their contrast
+fn window_luma(image: &InputImage, (min_x, min_y, max_x, max_y): Bounds) -> GrayImage {
+    let luma = |p: &Rgb<u16>| p.0.iter().map(|&v| v as u32).sum::<u32>() / 3;
+
+    let values: Vec<u32> = (min_y..max_y)
+        .flat_map(|y| (min_x..max_x).map(move |x| (x, y)))
+        .map(|(x, y)| luma(image.get_pixel(x, y)))
+        .collect();
+    let low = values.iter().copied().min().unwrap_or(0);
+    let high = values.iter().copied().max().unwrap_or(0).max(low + 1);
+
+    GrayImage::from_fn(max_x - min_x, max_y - min_y, |x, y| {
+        let value = values[(y * (max_x - min_x) + x) as usize];
+        Luma([((value - low) * u8::MAX as u32 / (high - low)) as u8])
+    })
+}
+
+/// Marks dark specks that are at most `max_size` pixels across, and darker
+/// than their surroundings by at least `contrast`, along with the pixels
+/// around them
+fn defect_mask(img: &GrayImage, max_size: u32, contrast: u8) -> GrayImage {
+    let (labels, defects) = label_defects(img, max_size, contrast);
+    let mask = GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        let label = labels.get_pixel(x, y).0[0];
+        Luma([if defects.contains_key(&label) {
+            u8::MAX
+        } else {
+            0
+        }])
+    });
+    dilate(&mask, Norm::LInf, 1)
+}
+
+/// Bounds of dark specks that are at most `max_size` pixels across
+fn find_defects(img: &GrayImage, max_size: u32, contrast: u8) -> Vec<Bounds> {
+    label_defects(img, max_size, contrast)
+        .1
+        .into_values()
+        .collect()
+}
+
+/// Labels connected dark specks, and returns the bounds of the ones that are
+/// small enough to be defects
+fn label_defects(
+    img: &GrayImage,
+    max_size: u32,
+    contrast: u8,
+) -> (Image<Luma<u32>>, HashMap<u32, Bounds>) {
+    // closing fills in anything darker than its surroundings and smaller than
+    // the disk, so the difference is what was filled in
+    let radius = (max_size / 2 + 1).min(MAX_RADIUS) as u8;
+    let closed = grayscale_close(img, &Mask::disk(radius));
+    let specks = GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        let filled = closed.get_pixel(x, y).0[0].saturating_sub(img.get_pixel(x, y).0[0]);
+        Luma([if filled >= contrast { u8::MAX } else { 0 }])
+    });
+
+    let labels = connected_components(&specks, Connectivity::Eight, Luma([0u8]));
+    let mut bounds: HashMap<u32, Bounds> = HashMap::new();
+    for (x, y, label) in labels.enumerate_pixels() {
+        if label.0[0] == 0 {
+            continue;
+        }
+        bounds
+            .entry(label.0[0])
+            .and_modify(|(min_x, min_y, max_x, max_y)| {
+                *min_x = (*min_x).min(x);
+                *min_y = (*min_y).min(y);
+                *max_x = (*max_x).max(x);
+                *max_y = (*max_y).max(y);
+            })
+            .or_insert((x, y, x, y));
+    }
+
+    bounds.retain(|_, &mut (min_x, min_y, max_x, max_y)| {
+        (max_x - min_x + 1).max(max_y - min_y + 1) <= max_size
+    });
+    (labels, bounds)
+}
+
+/// Fills in the pixels under `mask` from the outside in, with the average of
+/// their neighbors that are already known
+pub fn inpaint_mut(image: &mut InputImage, mask: &GrayImage) {
+    let (width, height) = image.dimensions();
+    let mut unknown = mask.clone();
+    let mut remaining: Vec<(u32, u32)> = mask
+        .enumerate_pixels()
+        .filter(|(_, _, p)| p.0[0] > 0)
+        .map(|(x, y, _)| (x, y))
+        .collect();
+
+    while !remaining.is_empty() {
+        let mut filled = vec![];
+        for &(x, y) in remaining.iter() {
+            let mut sum = [0_u32; 3];
+            let mut count = 0;
+            for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+                for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                    if unknown.get_pixel(nx, ny).0[0] == 0 {
+                        let p = image.get_pixel(nx, ny);
+                        (0..3).for_each(|channel| sum[channel] += p.0[channel] as u32);
+                        count += 1;
+                    }
+                }
+            }
+            if count > 0 {
+                filled.push((x, y, Rgb(sum.map(|v| (v / count) as u16))));
+            }
+        }
+
+        if filled.is_empty() {
+            // the whole image is masked
+            break;
+        }
+        for &(x, y, color) in filled.iter() {
+            image.put_pixel(x, y, color);
+            unknown.put_pixel(x, y, Luma([0]));
+        }
+        remaining.retain(|&(x, y)| unknown.get_pixel(x, y).0[0] > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: DustOptions = DustOptions {
+        sensitivity: 0.5,
+        max_size: 8,
+    };
+
+    /// A smooth negative, with a dark square of `size` pixels at `(x, y)`
+    fn negative(speck: Option<(u32, u32, u32)>) -> InputImage {
+        InputImage::from_fn(200, 160, |x, y| match speck {
+            Some((sx, sy, size))
+                if (sx..sx + size).contains(&x) && (sy..sy + size).contains(&y) =>
+            {
+                Rgb([2000; 3])
+            }
+            _ => Rgb([(20000 + x * 100) as u16, 30000, 25000]),
+        })
+    }
+
+    #[test]
+    fn finds_a_speck() {
+        let mask = detect(&negative(Some((100, 80, 3))), 100, &OPTIONS);
+        assert_eq!(mask.get_pixel(101, 81).0[0], u8::MAX);
+        // with the pixels around it
+        assert_eq!(mask.get_pixel(99, 79).0[0], u8::MAX);
+        assert_eq!(mask.get_pixel(20, 20).0[0], 0);
+    }
+
+    #[test]
+    fn leaves_a_clean_frame_alone() {
+        let mask = detect(&negative(None), 100, &OPTIONS);
+        assert!(mask.pixels().all(|p| p.0[0] == 0));
+    }
+
+    #[test]
+    fn ignores_dark_areas_larger_than_dust() {
+        let mut image = negative(None);
+        for y in 60..100 {
+            for x in 0..image.width() {
+                image.put_pixel(x, y, Rgb([2000; 3]));
+            }
+        }
+        let mask = detect(&image, 100, &OPTIONS);
+        assert!(mask.pixels().all(|p| p.0[0] == 0));
+    }
+
+    #[test]
+    fn inpaints_from_the_surroundings() {
+        let mut image = InputImage::from_pixel(16, 16, Rgb([30000, 20000, 10000]));
+        let mut mask = GrayImage::new(16, 16);
+        for (x, y) in [(7, 7), (8, 7), (7, 8), (8, 8)] {
+            image.put_pixel(x, y, Rgb([0; 3]));
+            mask.put_pixel(x, y, Luma([u8::MAX]));
+        }
+        inpaint_mut(&mut image, &mask);
+        assert!(image.pixels().all(|p| *p == Rgb([30000, 20000, 10000])));
+    }
+}
@@ -0,0 +1,187 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::conversion::InputImage;
+
+/// Number of cells along the longer side of the image that the light is
+/// measured in. Falloff is smooth, so a coarse grid is enough.
+const CELLS: u32 = 32;
+
+/// Cells darker than this share of the brightest one are only brightened as
+/// if they were this bright, so that noise isn't amplified
+const MIN_FALLOFF: f32 = 0.05;
+
+/// How the light falls off across the image, e.g. from an uneven light source
+/// or vignetting, measured from shots of the bare light source. Read from a
+/// TOML file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FlatField {
+    /// Size of the images that it was measured on, as `[width, height]`
+    pub size: [u32; 2],
+    /// Number of cells, as `[columns, rows]`
+    pub cells: [u32; 2],
+    /// Brightness of each cell relative to the brightest one, as `[r, g, b]`,
+    /// row by row
+    pub falloff: Vec<[f32; 3]>,
+}
+
+impl FlatField {
+    /// Measures the light across `image`, a linear shot of the bare light
+    /// source
+    pub fn measure(image: &InputImage) -> Result<Self, String> {
+        let (width, height) = image.dimensions();
+        let long_side = width.max(height).max(1);
+        let cells = [
+            (CELLS * width / long_side).max(1),
+            (CELLS * height / long_side).max(1),
+        ];
+
+        let mut sums = vec![[0.0_f64; 3]; (cells[0] * cells[1]) as usize];
+        let mut counts = vec![0_usize; sums.len()];
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let cell = (y * cells[1] / height * cells[0] + x * cells[0] / width) as usize;
+            for channel in 0..3 {
+                sums[cell][channel] += pixel[channel] as f64;
+            }
+            counts[cell] += 1;
+        }
+
+        let means: Vec<[f32; 3]> = sums
+            .iter()
+            .zip(&counts)
+            .map(|(sum, &count)| sum.map(|v| (v / count.max(1) as f64) as f32))
+            .collect();
+        Self::normalized([width, height], cells, means)
+    }
+
+    /// Averages flat fields measured from several shots, which evens out noise
+    pub fn average(fields: &[Self]) -> Result<Self, String> {
+        let Some(first) = fields.first() else {
+            return Err(String::from("no flat fields to average"));
+        };
+        if let Some(other) = fields.iter().find(|f| f.size != first.size) {
+            return Err(format!(
+                "the calibration shots have different sizes, {}x{} and {}x{}",
+                first.size[0], first.size[1], other.size[0], other.size[1]
+            ));
+        }
+
+        let mut sums = vec![[0.0; 3]; first.falloff.len()];
+        for field in fields {
+            for (sum, falloff) in sums.iter_mut().zip(&field.falloff) {
+                for channel in 0..3 {
+                    sum[channel] += falloff[channel];
+                }
+            }
+        }
+        Self::normalized(first.size, first.cells, sums)
+    }
+
+    /// Scales each channel of `brightness` so that its brightest cell is 1
+    fn normalized(
+        size: [u32; 2],
+        cells: [u32; 2],
+        brightness: Vec<[f32; 3]>,
+    ) -> Result<Self, String> {
+        let mut brightest = [0.0_f32; 3];
+        for cell in &brightness {
+            for channel in 0..3 {
+                brightest[channel] = brightest[channel].max(cell[channel]);
+            }
+        }
+        if brightest.iter().any(|&v| v <= 0.0) {
+            return Err(String::from(
+                "the calibration shots are black in at least one channel",
+            ));
+        }
+
+        let falloff = brightness
+            .iter()
+            .map(|cell| [0, 1, 2].map(|channel| cell[channel] / brightest[channel]))
+            .collect();
+        Ok(Self {
+            size,
+            cells,
+            falloff,
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read flat field {}: {}", path, e))?;
+        let field: Self =
+            toml::from_str(&contents).map_err(|e| format!("Invalid flat field {}: {}", path, e))?;
+
+        let [columns, rows] = field.cells;
+        if columns == 0 || rows == 0 || field.falloff.len() != (columns * rows) as usize {
+            return Err(format!(
+                "Invalid flat field {}: expected {}x{} cells, found {}",
+                path,
+                columns,
+                rows,
+                field.falloff.len()
+            )
+            .into());
+        }
+        Ok(field)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, toml::to_string(self)?)?;
+        println!("Saved {}", path);
+        Ok(())
+    }
+
+    /// Evens out the light across `image`, a linear image of the same size as
+    /// the calibration shots
+    pub fn apply_mut(&self, image: &mut InputImage) -> Result<(), String> {
+        let (width, height) = image.dimensions();
+        if [width, height] != self.size {
+            return Err(format!(
+                "the flat field was measured on {}x{} images, not {}x{}",
+                self.size[0], self.size[1], width, height
+            ));
+        }
+
+        let [columns, rows] = self.cells;
+        let at_x: Vec<(usize, usize, f32)> = (0..width)
+            .map(|x| cell_position(x, width, columns))
+            .collect();
+
+        image
+            .par_chunks_mut(width as usize * 3)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let (y0, y1, ty) = cell_position(y as u32, height, rows);
+                for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+                    let (x0, x1, tx) = at_x[x];
+                    for (channel, value) in pixel.iter_mut().enumerate() {
+                        let falloff = |cx: usize, cy: usize| {
+                            self.falloff[cy * columns as usize + cx][channel]
+                        };
+                        let top = lerp(falloff(x0, y0), falloff(x1, y0), tx);
+                        let bottom = lerp(falloff(x0, y1), falloff(x1, y1), tx);
+                        let gain = 1.0 / lerp(top, bottom, ty).max(MIN_FALLOFF);
+                        *value = (*value as f32 * gain).round().min(u16::MAX as f32) as u16;
+                    }
+                }
+            });
+        Ok(())
+    }
+}
+
+/// The cells on either side of pixel `v` along a side of `length` pixels and
+/// `cells` cells, and how far it is from the first one to the second. Pixels
+/// outside of the outer cells' centers take their value.
+fn cell_position(v: u32, length: u32, cells: u32) -> (usize, usize, f32) {
+    let position =
+        ((v as f32 + 0.5) * cells as f32 / length as f32 - 0.5).clamp(0.0, (cells - 1) as f32);
+    let first = position.floor() as usize;
+    let second = (first + 1).min(cells as usize - 1);
+    (first, second, position - first as f32)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
@@ -1,10 +1,12 @@
-use std::{io::Write, usize};
+use std::usize;
 
-use image::GrayImage;
+use clap::ValueEnum;
+use image::{GrayImage, Pixel};
 use imageproc::stats::cumulative_histogram;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::conversion::InputImage;
+use crate::conversion::{InputImage, RgbBuffer, Sample};
 
 /// imageproc's `equalize_histogram_mut` doesn't preserve black or white levels.
 /// Here, we keep track of the `min` CDF value so that pixels with values 0 and
@@ -27,14 +29,53 @@ pub fn normalize_histogram_mut(image: &mut GrayImage) {
     });
 }
 
-type HistogramRgb = Vec<Vec<usize>>;
+pub type HistogramRgb = Vec<Vec<usize>>;
 
-pub fn histogram_rgb(image: &InputImage, bins: usize) -> HistogramRgb {
+/// How to stretch histograms after inversion
+#[derive(Clone, Debug)]
+pub struct StretchOptions {
+    pub mode: StretchMode,
+    /// Share of pixels that may be clipped to black in each channel
+    pub black_clip: f32,
+    /// Share of pixels that may be clipped to white in each channel
+    pub white_clip: f32,
+    /// Share of pixels that are never clipped, however the histogram is shaped
+    pub clip_limit: f32,
+    /// Maximum factor that the range of values may be stretched by, so that
+    /// low-contrast scenes aren't pushed into noise
+    pub max_gain: Option<f32>,
+}
+
+impl Default for StretchOptions {
+    fn default() -> Self {
+        Self {
+            mode: StretchMode::Independent,
+            black_clip: 0.0001,
+            white_clip: 0.0001,
+            clip_limit: 0.005,
+            max_gain: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum StretchMode {
+    /// Stretch each channel on its own, which also removes color casts
+    Independent,
+    /// Stretch every channel by the same amount, based on luminance, which
+    /// keeps color casts
+    LinkedLuminance,
+    /// Don't stretch
+    None,
+}
+
+/// Values outside of 0 to 1 are counted in the first and last bins
+pub fn histogram_rgb<S: Sample>(image: &RgbBuffer<S>, bins: usize) -> HistogramRgb {
     let mut hist = vec![vec![0; bins]; 3];
 
     for p in image.pixels() {
-        for (channel, &value) in p.0.iter().enumerate() {
-            let bin = (value as f32 / u16::MAX as f32) * (bins - 1) as f32;
+        for (channel, &value) in p.channels().iter().enumerate() {
+            let bin = value.to_unit().clamp(0.0, 1.0) * (bins - 1) as f32;
             hist[channel][bin as usize] += 1;
         }
     }
@@ -42,14 +83,27 @@ pub fn histogram_rgb(image: &InputImage, bins: usize) -> HistogramRgb {
     hist
 }
 
+/// Histogram of perceived brightness, with the same weights as white balancing
+pub fn histogram_luma<S: Sample>(image: &RgbBuffer<S>, bins: usize) -> Vec<usize> {
+    let mut hist = vec![0; bins];
+
+    for p in image.pixels() {
+        let [r, g, b] = S::values(p).map(|value| value.to_unit());
+        let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+        let bin = luma.clamp(0.0, 1.0) * (bins - 1) as f32;
+        hist[bin as usize] += 1;
+    }
+
+    hist
+}
+
 pub fn find_cutoff_value(
     reverse: bool,
     channel_hist: &Vec<usize>,
     max_pixels_pct: f32,
     max_pixels_pct_diff: f32,
+    hard_pixels_pct_cutoff: f32,
 ) -> u16 {
-    let hard_pixels_pct_cutoff = 0.005;
-
     let hist_iter: Box<dyn Iterator<Item = _>> = if reverse {
         Box::new(channel_hist.iter().rev())
     } else {
@@ -59,7 +113,7 @@ pub fn find_cutoff_value(
     let pixels_total = channel_hist.iter().sum::<usize>() as f32;
 
     let mut pixels_running_count = 0 as f32;
-    let mut prev_count: usize = usize::MAX;
+    let mut prev_count: Option<usize> = None;
     let mut cutoff_value_by_count = None;
     let mut cutoff_value_by_diff = None;
 
@@ -71,7 +125,9 @@ pub fn find_cutoff_value(
         pixels_running_count += count as f32;
 
         let pixels_pct = pixels_running_count / pixels_total;
-        let pixels_diff_pct = (count - prev_count) as f32 / pixels_total;
+        // a jump from the previous non-empty bin, which the first bin doesn't have
+        let pixels_diff_pct =
+            prev_count.map_or(0.0, |prev_count| count as f32 - prev_count as f32) / pixels_total;
 
         if pixels_pct > hard_pixels_pct_cutoff {
             break;
@@ -89,7 +145,7 @@ pub fn find_cutoff_value(
             break;
         }
 
-        prev_count = count;
+        prev_count = Some(count);
     }
 
     let unscaled_cutoff_value = usize::max(
@@ -107,70 +163,371 @@ pub fn find_cutoff_value(
     }
 }
 
-pub fn stretch_channels_mut(image: &mut InputImage) {
-    let max_pixels_pct = 0.0001;
-    let max_pixels_pct_diff = 0.00005;
+/// Black and white points of each channel, in 16-bit values
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Levels {
+    pub black: [f64; 3],
+    pub white: [f64; 3],
+}
+
+impl Levels {
+    /// `clip_scale` scales the clip percentages from `options`, and
+    /// `diff_scale` sets how large a jump in the histogram may be, relative to
+    /// the clip percentage
+    fn from_histogram(
+        hist: &HistogramRgb,
+        options: &StretchOptions,
+        clip_scale: f32,
+        diff_scale: f32,
+    ) -> Self {
+        let cutoff = |reverse: bool, channel: usize| {
+            let clip = clip_scale
+                * if reverse {
+                    options.white_clip
+                } else {
+                    options.black_clip
+                };
+            find_cutoff_value(
+                reverse,
+                &hist[channel],
+                clip,
+                clip * diff_scale,
+                options.clip_limit,
+            ) as f64
+        };
 
-    // First, do a conservative stretch to ensure we use most of the value range in the histogram.
-    let hist = histogram_rgb(&image, 65_536);
-    let min: Vec<f64> = (0..3)
-        .map(|channel| find_cutoff_value(false, &hist[channel], max_pixels_pct / 10.0, 0.0) as f64)
-        .collect();
-    let max: Vec<f64> = (0..3)
-        .map(|channel| find_cutoff_value(true, &hist[channel], max_pixels_pct / 10.0, 0.0) as f64)
-        .collect();
-    image.par_pixels_mut().for_each(|pixel| {
-        for (channel, value) in pixel.0.iter_mut().enumerate() {
-            *value = f64::min(
-                u16::MAX as f64,
-                u16::MAX as f64 * ((*value as f64 - min[channel]) / (max[channel] - min[channel])),
-            ) as u16;
+        Self {
+            black: [0, 1, 2].map(|channel| cutoff(false, channel)),
+            white: [0, 1, 2].map(|channel| cutoff(true, channel)),
         }
-    });
+    }
+
+    /// Widens the levels around their center, so that no channel is stretched
+    /// by more than `max_gain`
+    fn limit_gain(&self, max_gain: f32) -> Levels {
+        let min_range = u16::MAX as f64 / max_gain as f64;
+        let mut limited = self.clone();
+
+        for channel in 0..3 {
+            let (black, white) = (self.black[channel], self.white[channel]);
+            if white - black >= min_range {
+                continue;
+            }
+
+            let center = (black + white) / 2.0;
+            let black = center - min_range / 2.0;
+            let white = center + min_range / 2.0;
+            let shift = if black < 0.0 {
+                -black
+            } else if white > u16::MAX as f64 {
+                u16::MAX as f64 - white
+            } else {
+                0.0
+            };
+            limited.black[channel] = black + shift;
+            limited.white[channel] = white + shift;
+        }
+
+        limited
+    }
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read levels {}: {}", path, e))?;
+        toml::from_str(&contents).map_err(|e| format!("Invalid levels {}: {}", path, e).into())
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, toml::to_string_pretty(self)?)?;
+        println!("Saved {}", path);
+        Ok(())
+    }
+
+    /// Moves each level to within `max_deviation` (a share of the full range)
+    /// of `reference`
+    pub fn limit_to(&self, reference: &Levels, max_deviation: f32) -> Levels {
+        let max_deviation = max_deviation as f64 * u16::MAX as f64;
+        let limit = |value: f64, reference: f64| {
+            value.clamp(reference - max_deviation, reference + max_deviation)
+        };
+        Levels {
+            black: [0, 1, 2].map(|channel| limit(self.black[channel], reference.black[channel])),
+            white: [0, 1, 2].map(|channel| limit(self.white[channel], reference.white[channel])),
+        }
+    }
+
+    pub fn map(&self, channel: usize, value: u16) -> u16 {
+        let (min, max) = (self.black[channel], self.white[channel]);
+        f64::min(
+            u16::MAX as f64,
+            u16::MAX as f64 * ((value as f64 - min) / (max - min)),
+        ) as u16
+    }
+
+    /// Like `map`, for a value from 0 to 1, without clipping the result
+    pub fn map_unit(&self, channel: usize, value: f32) -> f32 {
+        let (min, max) = (self.black[channel], self.white[channel]);
+        ((value as f64 * u16::MAX as f64 - min) / (max - min)) as f32
+    }
+
+    /// Combines two stretches into one, so that values are only rounded once
+    fn then(&self, next: &Levels) -> Levels {
+        let scale = |channel: usize, value: f64| {
+            self.black[channel]
+                + value / u16::MAX as f64 * (self.white[channel] - self.black[channel])
+        };
+        Levels {
+            black: [0, 1, 2].map(|channel| scale(channel, next.black[channel])),
+            white: [0, 1, 2].map(|channel| scale(channel, next.white[channel])),
+        }
+    }
+}
+
+/// Cutoff values picked by each pass of `find_cutoffs`. The second pass is
+/// measured after applying the first.
+#[derive(Clone, Debug, Serialize)]
+pub struct Cutoffs {
+    pub first: Levels,
+    pub second: Levels,
+}
+
+impl Cutoffs {
+    /// Combines both passes into the levels that are applied
+    pub fn levels(&self, options: &StretchOptions) -> Levels {
+        let levels = self.first.then(&self.second);
+        match options.max_gain {
+            Some(max_gain) => levels.limit_gain(max_gain),
+            None => levels,
+        }
+    }
+}
+
+/// Finds the cutoffs that levels which stretch each channel over the full range
+/// are based on, if `options` stretch at all
+pub fn find_cutoffs<S: Sample>(image: &RgbBuffer<S>, options: &StretchOptions) -> Option<Cutoffs> {
+    stretch_histogram(image, options.mode).map(|hist| cutoffs_from_histogram(&hist, options))
+}
+
+/// The full-resolution histogram that levels are found from, if `mode`
+/// stretches at all
+fn stretch_histogram<S: Sample>(image: &RgbBuffer<S>, mode: StretchMode) -> Option<HistogramRgb> {
+    match mode {
+        StretchMode::Independent => Some(histogram_rgb(image, 65_536)),
+        StretchMode::LinkedLuminance => {
+            // the same histogram for every channel results in the same levels
+            let luma = histogram_luma(image, 65_536);
+            Some(vec![luma.clone(), luma.clone(), luma])
+        }
+        StretchMode::None => None,
+    }
+}
+
+fn cutoffs_from_histogram(hist: &HistogramRgb, options: &StretchOptions) -> Cutoffs {
+    // First, do a conservative stretch to ensure we use most of the value range in the histogram.
+    let first = Levels::from_histogram(hist, options, 0.1, 0.0);
 
     // Then, do one more pass to refine black and white levels.
     // Using a 256-bin histogram is an easy way to smooth out the histogram curve.
-    let hist = histogram_rgb(&image, 256);
-    let min: Vec<f64> = (0..3)
-        .map(|channel| {
-            find_cutoff_value(false, &hist[channel], max_pixels_pct, max_pixels_pct_diff) as f64
-        })
-        .collect();
-    let max: Vec<f64> = (0..3)
-        .map(|channel| {
-            find_cutoff_value(true, &hist[channel], max_pixels_pct, max_pixels_pct_diff) as f64
-        })
-        .collect();
-    image.par_pixels_mut().for_each(|pixel| {
-        for (channel, value) in pixel.0.iter_mut().enumerate() {
-            *value = f64::min(
-                u16::MAX as f64,
-                u16::MAX as f64 * ((*value as f64 - min[channel]) / (max[channel] - min[channel])),
-            ) as u16;
-        }
-    });
+    let hist = remap_histogram(hist, &first, 256);
+    let second = Levels::from_histogram(&hist, options, 1.0, 0.5);
 
-    histogram_rgb(&image, 256);
+    Cutoffs { first, second }
 }
 
-#[allow(dead_code)]
-fn debug_histogram(hist: &Vec<Vec<usize>>) {
+/// The histogram that `histogram_rgb` would return after applying `levels`.
+/// `hist` should have one bin per 16-bit value.
+fn remap_histogram(hist: &HistogramRgb, levels: &Levels, bins: usize) -> HistogramRgb {
+    let mut remapped = vec![vec![0; bins]; 3];
+
     for (channel, channel_hist) in hist.iter().enumerate() {
-        let fname = format!(
-            "{}_{}_hist.txt",
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_micros(),
-            channel
+        for (value, &count) in channel_hist.iter().enumerate() {
+            let mapped = levels.map(channel, value as u16);
+            let bin = (mapped as f32 / u16::MAX as f32) * (bins - 1) as f32;
+            remapped[channel][bin as usize] += count;
+        }
+    }
+
+    remapped
+}
+
+pub fn apply_levels_mut<S: Sample>(image: &mut RgbBuffer<S>, levels: &Levels) {
+    S::map_channels_mut(image, |channel, value| levels.map_unit(channel, value));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum RollWeighting {
+    /// Every frame counts the same, regardless of its size
+    Equal,
+    /// Larger frames count more
+    Pixels,
+}
+
+/// Combined histogram of every frame in a roll, so that the same levels can be
+/// applied to each of them
+pub struct RollHistogram {
+    hist: Option<HistogramRgb>,
+    mode: StretchMode,
+    weighting: RollWeighting,
+}
+
+impl RollHistogram {
+    /// Pixel count that every frame is scaled to, for `RollWeighting::Equal`
+    const FRAME_WEIGHT: f64 = (1u64 << 32) as f64;
+
+    pub fn new(mode: StretchMode, weighting: RollWeighting) -> Self {
+        Self {
+            hist: None,
+            mode,
+            weighting,
+        }
+    }
+
+    pub fn add(&mut self, image: &InputImage) {
+        let Some(frame_hist) = stretch_histogram(image, self.mode) else {
+            return;
+        };
+
+        let scale = match self.weighting {
+            RollWeighting::Equal => Self::FRAME_WEIGHT / (image.width() * image.height()) as f64,
+            RollWeighting::Pixels => 1.0,
+        };
+
+        let hist = self
+            .hist
+            .get_or_insert_with(|| vec![vec![0; frame_hist[0].len()]; 3]);
+        for (channel_hist, frame_channel_hist) in hist.iter_mut().zip(frame_hist.iter()) {
+            for (count, &frame_count) in channel_hist.iter_mut().zip(frame_channel_hist.iter()) {
+                *count += (frame_count as f64 * scale).round() as usize;
+            }
+        }
+    }
+
+    /// Levels for the whole roll, or `None` if no frames were added
+    pub fn levels(&self, options: &StretchOptions) -> Option<Levels> {
+        self.hist
+            .as_ref()
+            .map(|hist| cutoffs_from_histogram(hist, options).levels(options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    fn levels(black: f64, white: f64) -> Levels {
+        Levels {
+            black: [black; 3],
+            white: [white; 3],
+        }
+    }
+
+    /// Every value from `min` to `max` in each channel
+    fn ramp(min: u16, max: u16) -> InputImage {
+        InputImage::from_fn(256, 256, |x, y| {
+            let t = (x + y * 256) as f32 / (256 * 256 - 1) as f32;
+            let value = (min as f32 + t * (max - min) as f32) as u16;
+            Rgb([value, value, value / 2])
+        })
+    }
+
+    #[test]
+    fn cutoff_handles_falling_bins() {
+        let hist = vec![500, 400, 300, 200, 100];
+        // no bin jumps up, so only the share of pixels counts
+        assert_eq!(
+            find_cutoff_value(false, &hist, 0.5, 0.01, 1.0),
+            u16::MAX / 4
+        );
+        assert_eq!(
+            find_cutoff_value(true, &hist, 0.1, 0.01, 1.0),
+            u16::MAX - u16::MAX / 4
         );
-        let mut file = std::fs::File::create(fname).unwrap();
+    }
 
-        for (value, &count) in channel_hist.iter().enumerate() {
-            if count > 0 {
-                file.write(format!("{}\t{}\n", value, count).as_bytes())
-                    .unwrap();
+    #[test]
+    fn cutoff_stops_at_a_jump() {
+        let hist = vec![1, 1, 1000, 1, 1];
+        assert_eq!(find_cutoff_value(false, &hist, 0.5, 0.5, 1.0), u16::MAX / 2);
+    }
+
+    #[test]
+    fn combined_levels_match_applying_both() {
+        let first = Levels {
+            black: [1000.0, 2000.0, 3000.0],
+            white: [60000.0, 50000.0, 40000.0],
+        };
+        let second = levels(5000.0, 55000.0);
+        let combined = first.then(&second);
+        for channel in 0..3 {
+            for value in [4000, 20000, 35000] {
+                let both = second.map(channel, first.map(channel, value)) as i32;
+                assert!((combined.map(channel, value) as i32 - both).abs() <= 1);
             }
         }
     }
+
+    #[test]
+    fn limits_gain_around_the_center() {
+        let limited = levels(30000.0, 32000.0).limit_gain(4.0);
+        let range = u16::MAX as f64 / 4.0;
+        assert!((limited.white[0] - limited.black[0] - range).abs() < 1e-6);
+        assert!((limited.black[0] + limited.white[0] - 62000.0).abs() < 1e-6);
+
+        // shifted to stay within the full range
+        let limited = levels(0.0, 1000.0).limit_gain(4.0);
+        assert_eq!(limited.black[0], 0.0);
+        assert!((limited.white[0] - range).abs() < 1e-6);
+
+        let wide = levels(1000.0, 60000.0).limit_gain(4.0);
+        assert_eq!(wide.black, [1000.0; 3]);
+        assert_eq!(wide.white, [60000.0; 3]);
+    }
+
+    #[test]
+    fn limits_levels_to_a_reference() {
+        let limited = levels(0.0, 40000.0).limit_to(&levels(5000.0, 50000.0), 0.05);
+        let max_deviation = 0.05_f32 as f64 * u16::MAX as f64;
+        assert!((limited.black[0] - (5000.0 - max_deviation)).abs() < 1e-6);
+        assert!((limited.white[0] - (50000.0 - max_deviation)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cutoffs_stretch_to_the_range_of_values() {
+        let image = ramp(10000, 50000);
+        let levels = find_cutoffs(&image, &StretchOptions::default())
+            .unwrap()
+            .levels(&StretchOptions::default());
+        assert!((levels.black[0] - 10000.0).abs() < 500.0);
+        assert!((levels.white[0] - 50000.0).abs() < 500.0);
+        // channels are stretched on their own
+        assert!((levels.white[2] - 25000.0).abs() < 500.0);
+    }
+
+    #[test]
+    fn linked_cutoffs_are_the_same_for_every_channel() {
+        let options = StretchOptions {
+            mode: StretchMode::LinkedLuminance,
+            ..StretchOptions::default()
+        };
+        let levels = find_cutoffs(&ramp(10000, 50000), &options)
+            .unwrap()
+            .levels(&options);
+        assert_eq!(levels.black[0], levels.black[2]);
+        assert_eq!(levels.white[0], levels.white[2]);
+    }
+
+    #[test]
+    fn no_cutoffs_without_stretching() {
+        let options = StretchOptions {
+            mode: StretchMode::None,
+            ..StretchOptions::default()
+        };
+        assert!(find_cutoffs(&ramp(10000, 50000), &options).is_none());
+
+        let mut roll = RollHistogram::new(StretchMode::None, RollWeighting::Equal);
+        roll.add(&ramp(10000, 50000));
+        assert!(roll.levels(&options).is_none());
+    }
 }
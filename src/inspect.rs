@@ -0,0 +1,212 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::conversion::Conversion;
+use crate::histogram::{HistogramRgb, Levels};
+use crate::raw_processor::Capture;
+
+const CHANNELS: [&str; 3] = ["r", "g", "b"];
+
+/// What `yancy inspect` found out about a RAW file, without saving anything
+#[derive(Clone, Debug, Serialize)]
+pub struct Inspection {
+    pub file: String,
+    pub capture: Capture,
+    /// Size of the decoded image in pixels, as `[width, height]`
+    pub size: [u32; 2],
+    pub frames: Vec<FrameInspection>,
+}
+
+/// What was detected in a frame, and how its histograms look
+#[derive(Clone, Debug, Serialize)]
+pub struct FrameInspection {
+    /// Path that the frame's outputs are named after
+    pub file: String,
+    /// Frame bounds as `[min_x, min_y, max_x, max_y]`, in pixels of the
+    /// (rotated) original
+    pub crop: [u32; 4],
+    /// Clockwise rotation in degrees, if the frame was rotated
+    pub rotation: Option<f32>,
+    /// Color of the film backing as 16-bit `[r, g, b]`
+    pub base_color: [u16; 3],
+    pub base_confidence: Option<f32>,
+    /// Levels that the frame would be stretched with
+    pub levels: Option<Levels>,
+    /// Each channel of the frame before stretching
+    pub histogram_before: Option<[ChannelStats; 3]>,
+    /// Each channel of the frame after all adjustments
+    pub histogram_after: Option<[ChannelStats; 3]>,
+}
+
+/// Statistics of a channel's histogram, with values from 0 to 1
+#[derive(Clone, Debug, Serialize)]
+pub struct ChannelStats {
+    pub mean: f32,
+    pub median: f32,
+    /// Values that 1% of the pixels are below, and 1% are above
+    pub p01: f32,
+    pub p99: f32,
+    /// Shares of the pixels in the lowest and highest bins
+    pub clipped_black: f32,
+    pub clipped_white: f32,
+}
+
+impl FrameInspection {
+    pub fn new(file: &str, conversion: &Conversion) -> Self {
+        let (min_x, min_y, max_x, max_y) = conversion.crop;
+        let histograms = conversion.histograms.as_ref();
+        let channel_stats = |histogram: &HistogramRgb| {
+            [0, 1, 2].map(|channel| ChannelStats::new(&histogram[channel]))
+        };
+
+        Self {
+            file: file.to_owned(),
+            crop: [min_x, min_y, max_x, max_y],
+            rotation: conversion.rotation,
+            base_color: conversion.base_color.0,
+            base_confidence: conversion.base_confidence,
+            levels: conversion.levels.clone(),
+            histogram_before: histograms.map(|h| channel_stats(&h.before)),
+            histogram_after: histograms.map(|h| channel_stats(&h.after)),
+        }
+    }
+}
+
+impl ChannelStats {
+    pub fn new(histogram: &[usize]) -> Self {
+        let total = histogram.iter().sum::<usize>().max(1) as f32;
+        let last = histogram.len().saturating_sub(1).max(1) as f32;
+
+        let mean = histogram
+            .iter()
+            .enumerate()
+            .map(|(bin, &count)| bin as f32 * count as f32)
+            .sum::<f32>()
+            / total
+            / last;
+        // value of the bin that the `share` of pixels below it ends in
+        let percentile = |share: f32| {
+            let mut below = 0;
+            for (bin, &count) in histogram.iter().enumerate() {
+                below += count;
+                if below as f32 >= share * total {
+                    return bin as f32 / last;
+                }
+            }
+            1.0
+        };
+        let share = |count: Option<&usize>| count.copied().unwrap_or(0) as f32 / total;
+
+        Self {
+            mean,
+            median: percentile(0.5),
+            p01: percentile(0.01),
+            p99: percentile(0.99),
+            clipped_black: share(histogram.first()),
+            clipped_white: share(histogram.last()),
+        }
+    }
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let capture = &self.capture;
+        writeln!(f, "{}", self.file)?;
+        writeln!(f, "  Camera: {} {}", capture.make, capture.model)?;
+        if !capture.lens.is_empty() {
+            writeln!(f, "  Lens: {}", capture.lens)?;
+        }
+
+        let mut exposure = vec![];
+        if let Some(iso) = capture.iso {
+            exposure.push(format!("ISO {}", iso));
+        }
+        match capture.exposure_time {
+            Some(time) if time < 1.0 => exposure.push(format!("1/{:.0} s", 1.0 / time)),
+            Some(time) => exposure.push(format!("{} s", time)),
+            None => {}
+        }
+        if let Some(f_number) = capture.f_number {
+            exposure.push(format!("f/{}", f_number));
+        }
+        if let Some(focal_length) = capture.focal_length {
+            exposure.push(format!("{} mm", focal_length));
+        }
+        if !exposure.is_empty() {
+            writeln!(f, "  Exposure: {}", exposure.join(", "))?;
+        }
+        if let Some(date_time) = &capture.date_time {
+            writeln!(f, "  Date: {}", date_time)?;
+        }
+        writeln!(f, "  Size: {}x{}", self.size[0], self.size[1])?;
+
+        for frame in &self.frames {
+            write!(f, "{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FrameInspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [min_x, min_y, max_x, max_y] = self.crop;
+        writeln!(f, "  Frame {}", self.file)?;
+        writeln!(
+            f,
+            "    Crop: {},{} to {},{} ({}x{})",
+            min_x,
+            min_y,
+            max_x,
+            max_y,
+            max_x - min_x,
+            max_y - min_y
+        )?;
+        if let Some(rotation) = self.rotation {
+            writeln!(f, "    Rotation: {:.2} degrees", rotation)?;
+        }
+
+        let [r, g, b] = self.base_color;
+        match self.base_confidence {
+            Some(confidence) => writeln!(
+                f,
+                "    Film backing: {},{},{} (confidence {:.2})",
+                r, g, b, confidence
+            )?,
+            None => writeln!(f, "    Film backing: {},{},{} (from the settings)", r, g, b)?,
+        }
+        if let Some(levels) = &self.levels {
+            let values = |values: [f64; 3]| values.map(|v| format!("{:.0}", v)).join(",");
+            writeln!(
+                f,
+                "    Levels: black {}, white {}",
+                values(levels.black),
+                values(levels.white)
+            )?;
+        }
+
+        for (name, histogram) in [
+            ("before stretching", &self.histogram_before),
+            ("after adjustments", &self.histogram_after),
+        ] {
+            let Some(channels) = histogram else {
+                continue;
+            };
+            writeln!(f, "    Histogram {}:", name)?;
+            for (channel, stats) in CHANNELS.iter().zip(channels) {
+                writeln!(
+                    f,
+                    "      {}: mean {:.3}, median {:.3}, 1-99% {:.3} to {:.3}, clipped {:.2}% black, {:.2}% white",
+                    channel,
+                    stats.mean,
+                    stats.median,
+                    stats.p01,
+                    stats.p99,
+                    stats.clipped_black * 100.0,
+                    stats.clipped_white * 100.0
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
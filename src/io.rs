@@ -4,8 +4,13 @@ use std::{
     path::{Path, PathBuf},
 };
 
+use glob::{MatchOptions, Pattern};
 use image::{DynamicImage, EncodableLayout, ImageBuffer, ImageError, Pixel, PixelWithColorType};
 
+use crate::dng;
+
+/// Saves an intermediate image for debugging. 16-bit images are saved as 8-bit
+/// previews.
 pub fn save_image<'a, P, Container>(
     path: &str,
     dir_suffix: &Option<String>,
@@ -19,6 +24,26 @@ where
     Container: Deref<Target = [P::Subpixel]>,
     ImageBuffer<P, Container>: Into<DynamicImage>,
 {
+    let output_path = output_path(path, dir_suffix, file_suffix, extension)?;
+    let image = match image.into() {
+        image @ (DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_)) => {
+            DynamicImage::ImageRgb8(image.to_rgb8())
+        }
+        image => image,
+    };
+    image.save(&output_path)?;
+    println!("Saved {}", output_path);
+    Ok(())
+}
+
+/// Path of an output of `path`, placed in a sibling directory named after
+/// `path`'s directory and `dir_suffix`, if given
+pub fn output_path(
+    path: &str,
+    dir_suffix: &Option<String>,
+    file_suffix: &str,
+    extension: &str,
+) -> std::io::Result<String> {
     let output_path = if let Some(dir_suffix) = dir_suffix {
         let current_path = Path::new(path);
         let filename = current_path.file_name().unwrap().to_str().unwrap();
@@ -40,38 +65,62 @@ where
         path.to_owned()
     };
 
-    let output_path = format!("{}.{}.{}", output_path, file_suffix, extension);
-
-    if image.save(&output_path).is_ok() {
-        println!("Saved {}", output_path);
-        return Ok(());
-    }
-
-    Into::<DynamicImage>::into(image)
-        .to_rgb8()
-        .save(&output_path)?;
-    println!("Saved (8-bit rgb) {}", output_path);
-    Ok(())
+    Ok(format!("{}.{}.{}", output_path, file_suffix, extension))
 }
 
-pub fn read_dir_raw_files<'a>(dir: &'a str) -> std::io::Result<Vec<PathBuf>> {
+/// RAW files in `dir`, and in its subdirectories if `recursive` is set, sorted
+/// by path. With `include` patterns, only files whose path relative to `dir`
+/// matches one of them are kept, and files that match an `exclude` pattern
+/// are left out.
+pub fn read_dir_raw_files(
+    dir: &str,
+    recursive: bool,
+    include: &[Pattern],
+    exclude: &[Pattern],
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
     let path = Path::new(dir);
 
+    if !path.exists() {
+        return Err(format!("Directory {} doesn't exist", dir).into());
+    }
     if !path.is_dir() {
-        return Ok(vec![]);
+        return Err(format!("{} isn't a directory", dir).into());
     }
 
-    let dir_entries = fs::read_dir(dir)?.flatten();
-
-    let raw_file_paths = dir_entries.flat_map(|dir_entry| {
-        if has_raw_file_extension(&dir_entry.path()) {
-            Some(dir_entry.path())
-        } else {
-            None
+    let mut raw_file_paths = vec![];
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let dir_entries = fs::read_dir(&dir)
+            .map_err(|e| format!("Unable to read directory {}: {}", dir.display(), e))?;
+        for dir_entry in dir_entries.flatten() {
+            let entry_path = dir_entry.path();
+            // symlinked directories aren't followed, so that links can't loop
+            if recursive && dir_entry.file_type().is_ok_and(|t| t.is_dir()) {
+                dirs.push(entry_path);
+            } else if is_raw_input(&entry_path) {
+                raw_file_paths.push(entry_path);
+            }
         }
+    }
+
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    raw_file_paths.retain(|file_path| {
+        let relative_path = file_path.strip_prefix(path).unwrap_or(file_path);
+        let matches = |pattern: &Pattern| pattern.matches_path_with(relative_path, options);
+        (include.is_empty() || include.iter().any(matches)) && !exclude.iter().any(matches)
     });
+    raw_file_paths.sort();
+
+    Ok(raw_file_paths)
+}
 
-    Ok(raw_file_paths.collect())
+/// Whether `path` is a RAW file to convert, and not a DNG that yancy saved
+/// itself, so that re-runs don't convert their own outputs
+pub fn is_raw_input(path: &Path) -> bool {
+    has_raw_file_extension(path) && !dng::is_own_output(path)
 }
 
 pub fn has_raw_file_extension(path: &Path) -> bool {
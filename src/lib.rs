@@ -1,6 +1,29 @@
 extern crate openmp_sys;
 
+pub mod balance;
+pub mod base;
+pub mod color;
+pub mod config;
 pub mod conversion;
+pub mod denoise;
+pub mod dng;
+pub mod dust;
+pub mod flat_field;
 pub mod histogram;
+pub mod inspect;
 pub mod io;
+pub mod lut;
+pub mod naming;
+pub mod output;
+pub mod profile;
 pub mod raw_processor;
+pub mod sharpen;
+pub mod sidecar;
+pub mod sprockets;
+pub mod state;
+pub mod stats;
+pub mod tone;
+pub mod watch;
+
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,412 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use clap::ValueEnum;
+use image::{ImageBuffer, Rgb};
+use rayon::prelude::*;
+
+use crate::conversion::{InputImage, RgbBuffer, Sample};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum LutInterpolation {
+    /// Blends the 8 corners of the surrounding cube
+    Trilinear,
+    /// Blends 4 corners of the surrounding cube, which keeps neutral colors
+    /// neutral
+    Tetrahedral,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Kind {
+    /// A curve for each channel
+    OneD,
+    /// A cube of colors, with red changing fastest, then green, then blue
+    ThreeD,
+}
+
+/// A color lookup table, with values from 0 to 1
+#[derive(Clone, Debug)]
+pub struct Lut {
+    kind: Kind,
+    size: usize,
+    table: Vec<[f32; 3]>,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+}
+
+impl Lut {
+    /// Reads an Adobe/Resolve `.cube` file, or a Hald CLUT image
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+
+        let lut = if extension.as_deref() == Some("cube") {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read LUT {}: {}", path, e))?;
+            Self::parse_cube(&contents)
+        } else {
+            let image =
+                image::open(path).map_err(|e| format!("Unable to read LUT {}: {}", path, e))?;
+            Self::from_hald(&image.to_rgb32f())
+        };
+
+        lut.map_err(|e| format!("Invalid LUT {}: {}", path, e).into())
+    }
+
+    fn parse_cube(contents: &str) -> Result<Self, String> {
+        let mut kind = None;
+        let mut size = 0;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut table = vec![];
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap();
+            // table entries are the only lines that don't start with a keyword
+            let is_entry =
+                keyword.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.');
+            let values = || -> Result<Vec<f32>, String> {
+                line.split_whitespace()
+                    .skip(if is_entry { 0 } else { 1 })
+                    .map(|v| v.parse::<f32>())
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format!("line {}: {}", number + 1, e))
+            };
+            let triple = || -> Result<[f32; 3], String> {
+                values()?
+                    .try_into()
+                    .map_err(|_| format!("line {}: expected three values", number + 1))
+            };
+
+            match keyword {
+                "TITLE" => {}
+                "LUT_1D_SIZE" | "LUT_3D_SIZE" => {
+                    kind = Some(if keyword == "LUT_1D_SIZE" {
+                        Kind::OneD
+                    } else {
+                        Kind::ThreeD
+                    });
+                    size = words
+                        .next()
+                        .and_then(|v| v.parse().ok())
+                        .filter(|&size: &usize| size >= 2)
+                        .ok_or_else(|| format!("line {}: invalid size", number + 1))?;
+                }
+                "DOMAIN_MIN" => domain_min = triple()?,
+                "DOMAIN_MAX" => domain_max = triple()?,
+                "LUT_1D_INPUT_RANGE" | "LUT_3D_INPUT_RANGE" => match values()?[..] {
+                    [min, max] => {
+                        domain_min = [min; 3];
+                        domain_max = [max; 3];
+                    }
+                    _ => return Err(format!("line {}: expected two values", number + 1)),
+                },
+                _ if is_entry => table.push(triple()?),
+                _ => return Err(format!("line {}: unknown keyword {}", number + 1, keyword)),
+            }
+        }
+
+        let kind = kind.ok_or("missing LUT_1D_SIZE or LUT_3D_SIZE")?;
+        let expected = match kind {
+            Kind::OneD => size,
+            Kind::ThreeD => size.pow(3),
+        };
+        if table.len() != expected {
+            return Err(format!(
+                "expected {} entries, found {}",
+                expected,
+                table.len()
+            ));
+        }
+        if (0..3).any(|channel| domain_min[channel] >= domain_max[channel]) {
+            return Err(String::from("DOMAIN_MIN should be below DOMAIN_MAX"));
+        }
+
+        Ok(Self {
+            kind,
+            size,
+            table,
+            domain_min,
+            domain_max,
+        })
+    }
+
+    /// A Hald CLUT of level `L` is a square image of `L³ x L³` pixels, holding
+    /// a cube of `L²` colors per side
+    fn from_hald(image: &ImageBuffer<Rgb<f32>, Vec<f32>>) -> Result<Self, String> {
+        let width = image.width();
+        let level = (width as f64).cbrt().round() as u32;
+        if image.height() != width || level.pow(3) != width || level < 2 {
+            return Err(format!(
+                "a Hald CLUT should be a square of L³ x L³ pixels, found {}x{}",
+                width,
+                image.height()
+            ));
+        }
+
+        Ok(Self {
+            kind: Kind::ThreeD,
+            size: level.pow(2) as usize,
+            table: image.pixels().map(|p| p.0).collect(),
+            domain_min: [0.0; 3],
+            domain_max: [1.0; 3],
+        })
+    }
+
+    /// Samples a 3D LUT of `size` per side from `transform`, which is applied
+    /// to an image of every color in the cube
+    pub fn sample(size: usize, transform: impl FnOnce(&mut InputImage)) -> Self {
+        let side = size as u32;
+        let step = u16::MAX as f32 / (size - 1) as f32;
+        let value = |i: u32| (i as f32 * step).round() as u16;
+
+        let mut image = ImageBuffer::from_fn(side * side, side, |x, y| {
+            Rgb([value(x % side), value(x / side), value(y)])
+        });
+        transform(&mut image);
+
+        Self {
+            kind: Kind::ThreeD,
+            size,
+            table: image
+                .pixels()
+                .map(|p| p.0.map(|v| v as f32 / u16::MAX as f32))
+                .collect(),
+            domain_min: [0.0; 3],
+            domain_max: [1.0; 3],
+        }
+    }
+
+    pub fn save_cube(&self, path: &str, title: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut cube = format!("TITLE \"{}\"\n", title);
+        match self.kind {
+            Kind::OneD => writeln!(cube, "LUT_1D_SIZE {}", self.size)?,
+            Kind::ThreeD => writeln!(cube, "LUT_3D_SIZE {}", self.size)?,
+        }
+        let [r, g, b] = self.domain_min;
+        writeln!(cube, "DOMAIN_MIN {:.6} {:.6} {:.6}", r, g, b)?;
+        let [r, g, b] = self.domain_max;
+        writeln!(cube, "DOMAIN_MAX {:.6} {:.6} {:.6}", r, g, b)?;
+        for [r, g, b] in self.table.iter() {
+            writeln!(cube, "{:.6} {:.6} {:.6}", r, g, b)?;
+        }
+
+        std::fs::write(path, cube)?;
+        println!("Saved {}", path);
+        Ok(())
+    }
+
+    /// Maps a color with values from 0 to 1
+    fn map(&self, color: [f32; 3], interpolation: LutInterpolation) -> [f32; 3] {
+        let position = [0, 1, 2].map(|channel| {
+            let (min, max) = (self.domain_min[channel], self.domain_max[channel]);
+            ((color[channel] - min) / (max - min)).clamp(0.0, 1.0) * (self.size - 1) as f32
+        });
+
+        match self.kind {
+            Kind::OneD => [0, 1, 2].map(|channel| {
+                let (i, t) = self.cell(position[channel]);
+                lerp(self.table[i][channel], self.table[i + 1][channel], t)
+            }),
+            Kind::ThreeD => self.map_3d(position, interpolation),
+        }
+    }
+
+    /// Index of the lattice point below `position`, and how far along the
+    /// cell it is
+    fn cell(&self, position: f32) -> (usize, f32) {
+        let i = (position.floor() as usize).min(self.size - 2);
+        (i, position - i as f32)
+    }
+
+    fn map_3d(&self, position: [f32; 3], interpolation: LutInterpolation) -> [f32; 3] {
+        let [(r, fr), (g, fg), (b, fb)] = position.map(|p| self.cell(p));
+        let n = self.size;
+        let corner = |dr: usize, dg: usize, db: usize| {
+            self.table[(r + dr) + (g + dg) * n + (b + db) * n * n]
+        };
+
+        match interpolation {
+            LutInterpolation::Trilinear => {
+                let c00 = mix(corner(0, 0, 0), corner(1, 0, 0), fr);
+                let c10 = mix(corner(0, 1, 0), corner(1, 1, 0), fr);
+                let c01 = mix(corner(0, 0, 1), corner(1, 0, 1), fr);
+                let c11 = mix(corner(0, 1, 1), corner(1, 1, 1), fr);
+                mix(mix(c00, c10, fg), mix(c01, c11, fg), fb)
+            }
+            LutInterpolation::Tetrahedral => {
+                let c000 = corner(0, 0, 0);
+                let c111 = corner(1, 1, 1);
+                // walk from c000 to c111 along the edges of the tetrahedron
+                // that contains the position
+                let (first, second, weights) = if fr > fg {
+                    if fg > fb {
+                        (corner(1, 0, 0), corner(1, 1, 0), [fr, fg, fb])
+                    } else if fr > fb {
+                        (corner(1, 0, 0), corner(1, 0, 1), [fr, fb, fg])
+                    } else {
+                        (corner(0, 0, 1), corner(1, 0, 1), [fb, fr, fg])
+                    }
+                } else if fb > fg {
+                    (corner(0, 0, 1), corner(0, 1, 1), [fb, fg, fr])
+                } else if fb > fr {
+                    (corner(0, 1, 0), corner(0, 1, 1), [fg, fb, fr])
+                } else {
+                    (corner(0, 1, 0), corner(1, 1, 0), [fg, fr, fb])
+                };
+                let [w1, w2, w3] = weights;
+                [0, 1, 2].map(|channel| {
+                    c000[channel]
+                        + w1 * (first[channel] - c000[channel])
+                        + w2 * (second[channel] - first[channel])
+                        + w3 * (c111[channel] - second[channel])
+                })
+            }
+        }
+    }
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [0, 1, 2].map(|channel| lerp(a[channel], b[channel], t))
+}
+
+pub fn apply_lut_mut<S: Sample>(
+    image: &mut RgbBuffer<S>,
+    lut: &Lut,
+    interpolation: LutInterpolation,
+) {
+    image.par_pixels_mut().for_each(|pixel| {
+        let color = S::values(pixel).map(|v| v.to_unit());
+        *pixel = lut.map(color, interpolation).map(S::from_unit).into();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-5;
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        assert!(
+            (0..3).all(|channel| (a[channel] - b[channel]).abs() < EPSILON),
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    /// A 2x2x2 cube whose corners are `corner(r, g, b)`
+    fn cube(corner: impl Fn(f32, f32, f32) -> [f32; 3]) -> Lut {
+        let mut contents = String::from("TITLE \"test\"\n# comment\nLUT_3D_SIZE 2\n\n");
+        for b in [0.0, 1.0] {
+            for g in [0.0, 1.0] {
+                for r in [0.0, 1.0] {
+                    let [r, g, b] = corner(r, g, b);
+                    contents.push_str(&format!("{} {} {}\n", r, g, b));
+                }
+            }
+        }
+        Lut::parse_cube(&contents).unwrap()
+    }
+
+    #[test]
+    fn identity_cube_keeps_colors() {
+        let lut = cube(|r, g, b| [r, g, b]);
+        for interpolation in [LutInterpolation::Trilinear, LutInterpolation::Tetrahedral] {
+            for color in [[0.0, 0.0, 0.0], [0.2, 0.5, 0.9], [0.7, 0.1, 0.4], [1.0; 3]] {
+                assert_close(lut.map(color, interpolation), color);
+            }
+        }
+    }
+
+    #[test]
+    fn red_changes_fastest() {
+        let lut = cube(|r, g, b| [r, g * 0.5, b * 0.25]);
+        assert_close(
+            lut.map([1.0, 1.0, 1.0], LutInterpolation::Trilinear),
+            [1.0, 0.5, 0.25],
+        );
+        assert_close(
+            lut.map([1.0, 0.0, 0.0], LutInterpolation::Trilinear),
+            [1.0, 0.0, 0.0],
+        );
+    }
+
+    #[test]
+    fn tetrahedral_keeps_neutral_colors_neutral() {
+        // only the corners on the gray axis are neutral
+        let lut = cube(|r, g, b| match (r == g, g == b) {
+            (true, true) => [r; 3],
+            _ => [r, g * 0.2, b],
+        });
+
+        let gray = lut.map([0.5; 3], LutInterpolation::Tetrahedral);
+        assert_close(gray, [0.5; 3]);
+
+        let gray = lut.map([0.5; 3], LutInterpolation::Trilinear);
+        assert!((gray[0] - gray[1]).abs() > 0.1);
+    }
+
+    #[test]
+    fn tetrahedral_differs_from_trilinear_inside_cells() {
+        let lut = cube(|r, g, b| [r * g * b; 3]);
+        let color = [0.5, 0.5, 0.5];
+        assert_close(lut.map(color, LutInterpolation::Trilinear), [0.125; 3]);
+        assert_close(lut.map(color, LutInterpolation::Tetrahedral), [0.5; 3]);
+    }
+
+    #[test]
+    fn one_d_cube_with_domain() {
+        let lut = Lut::parse_cube(
+            "LUT_1D_SIZE 3\nDOMAIN_MIN 0 0 0\nDOMAIN_MAX 2 2 2\n0 0 0\n0.25 0.5 1\n1 1 1\n",
+        )
+        .unwrap();
+        assert_close(
+            lut.map([1.0, 1.0, 1.0], LutInterpolation::Trilinear),
+            [0.25, 0.5, 1.0],
+        );
+        assert_close(
+            lut.map([0.5, 3.0, -1.0], LutInterpolation::Trilinear),
+            [0.125, 1.0, 0.0],
+        );
+    }
+
+    #[test]
+    fn invalid_cubes() {
+        let parse = |contents: &str| Lut::parse_cube(contents).unwrap_err();
+        assert_eq!(
+            parse("0 0 0\n1 1 1\n"),
+            "missing LUT_1D_SIZE or LUT_3D_SIZE"
+        );
+        assert_eq!(parse("LUT_1D_SIZE 1\n"), "line 1: invalid size");
+        assert_eq!(
+            parse("LUT_1D_SIZE 2\n0 0 0\n"),
+            "expected 2 entries, found 1"
+        );
+        assert_eq!(
+            parse("LUT_1D_SIZE 2\n0 0\n1 1 1\n"),
+            "line 2: expected three values"
+        );
+        assert_eq!(
+            parse("LUT_1D_SIZE 2\nGAMMA 2.2\n"),
+            "line 2: unknown keyword GAMMA"
+        );
+        assert_eq!(
+            parse("LUT_1D_SIZE 2\nDOMAIN_MIN 1 0 0\n0 0 0\n1 1 1\n"),
+            "DOMAIN_MIN should be below DOMAIN_MAX"
+        );
+    }
+}
@@ -1,21 +1,207 @@
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 
-use clap::{Args, Parser, ValueEnum};
-use image::{ConvertColorOptions, metadata::Cicp};
-use yancy::{conversion, io, raw_processor};
+use clap::parser::ValueSource;
+use clap::{
+    ArgAction, ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
+use glob::Pattern;
+use image::{ConvertColorOptions, Rgb, imageops, metadata::Cicp};
+use yancy::balance::{AutoBalance, BalanceOptions, Point};
+use yancy::base::{self, Region};
+use yancy::color::{ColorOptions, HueShift};
+use yancy::config::{self, ConfigFile, JobFile, Setting, Source};
+use yancy::conversion::{CropMode, InputImage, RollLevels};
+use yancy::denoise::DenoiseOptions;
+use yancy::dust::DustOptions;
+use yancy::flat_field::FlatField;
+use yancy::histogram::{Levels, RollHistogram, RollWeighting, StretchMode, StretchOptions};
+use yancy::inspect::{FrameInspection, Inspection};
+use yancy::lut::{Lut, LutInterpolation};
+use yancy::naming::{self, FrameInfo, HALVES, Layout, OutputTemplate};
+use yancy::output::{OutputFormat, Overwrite, Rendition};
+use yancy::profile::Profile;
+use yancy::raw_processor::{self, Capture};
+use yancy::sharpen::SharpenOptions;
+use yancy::sidecar::Sidecar;
+use yancy::state::{self, BatchState};
+use yancy::stats::{self, FrameStats};
+use yancy::tone::{Curve, Curves, ToneOptions};
+use yancy::{conversion, io, watch};
 
 /// yet another negative conversion thingy
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None, arg_required_else_help = true)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    arg_required_else_help = true,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Without a command, files are converted
     #[command(flatten)]
-    input: Input,
+    convert: ConvertArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Converts RAW files to positives. Also runs without a command
+    Convert(ConvertArgs),
+    /// Prints the metadata of RAW files, and the frame, film backing color and histograms detected in them, without saving any images
+    Inspect(InspectArgs),
+    /// Measures profiles from calibration shots
+    Calibrate(CalibrateArgs),
+    /// Converts RAW files quickly to small JPEGs, e.g. to check the settings before converting a roll
+    Preview(PreviewArgs),
+    /// Runs the conversions listed in a job file
+    Batch(BatchArgs),
+    /// Converts RAW files as they're added to a directory, e.g. while scanning tethered
+    Watch(WatchArgs),
+    /// Shows the settings from config files
+    Config(ConfigArgs),
+}
+
+#[derive(Args, Debug)]
+struct ConvertArgs {
+    #[command(flatten)]
+    inputs: Inputs,
+
+    #[command(flatten)]
+    settings: Settings,
+}
+
+#[derive(Args, Debug)]
+struct InspectArgs {
+    #[command(flatten)]
+    inputs: Inputs,
+
+    /// Also saves the report as JSON to this file, e.g. for checks in scripts
+    #[arg(long)]
+    json: Option<String>,
+
+    #[command(flatten)]
+    settings: Settings,
+}
+
+#[derive(Args, Debug)]
+struct CalibrateArgs {
+    #[command(subcommand)]
+    profile: Calibration,
+}
+
+#[derive(Subcommand, Debug)]
+enum Calibration {
+    /// Measures the film backing color from shots of unexposed film, e.g. the leader, and saves it as a profile for --base-profile
+    Base(BaseCalibrationArgs),
+    /// Measures how the light falls off across shots of the bare light source, without film, and saves it for --flat-field
+    FlatField(FlatFieldCalibrationArgs),
+}
+
+#[derive(Args, Debug)]
+struct BaseCalibrationArgs {
+    /// RAW files of the calibration shots
+    #[arg(required = true)]
+    files: Vec<String>,
+
+    /// Profile file to save. Other values in an existing profile are kept
+    #[arg(short, long)]
+    output: String,
+
+    /// Region of each shot to measure, as x,y,width,height. Values are fractions of the image's width and height if all are at most 1, otherwise pixels
+    #[arg(long, default_value = "0.25,0.25,0.5,0.5")]
+    region: Region,
+
+    /// Name of the film stock, saved in the profile for {film} in --output-template
+    #[arg(long)]
+    film_stock: Option<String>,
+
+    /// Flat field to even out the shots with first, as the conversions do
+    #[arg(long)]
+    flat_field: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct FlatFieldCalibrationArgs {
+    /// RAW files of the calibration shots, taken with the same camera, lens and aperture as the scans
+    #[arg(required = true)]
+    files: Vec<String>,
+
+    /// Flat field file to save
+    #[arg(short, long)]
+    output: String,
+}
+
+#[derive(Args, Debug)]
+struct PreviewArgs {
+    #[command(flatten)]
+    inputs: Inputs,
+
+    /// Longest side of the previews in pixels
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(16..))]
+    size: u32,
+
+    #[command(flatten)]
+    settings: Settings,
+}
+
+#[derive(Args, Debug)]
+struct BatchArgs {
+    /// Job file with a [[jobs]] table for each conversion. Keys are the long names of options, including the inputs and preset. Top-level keys are used by every job
+    job_file: String,
+}
+
+#[derive(Args, Debug)]
+struct ConfigArgs {
+    #[command(subcommand)]
+    action: ConfigAction,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Prints the settings in effect as TOML, with where each of them came from. Takes the same options as a conversion
+    Show(Settings),
+}
+
+#[derive(Args, Debug)]
+struct WatchArgs {
+    /// Directory to watch for new RAW files
+    dir: String,
+
+    /// Measures the film backing color on the first frame that it's detected on confidently, and uses it for every frame after it
+    #[arg(long, default_value_t = false)]
+    roll_base: bool,
+
+    #[command(flatten)]
+    settings: Settings,
+}
+
+/// Settings of the conversion and its outputs
+#[derive(Args, Debug)]
+struct Settings {
+    /// Named set of settings from a [presets] table in yancy.toml or ~/.config/yancy/config.toml. Options given on the command line take precedence
+    #[arg(long)]
+    preset: Option<String>,
 
     /// Output file format
     #[arg(long, default_value_t = OutputFormat::Tiff)]
     output_format: OutputFormat,
 
-    /// Output file suffix
+    /// File saved for each frame, as FORMAT[,KEY=VALUE...], e.g. jpeg,size=2048,quality=85,suffix=web. Keys are size (longest side in pixels), depth (8, 16 or 32), quality (1 to 100, JPEG and AVIF), subsampling (444, 422 or 420, JPEG), speed (1 to 10, AVIF), lossless (WebP), compression (none, lzw or deflate, TIFF), suffix and dir (relative to the input or --output-dir). Can be repeated, replacing --output-format
+    #[arg(long = "output", conflicts_with = "output_format")]
+    outputs: Vec<Rendition>,
+
+    /// Adjusts frames in 32-bit float, so that values aren't rounded between steps, and ones outside of the range are kept for EXR and 32-bit TIFF outputs. Always on for those outputs
+    #[arg(long, default_value_t = false)]
+    float: bool,
+
+    /// Output file suffix, unless an --output sets its own
     #[arg(long, default_value = "positive")]
     output_suffix: String,
 
@@ -23,6 +209,34 @@ struct Cli {
     #[arg(long)]
     output_dir_suffix: Option<String>,
 
+    /// Directory to save every output in, instead of next to its input
+    #[arg(long, conflicts_with = "output_dir_suffix")]
+    output_dir: Option<PathBuf>,
+
+    /// Names of outputs, without the extension, e.g. {roll}/{date}_{seq:3}. Placeholders are {stem} (input file name without extension), {frame} (index in the file), {half} (a or b for half frames), {date} (capture date), {camera}, {film}, {roll}, {seq} (index in the run) and {suffix}. {frame:N} and {seq:N} are padded to N digits
+    #[arg(long)]
+    output_template: Option<OutputTemplate>,
+
+    /// Name of the film stock, for {film} in --output-template. Defaults to the one in --base-profile
+    #[arg(long)]
+    film_stock: Option<String>,
+
+    /// Name of the roll, for {roll} in --output-template. Defaults to the name of each input's directory
+    #[arg(long)]
+    roll_name: Option<String>,
+
+    /// Whether outputs that already exist are saved again
+    #[arg(long, value_enum, default_value_t = Overwrite::Always)]
+    overwrite: Overwrite,
+
+    /// Records finished and failed inputs in a state file in --output-dir (or the input directory), and skips inputs that were converted with the same settings and haven't changed since
+    #[arg(long, default_value_t = false)]
+    resume: bool,
+
+    /// Flat field saved by calibrate flat-field, which evens out the light source and vignetting before anything else
+    #[arg(long)]
+    flat_field: Option<String>,
+
     /// Splits input file(s) in half vertically before processing
     #[arg(long, default_value_t = false)]
     half_frame: bool,
@@ -31,13 +245,221 @@ struct Cli {
     #[arg(long)]
     aspect_ratio: Option<f32>,
 
+    /// What to crop the output to: none, frame or full-bleed. A fraction is still accepted as --crop-inset, but deprecated
+    #[arg(long, default_value = "frame", value_parser = parse_crop)]
+    crop: Crop,
+
     /// Amount of additional crop after border removal, as a percentage of the original image's width and height
     #[arg(short = 'c', long, default_value_t = 0.01)]
-    crop: f32,
+    crop_inset: f32,
+
+    /// Removes dust and other small defects from the negative, by filling them in from their surroundings
+    #[arg(long, default_value_t = false)]
+    dust_removal: bool,
+
+    /// How faint a defect may be and still be removed, from 0 to 1
+    #[arg(long, default_value_t = 0.5)]
+    dust_sensitivity: f32,
+
+    /// Largest defect that is removed, in pixels of the original
+    #[arg(long, default_value_t = 25)]
+    dust_max_size: u32,
+
+    /// Region to sample the film backing color from, as x,y,width,height. Values are fractions of the image's width and height if all are at most 1, otherwise pixels
+    #[arg(long)]
+    base_region: Option<Region>,
+
+    /// Film backing color as 16-bit r,g,b, used when it can't be detected confidently, e.g. as measured from another frame of the roll
+    #[arg(long, value_parser = parse_color)]
+    base_color: Option<Rgb<u16>>,
+
+    /// Profile file with a film backing color, used when it can't be detected confidently unless --base-color is set, and optionally the film stock
+    #[arg(long)]
+    base_profile: Option<String>,
+
+    /// Detected film backing colors with a lower confidence (0 to 1) are replaced with --base-color or --base-profile
+    #[arg(long, default_value_t = 0.5)]
+    min_base_confidence: f32,
+
+    /// Detects sprocket holes and the film rebate, for scans that show the full width of 35mm film. Vertical film is rotated to run horizontally
+    #[arg(long, default_value_t = false)]
+    sprockets: bool,
+
+    /// How to stretch histograms after inversion
+    #[arg(long, value_enum, default_value_t = StretchMode::Independent)]
+    stretch: StretchMode,
+
+    /// Share of pixels that may be clipped to black in each channel when stretching
+    #[arg(long, default_value_t = 0.0001)]
+    clip_black: f32,
+
+    /// Share of pixels that may be clipped to white in each channel when stretching
+    #[arg(long, default_value_t = 0.0001)]
+    clip_white: f32,
+
+    /// Share of pixels that are never clipped when stretching, however the histogram is shaped
+    #[arg(long, default_value_t = 0.005)]
+    clip_limit: f32,
+
+    /// Maximum factor that the range of values may be stretched by, e.g. to keep foggy scenes from turning noisy
+    #[arg(long)]
+    max_gain: Option<f32>,
+
+    /// Stretches every frame with the same levels, measured from all of the frames in a first pass, so that the roll looks consistent
+    #[arg(long, default_value_t = false)]
+    roll_levels: bool,
+
+    /// How frames are weighted when measuring roll levels
+    #[arg(long, value_enum, default_value_t = RollWeighting::Equal)]
+    roll_weighting: RollWeighting,
+
+    /// How far each frame's own levels may deviate from the roll levels, as a percentage of the full range
+    #[arg(long, default_value_t = 0.0)]
+    roll_max_deviation: f32,
+
+    /// Saves the measured roll levels to a file, for reuse with --load-roll-levels
+    #[arg(long, requires = "roll_levels")]
+    save_roll_levels: Option<String>,
+
+    /// Stretches every frame with roll levels saved by --save-roll-levels, instead of measuring them
+    #[arg(long, conflicts_with = "roll_levels")]
+    load_roll_levels: Option<String>,
+
+    /// White balance after inversion, from -100 (cooler) to 100 (warmer)
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    temperature: f32,
+
+    /// White balance after inversion, from -100 (greener) to 100 (more magenta)
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    tint: f32,
+
+    /// Point of the output that should be neutral gray, as x,y in pixels or fractions of the output
+    #[arg(long)]
+    neutral_point: Option<Point>,
+
+    /// Balances the output automatically after inversion. Ignored if --neutral-point is set
+    #[arg(long, value_enum, default_value_t = AutoBalance::None)]
+    auto_balance: AutoBalance,
+
+    /// Strength of luma noise reduction after stretching, from 0 to 100. Film grain is luma noise, so keep it low to keep the grain
+    #[arg(long, default_value_t = 0.0)]
+    denoise_luma: f32,
+
+    /// Strength of color noise reduction after stretching, from 0 to 100
+    #[arg(long, default_value_t = 0.0)]
+    denoise_chroma: f32,
+
+    /// Radius of noise reduction in pixels. Color noise is averaged over twice the radius
+    #[arg(long, default_value_t = 2)]
+    denoise_radius: u32,
+
+    /// Exposure compensation in stops, applied after stretching
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    exposure: f32,
+
+    /// Strength of an S-curve around the midtones. Negative values lower the contrast
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    contrast: f32,
+
+    /// Rolls off highlights instead of clipping them, starting this far below white (0 to 1)
+    #[arg(long, default_value_t = 0.0)]
+    highlights: f32,
+
+    /// Rolls off shadows instead of clipping them, starting this far above black (0 to 1)
+    #[arg(long, default_value_t = 0.0)]
+    shadows: f32,
+
+    /// Value that becomes black after stretching (0 to 1)
+    #[arg(long, default_value_t = 0.0)]
+    black_point: f32,
+
+    /// Value that becomes white after stretching (0 to 1)
+    #[arg(long, default_value_t = 1.0)]
+    white_point: f32,
+
+    /// Custom curve through control points as CHANNEL:x,y/x,y/..., where CHANNEL is rgb, r, g or b. Can be repeated
+    #[arg(long = "curve", value_parser = parse_curve)]
+    curves: Vec<(String, Curve)>,
+
+    /// Saturation from -100 (grayscale) to 100 (twice the chroma), applied in OKLCh
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    saturation: f32,
+
+    /// Like --saturation, but mostly affects muted colors
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    vibrance: f32,
+
+    /// Rotates hues near HUE by up to SHIFT degrees, as HUE:SHIFT[:WIDTH], e.g. green:-10. HUE is in degrees or a color name (red, orange, yellow, green, aqua, blue, purple, magenta). Can be repeated
+    #[arg(long = "hue-shift", allow_hyphen_values = true)]
+    hue_shifts: Vec<HueShift>,
+
+    /// Look applied after everything else, from a .cube file (1D or 3D) or a Hald CLUT image
+    #[arg(long)]
+    lut: Option<String>,
+
+    /// How colors between the points of a 3D LUT are interpolated
+    #[arg(long, value_enum, default_value_t = LutInterpolation::Tetrahedral)]
+    lut_interpolation: LutInterpolation,
+
+    /// Sharpens the output with an unsharp mask, adding back this percentage of the detail (0 to 500)
+    #[arg(long, default_value_t = 0.0)]
+    sharpen: f32,
+
+    /// Radius of sharpening in pixels. Defaults to one that suits the size of the output
+    #[arg(long)]
+    sharpen_radius: Option<f32>,
+
+    /// Differences below this share of the full range (0 to 1) aren't sharpened, which keeps smooth areas smooth
+    #[arg(long, default_value_t = 0.0)]
+    sharpen_threshold: f32,
+
+    /// Only sharpens edges, so that film grain isn't sharpened
+    #[arg(long, default_value_t = false)]
+    sharpen_edges: bool,
+
+    /// Saves the conversion of the first frame (white balance, invert, levels and tone) as a .cube file, for use in other tools
+    #[arg(long)]
+    export_lut: Option<String>,
+
+    /// Number of points per side of the LUT saved by --export-lut
+    #[arg(long, default_value_t = 33, value_parser = clap::value_parser!(u32).range(2..=256))]
+    export_lut_size: u32,
+
+    /// Saves the detected crop and base color to a sidecar file next to each input, for manual editing
+    #[arg(long, default_value_t = false)]
+    write_sidecars: bool,
+
+    /// Writes the histograms, levels, base color and crop of each frame to a .json or .csv file
+    #[arg(long)]
+    export_stats: Option<String>,
 
     /// Saves intermediate images during processing
     #[arg(long, default_value_t = false)]
     debug: bool,
+
+    /// Values of the arguments that were given, by their id, which --resume
+    /// compares. Filled in by `parse`.
+    #[arg(skip)]
+    given: BTreeMap<String, Vec<String>>,
+}
+
+/// Files given with -f or -d
+#[derive(Args, Debug)]
+struct Inputs {
+    #[command(flatten)]
+    input: Input,
+
+    /// Also converts files in subdirectories of -d. Their structure is mirrored under --output-dir
+    #[arg(short = 'r', long, default_value_t = false, conflicts_with = "file")]
+    recursive: bool,
+
+    /// Only converts files in -d whose path relative to it matches one of these glob patterns, e.g. "2024/**"
+    #[arg(long, conflicts_with = "file")]
+    include: Vec<Pattern>,
+
+    /// Skips files in -d whose path relative to it matches one of these glob patterns, e.g. "**/rejects/**"
+    #[arg(long, conflicts_with = "file")]
+    exclude: Vec<Pattern>,
 }
 
 #[derive(Args, Debug)]
@@ -52,73 +474,889 @@ struct Input {
     dir: Option<String>,
 }
 
-#[derive(Clone, ValueEnum, Debug)]
-enum OutputFormat {
-    Png,
-    Jpeg,
-    Webp,
-    Tiff,
-    Avif,
+fn parse_curve(s: &str) -> Result<(String, Curve), String> {
+    match s.split_once(':') {
+        Some((channel @ ("rgb" | "r" | "g" | "b"), points)) => {
+            Ok((channel.to_owned(), points.parse()?))
+        }
+        _ => Err(format!(
+            "invalid curve \"{}\": expected CHANNEL:x,y/x,y/..., where CHANNEL is rgb, r, g or b",
+            s
+        )),
+    }
 }
 
-impl std::fmt::Display for OutputFormat {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Png => write!(f, "png"),
-            Self::Jpeg => write!(f, "jpeg"),
-            Self::Webp => write!(f, "webp"),
-            Self::Tiff => write!(f, "tiff"),
-            Self::Avif => write!(f, "avif"),
-        }
+/// Value of --crop, which used to be the inset, before there were modes
+#[derive(Clone, Copy, Debug)]
+enum Crop {
+    Mode(CropMode),
+    /// Deprecated, replaced by --crop-inset
+    Inset(f32),
+}
+
+fn parse_crop(s: &str) -> Result<Crop, String> {
+    if let Ok(inset) = s.parse() {
+        return Ok(Crop::Inset(inset));
+    }
+    CropMode::from_str(s, true)
+        .map(Crop::Mode)
+        .map_err(|_| format!("invalid crop \"{}\": expected none, frame or full-bleed", s))
+}
+
+fn parse_color(s: &str) -> Result<Rgb<u16>, String> {
+    let values: Vec<u16> = s
+        .split(',')
+        .map(|v| v.trim().parse::<u16>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("invalid color \"{}\": {}", s, e))?;
+
+    match values[..] {
+        [r, g, b] => Ok(Rgb([r, g, b])),
+        _ => Err(format!("invalid color \"{}\": expected r,g,b", s)),
     }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Cli::parse();
+    let (args, config) = with_config(std::env::args_os().collect())?;
+    let matches = cli().get_matches_from(args);
+    let cli = parse(&matches)?;
+
+    match &cli.command {
+        Some(Command::Convert(convert_args)) => {
+            convert(&convert_args.inputs, &convert_args.settings, None)
+        }
+        Some(Command::Inspect(inspect_args)) => inspect(inspect_args),
+        Some(Command::Calibrate(CalibrateArgs {
+            profile: Calibration::Base(base_args),
+        })) => calibrate_base(base_args),
+        Some(Command::Calibrate(CalibrateArgs {
+            profile: Calibration::FlatField(flat_field_args),
+        })) => calibrate_flat_field(flat_field_args),
+        Some(Command::Preview(preview_args)) => preview(preview_args),
+        Some(Command::Batch(batch_args)) => run_jobs(batch_args),
+        Some(Command::Watch(watch_args)) => watch_dir(watch_args),
+        Some(Command::Config(ConfigArgs {
+            action: ConfigAction::Show(_),
+        })) => {
+            show_config(&matches, &config);
+            Ok(())
+        }
+        None => convert(&cli.convert.inputs, &cli.convert.settings, None),
+    }
+}
+
+/// The command line interface, with the flags that turn off flags set in
+/// config files
+fn cli() -> clap::Command {
+    config::with_negations(Cli::command())
+}
+
+/// Arguments that don't change what outputs look like, which --resume doesn't
+/// compare
+const UNCOMPARED_ARGS: [&str; 6] = [
+    "preset",
+    "overwrite",
+    "resume",
+    "file",
+    "include",
+    "exclude",
+];
+
+impl Cli {
+    /// Settings of the command that is run, if it takes any
+    fn settings_mut(&mut self) -> Option<&mut Settings> {
+        match &mut self.command {
+            Some(Command::Convert(ConvertArgs { settings, .. }))
+            | Some(Command::Inspect(InspectArgs { settings, .. }))
+            | Some(Command::Preview(PreviewArgs { settings, .. }))
+            | Some(Command::Watch(WatchArgs { settings, .. }))
+            | Some(Command::Config(ConfigArgs {
+                action: ConfigAction::Show(settings),
+            })) => Some(settings),
+            Some(Command::Calibrate(_) | Command::Batch(_)) => None,
+            None => Some(&mut self.convert.settings),
+        }
+    }
+}
+
+/// Parses `matches`, and records which values of the settings were given
+fn parse(matches: &ArgMatches) -> Result<Cli, clap::Error> {
+    let mut parsed = Cli::from_arg_matches(matches)?;
+    let root = cli();
+    let (command, matches, _) = innermost(&root, matches);
+    if let Some(settings) = parsed.settings_mut() {
+        settings.given = command
+            .get_arguments()
+            .map(|arg| arg.get_id().as_str())
+            .filter(|id| !UNCOMPARED_ARGS.contains(id) && !id.starts_with(config::NEGATION_PREFIX))
+            .filter(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+            .filter_map(|id| {
+                let values = matches.get_raw(id)?;
+                let values = values.map(|v| v.to_string_lossy().into_owned()).collect();
+                Some((id.to_owned(), values))
+            })
+            .collect();
+    }
+    Ok(parsed)
+}
+
+/// The subcommand that is run, with its matches and the number of arguments
+/// that name it, including the program's name
+fn innermost<'a>(
+    command: &'a clap::Command,
+    matches: &'a ArgMatches,
+) -> (&'a clap::Command, &'a ArgMatches, usize) {
+    let (mut command, mut matches, mut depth) = (command, matches, 1);
+    while let Some((name, sub_matches)) = matches.subcommand() {
+        command = command
+            .find_subcommand(name)
+            .expect("subcommand should be defined");
+        matches = sub_matches;
+        depth += 1;
+    }
+    (command, matches, depth)
+}
+
+/// Settings from config files, by the id of their argument
+type Config = BTreeMap<String, Setting>;
+
+/// Adds the settings of the user's and the project's config files, and of
+/// --preset, to the command line arguments `args`, below the ones given there.
+/// Returns the arguments, and the settings that were added.
+fn with_config(args: Vec<OsString>) -> Result<(Vec<OsString>, Config), Box<dyn std::error::Error>> {
+    let mut root = cli();
+    root.build();
+    let matches = root
+        .clone()
+        .try_get_matches_from(&args)
+        .unwrap_or_else(|e| e.exit());
+    let (command, matches, depth) = innermost(&root, &matches);
+    if command.get_arguments().all(|arg| arg.get_id() != "preset") {
+        return Ok((args, BTreeMap::new()));
+    }
 
-    let files: Vec<String> = if let Some(files) = &args.input.file {
-        files
+    // in increasing precedence
+    let paths = [
+        config::user_path(),
+        Some(PathBuf::from(config::PROJECT_FILE_NAME)),
+    ];
+    let mut files = vec![];
+    for path in paths.into_iter().flatten() {
+        files.extend(ConfigFile::load(&path)?);
+    }
+
+    let preset = matches.get_one::<String>("preset").map(String::as_str);
+    let mut settings = config::resolve(&files, preset, command, &root)?;
+
+    // the command line takes precedence over config files
+    let given: Vec<&str> = command
+        .get_arguments()
+        .map(|arg| arg.get_id().as_str())
+        .filter(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+        .collect();
+    settings.retain(|id, _| {
+        !given.iter().any(|given| {
+            given == id || *given == config::negation(id) || config::conflicts(command, id, given)
+        })
+    });
+
+    let mut with_config = args[..depth].to_vec();
+    with_config.extend(settings.values().flat_map(|setting| setting.args.clone()));
+    with_config.extend_from_slice(&args[depth..]);
+    Ok((with_config, settings))
+}
+
+/// Prints the settings in effect as TOML, with where each of them came from
+fn show_config(matches: &ArgMatches, config: &Config) {
+    let command = cli();
+    let (command, matches, _) = innermost(&command, matches);
+
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let (Some(long), Some(values)) = (arg.get_long(), matches.get_raw(id)) else {
+            continue;
+        };
+        if id == "preset" || id.starts_with(config::NEGATION_PREFIX) {
+            continue;
+        }
+
+        let values: Vec<toml::Value> = values
+            .map(|value| {
+                let value = value.to_string_lossy();
+                if let Ok(integer) = value.parse() {
+                    toml::Value::Integer(integer)
+                } else if let Ok(float) = value.parse::<f64>()
+                    && float.is_finite()
+                {
+                    toml::Value::Float(float)
+                } else if let Ok(boolean) = value.parse() {
+                    toml::Value::Boolean(boolean)
+                } else {
+                    toml::Value::String(value.into_owned())
+                }
+            })
+            .collect();
+        let value = match arg.get_action() {
+            ArgAction::Append => toml::Value::Array(values),
+            _ => values
+                .into_iter()
+                .next()
+                .unwrap_or(toml::Value::Boolean(false)),
+        };
+
+        let negated = matches!(arg.get_action(), ArgAction::SetTrue)
+            && matches.value_source(&config::negation(id)) == Some(ValueSource::CommandLine);
+        let source = match (config.get(id), matches.value_source(id)) {
+            (Some(setting), _) => setting.source.clone(),
+            (None, Some(ValueSource::DefaultValue)) if !negated => Source::Default,
+            _ => Source::CommandLine,
+        };
+        println!("{} = {}  # {}", long, value, source);
+    }
+}
+
+/// Paths of the files given with -f or -d
+fn input_files(inputs: &Inputs) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    if let Some(files) = &inputs.input.file {
+        Ok(files
             .into_iter()
             .flat_map(|file| {
-                if io::has_raw_file_extension(&Path::new(&file)) {
+                if io::is_raw_input(&Path::new(&file)) {
                     Some(String::from(file))
                 } else {
                     None
                 }
             })
-            .collect()
-    } else if let Some(dir) = &args.input.dir {
-        io::read_dir_raw_files(&dir)?
-            .iter()
-            .map(|file_path| {
-                String::from(
-                    file_path
-                        .to_str()
-                        .expect("file path should be a valid UTF-8 sequence"),
-                )
-            })
-            .collect()
+            .collect())
+    } else if let Some(dir) = &inputs.input.dir {
+        let files: Vec<String> =
+            io::read_dir_raw_files(dir, inputs.recursive, &inputs.include, &inputs.exclude)?
+                .iter()
+                .map(|file_path| {
+                    String::from(
+                        file_path
+                            .to_str()
+                            .expect("file path should be a valid UTF-8 sequence"),
+                    )
+                })
+                .collect();
+        if files.is_empty() {
+            let hint = if inputs.recursive {
+                ""
+            } else {
+                ". Use --recursive to include subdirectories"
+            };
+            return Err(format!("No supported RAW files found in {}{}", dir, hint).into());
+        }
+        Ok(files)
     } else {
         panic!("expected either directory or file inputs");
+    }
+}
+
+/// Converts the files given with -f or -d, or makes previews of them no
+/// larger than `preview_size`
+fn convert(
+    inputs: &Inputs,
+    args: &Settings,
+    preview_size: Option<u32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let files = input_files(inputs)?;
+
+    if let Some(path) = &args.export_stats {
+        stats::Format::from_path(path)?;
+    }
+
+    let input_dir = inputs.input.dir.as_deref();
+    let renditions = match preview_size {
+        Some(size) => preview_renditions(args, size)?,
+        None => renditions(args)?,
     };
+    let layout = layout(args, input_dir)?;
+    let mut options = conversion_options(args)?;
+    options.float |= renditions.iter().any(Rendition::is_float);
+    if preview_size.is_some() {
+        // specks and noise barely show at the size of a preview
+        options.dust = None;
+        options.denoise = DenoiseOptions::default();
+    }
+    let working_size = preview_size.map(|size| size * PREVIEW_OVERSAMPLING);
 
-    files.into_iter().for_each(|file| {
-        if let Err(e) = process_file(&file, &args) {
-            println!("Unable to process file {}: {}", file, e);
+    if let Some(path) = &args.load_roll_levels {
+        options.roll_levels = Some(RollLevels {
+            levels: Levels::load(path)?,
+            max_deviation: args.roll_max_deviation,
+        });
+    } else if args.roll_levels {
+        options.roll_levels = measure_roll_levels(&files, args, &options, working_size)?;
+    }
+
+    if let Some(path) = &args.export_lut {
+        match files.first() {
+            Some(file) => export_lut(file, path, args, &options)?,
+            None => println!("Warning: no files to export a LUT from"),
         }
-    });
+    }
+
+    let state_path = args.resume.then(|| state_path(args, input_dir, &files));
+    let mut batch = Batch::new(args, options, renditions, layout, working_size, state_path)?;
+    for (i, file) in files.iter().enumerate() {
+        batch.convert_file(file, i);
+    }
+    batch.finish()
+}
+
+/// Suffix of previews, unless an --output sets its own
+const PREVIEW_SUFFIX: &str = "preview";
+
+/// Inputs of previews are scaled down to this many times the size of the
+/// previews, so that the frame still covers it after cropping
+const PREVIEW_OVERSAMPLING: u32 = 2;
+
+/// Converts the files given with -f or -d to small previews, from inputs
+/// that are scaled down first
+fn preview(preview_args: &PreviewArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let args = &preview_args.settings;
+
+    if args.write_sidecars {
+        return Err(
+            "--write-sidecars can't be used with preview, whose crops are in pixels of the scaled down inputs"
+                .into(),
+        );
+    }
+    if args.export_lut.is_some() {
+        return Err("--export-lut can't be used with preview".into());
+    }
+
+    convert(&preview_args.inputs, args, Some(preview_args.size))
+}
+
+/// The files to save for each frame of a preview, no larger than `size`. A
+/// JPEG, unless --output is given.
+fn preview_renditions(args: &Settings, size: u32) -> Result<Vec<Rendition>, String> {
+    if args.outputs.is_empty() {
+        let mut rendition = Rendition::new(OutputFormat::Jpeg);
+        rendition.size = Some(size);
+        rendition.suffix = Some(String::from(PREVIEW_SUFFIX));
+        return Ok(vec![rendition]);
+    }
+
+    let mut renditions = renditions(args)?;
+    for rendition in &mut renditions {
+        rendition.size = Some(rendition.size.map_or(size, |s| s.min(size)));
+    }
+    Ok(renditions)
+}
+
+/// Prints what's detected in the files given with -f or -d, and optionally
+/// saves it as JSON
+fn inspect(inspect_args: &InspectArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let args = &inspect_args.settings;
+    let files = input_files(&inspect_args.inputs)?;
+
+    let mut options = conversion_options(args)?;
+    options.collect_histograms = true;
+
+    let mut inspections = vec![];
+    for file in &files {
+        match inspect_file(file, args, &options) {
+            Ok(inspection) => {
+                print!("{}", inspection);
+                inspections.push(inspection);
+            }
+            Err(e) => println!("Unable to inspect file {}: {}", file, e),
+        }
+    }
+
+    if let Some(path) = &inspect_args.json {
+        std::fs::write(path, serde_json::to_string_pretty(&inspections)?)?;
+        println!("Saved {}", path);
+    }
+
+    // fail, so that checks in scripts notice
+    if inspections.len() < files.len() {
+        return Err(format!(
+            "Unable to inspect {} of {} file(s)",
+            files.len() - inspections.len(),
+            files.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn inspect_file(
+    path: &str,
+    args: &Settings,
+    options: &conversion::Options,
+) -> Result<Inspection, Box<dyn std::error::Error>> {
+    let file = load_frames(path, args, None, false)?;
+
+    let mut frames = vec![];
+    for (frame_path, image) in &file.frames {
+        let sidecar = file.sidecar(frame_path)?;
+        let converted =
+            conversion::convert(image, options, &sidecar, None, &args.output_dir_suffix)?;
+        frames.push(FrameInspection::new(frame_path, &converted));
+    }
+
+    Ok(Inspection {
+        file: path.to_owned(),
+        capture: file.capture,
+        size: file.size,
+        frames,
+    })
+}
+
+/// Measures the film backing color of calibration shots, and saves it as a
+/// profile
+fn calibrate_base(calibrate_args: &BaseCalibrationArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut colors = vec![];
+    for file in &calibrate_args.files {
+        let (image, _) = load_image(file, calibrate_args.flat_field.as_deref())
+            .map_err(|e| format!("Unable to process file {}: {}", file, e))?;
+        let bounds @ [min_x, min_y, max_x, max_y] = calibrate_args
+            .region
+            .to_bounds(image.width(), image.height());
+        if min_x >= max_x || min_y >= max_y {
+            return Err(format!(
+                "The region is outside of {}, which is {}x{}",
+                file,
+                image.width(),
+                image.height()
+            )
+            .into());
+        }
+
+        let color = base::sample_region(&image, bounds);
+        let [r, g, b] = color.0;
+        println!("Measured {},{},{} in {}", r, g, b, file);
+        colors.push(color);
+    }
+
+    let color = base::robust_average(colors);
+    let [r, g, b] = color.0;
+    println!("Film backing color: {},{},{}", r, g, b);
+
+    let output = &calibrate_args.output;
+    let mut profile = if Path::new(output).is_file() {
+        Profile::load(output)?
+    } else {
+        Profile::default()
+    };
+    profile.base_color = Some(color.0);
+    if let Some(film_stock) = &calibrate_args.film_stock {
+        profile.film_stock = Some(film_stock.clone());
+    }
+    profile.save(output)
+}
+
+/// Measures the falloff of the light across calibration shots, and saves it
+/// as a flat field
+fn calibrate_flat_field(
+    calibrate_args: &FlatFieldCalibrationArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut fields = vec![];
+    for file in &calibrate_args.files {
+        // measured on linear values, which is where it's applied
+        let (image, _) = raw_processor::load_raw_image(file)
+            .map_err(|e| format!("Unable to process file {}: {}", file, e))?;
+        let field = FlatField::measure(&image)
+            .map_err(|e| format!("Unable to measure file {}: {}", file, e))?;
+        println!("Measured {}", file);
+        fields.push(field);
+    }
+
+    let field = FlatField::average(&fields)?;
+    let darkest = field
+        .falloff
+        .iter()
+        .flatten()
+        .fold(1.0_f32, |darkest, &v| darkest.min(v));
+    println!(
+        "The darkest part of the image gets {:.0}% of the light of the brightest",
+        darkest * 100.0
+    );
+    field.save(&calibrate_args.output)
+}
+
+/// Runs the conversions listed in a job file, one after another. Every job is
+/// checked before any of them runs.
+fn run_jobs(batch_args: &BatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let job_file = JobFile::load(Path::new(&batch_args.job_file))?;
+    if job_file.jobs.is_empty() {
+        return Err(format!("No jobs in {}", batch_args.job_file).into());
+    }
+
+    let mut root = cli();
+    root.build();
+    let convert_command = root
+        .find_subcommand("convert")
+        .expect("convert should be a subcommand");
+
+    let mut jobs = vec![];
+    for index in 0..job_file.jobs.len() {
+        let invalid = |e: clap::Error| {
+            format!(
+                "Invalid job {} in {}: {}",
+                index + 1,
+                batch_args.job_file,
+                error_message(&e)
+            )
+        };
+
+        let mut args = vec![OsString::from(root.get_name()), OsString::from("convert")];
+        args.extend(job_file.args(index, convert_command)?);
+        cli().try_get_matches_from(&args).map_err(invalid)?;
+
+        let (args, _) = with_config(args)?;
+        let matches = cli().try_get_matches_from(&args).map_err(invalid)?;
+        match parse(&matches)?.command {
+            Some(Command::Convert(convert_args)) => jobs.push(convert_args),
+            _ => unreachable!("jobs are conversions"),
+        }
+    }
 
+    let mut failed = 0;
+    for (i, job) in jobs.iter().enumerate() {
+        println!("Running job {} of {}...", i + 1, jobs.len());
+        if let Err(e) = convert(&job.inputs, &job.settings, None) {
+            println!("Job {} failed: {}", i + 1, e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} job(s) failed", failed, jobs.len()).into());
+    }
     Ok(())
 }
 
-fn process_file(path: &str, args: &Cli) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Converting file {}...", path);
+/// The message of a command line error, without the usage
+fn error_message(e: &clap::Error) -> String {
+    let message = e.to_string();
+    let lines: Vec<&str> = message
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .map(str::trim)
+        .collect();
+    let message = lines.join(" ");
+    message
+        .strip_prefix("error: ")
+        .unwrap_or(&message)
+        .to_owned()
+}
+
+/// Converts RAW files as they're added to a directory, until it's stopped
+fn watch_dir(watch_args: &WatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let args = &watch_args.settings;
+    let dir = watch_args.dir.as_str();
+
+    if args.roll_levels {
+        return Err(
+            "--roll-levels needs every frame up front. Use --load-roll-levels to stretch the frames with the levels of another roll".into(),
+        );
+    }
+    if args.export_lut.is_some() {
+        return Err("--export-lut can't be used with watch".into());
+    }
+    if let Some(path) = &args.export_stats {
+        stats::Format::from_path(path)?;
+    }
+
+    let renditions = renditions(args)?;
+    let layout = layout(args, Some(dir))?;
+
+    let mut options = conversion_options(args)?;
+    options.float |= renditions.iter().any(Rendition::is_float);
+    if let Some(path) = &args.load_roll_levels {
+        options.roll_levels = Some(RollLevels {
+            levels: Levels::load(path)?,
+            max_deviation: args.roll_max_deviation,
+        });
+    }
+
+    let state_path = args.resume.then(|| state_path(args, Some(dir), &[]));
+    let mut batch = Batch::new(args, options, renditions, layout, None, state_path)?;
+    let mut index = 0;
+
+    println!("Watching {} for new RAW files. Press Ctrl+C to stop", dir);
+    watch::watch_raw_files(dir, |file| {
+        let first_frame = batch.frame_stats.len();
+        let skipped = batch.skipped;
+        batch.convert_file(file, index);
+        index += 1;
+        if batch.skipped > skipped {
+            println!("Skipped {}, which was converted already", file);
+        }
+
+        if watch_args.roll_base && batch.options.base_color.is_none() {
+            let min_confidence = batch.options.min_base_confidence;
+            let calibration = batch.frame_stats[first_frame..]
+                .iter()
+                .find(|stats| stats.base_confidence.is_some_and(|c| c >= min_confidence));
+            if let Some(calibration) = calibration {
+                let [r, g, b] = calibration.base_color;
+                println!(
+                    "Using the film backing color of {} for the rest of the roll: {},{},{}",
+                    calibration.file, r, g, b
+                );
+                batch.options.base_color = Some(Rgb(calibration.base_color));
+                batch.describe();
+            }
+        }
+
+        if let Some(path) = &args.export_stats
+            && let Err(e) = stats::export(path, &batch.frame_stats)
+        {
+            println!("Warning: unable to save {}: {}", path, e);
+        }
+        println!("Watching {} for new RAW files...", dir);
+    })
+}
+
+/// The files to save for each frame, which shouldn't overwrite each other
+fn renditions(args: &Settings) -> Result<Vec<Rendition>, String> {
+    if args.outputs.is_empty() {
+        return Ok(vec![Rendition::new(args.output_format)]);
+    }
+
+    let destination = |r: &Rendition| {
+        (
+            r.dir.clone(),
+            r.suffix
+                .clone()
+                .unwrap_or_else(|| args.output_suffix.clone()),
+            r.format,
+        )
+    };
+    for (i, rendition) in args.outputs.iter().enumerate() {
+        if args.outputs[..i]
+            .iter()
+            .any(|other| destination(other) == destination(rendition))
+        {
+            return Err(format!(
+                "Two outputs would be saved to the same files (.{}.{}). Give them different suffixes or dirs",
+                destination(rendition).1,
+                rendition.format
+            ));
+        }
+    }
+    Ok(args.outputs.clone())
+}
+
+/// Where outputs are saved, and what they're named
+fn layout(args: &Settings, input_dir: Option<&str>) -> Result<Layout, Box<dyn std::error::Error>> {
+    let film_stock = match (&args.film_stock, &args.base_profile) {
+        (Some(film_stock), _) => Some(film_stock.clone()),
+        (None, Some(path)) => Profile::load(path)?.film_stock,
+        (None, None) => None,
+    };
+
+    if let Some(template) = &args.output_template {
+        template.validate(args.half_frame)?;
+        if template.uses_film() && film_stock.is_none() {
+            return Err(
+                "The output template uses {film}, but no film stock is set. Set --film-stock, or film_stock in --base-profile"
+                    .into(),
+            );
+        }
+    }
+
+    Ok(Layout {
+        dir: args.output_dir.clone(),
+        input_dir: input_dir.map(PathBuf::from),
+        dir_suffix: args.output_dir_suffix.clone(),
+        template: args.output_template.clone(),
+        suffix: args.output_suffix.clone(),
+        film_stock,
+        roll: args.roll_name.clone(),
+    })
+}
+
+fn conversion_options(args: &Settings) -> Result<conversion::Options, Box<dyn std::error::Error>> {
+    let fallback_base_color = match (args.base_color, &args.base_profile) {
+        (Some(color), _) => Some(color),
+        (None, Some(path)) => Profile::load(path)?.base_color.map(Rgb),
+        (None, None) => None,
+    };
+
+    let default_aspect_ratio = if args.half_frame { 0.7083 } else { 1.5 };
+    let (crop_mode, crop_inset) = match args.crop {
+        Crop::Mode(mode) => (mode, args.crop_inset),
+        Crop::Inset(inset) => {
+            println!(
+                "Warning: --crop {} is deprecated, use --crop-inset {} instead",
+                inset, inset
+            );
+            (CropMode::Frame, inset)
+        }
+    };
+
+    Ok(conversion::Options {
+        aspect_ratio: args.aspect_ratio.unwrap_or(default_aspect_ratio),
+        crop_percentage: crop_inset,
+        base_region: args.base_region,
+        base_color: None,
+        fallback_base_color,
+        min_base_confidence: args.min_base_confidence,
+        detect_sprockets: args.sprockets,
+        crop_mode,
+        float: args.float,
+        dust: args.dust_removal.then_some(DustOptions {
+            sensitivity: args.dust_sensitivity,
+            max_size: args.dust_max_size,
+        }),
+        stretch: StretchOptions {
+            mode: args.stretch,
+            black_clip: args.clip_black,
+            white_clip: args.clip_white,
+            clip_limit: args.clip_limit,
+            max_gain: args.max_gain,
+        },
+        roll_levels: None,
+        balance: BalanceOptions {
+            temperature: args.temperature,
+            tint: args.tint,
+            neutral_point: args.neutral_point,
+            auto: args.auto_balance,
+        },
+        denoise: denoise_options(args)?,
+        tone: tone_options(args)?,
+        color: ColorOptions {
+            saturation: args.saturation,
+            vibrance: args.vibrance,
+            hue_shifts: args.hue_shifts.clone(),
+        },
+        lut: args.lut.as_deref().map(Lut::load).transpose()?,
+        lut_interpolation: args.lut_interpolation,
+        sharpen: sharpen_options(args)?,
+        collect_histograms: args.export_stats.is_some(),
+    })
+}
+
+fn denoise_options(args: &Settings) -> Result<DenoiseOptions, String> {
+    let options = DenoiseOptions {
+        luma: args.denoise_luma,
+        chroma: args.denoise_chroma,
+        radius: args.denoise_radius,
+    };
+    options.validate()?;
+    Ok(options)
+}
+
+fn sharpen_options(args: &Settings) -> Result<SharpenOptions, String> {
+    let options = SharpenOptions {
+        amount: args.sharpen,
+        radius: args.sharpen_radius,
+        threshold: args.sharpen_threshold,
+        edge_mask: args.sharpen_edges,
+    };
+    options.validate()?;
+    Ok(options)
+}
+
+fn tone_options(args: &Settings) -> Result<ToneOptions, String> {
+    let mut curves = Curves::default();
+    for (channel, curve) in args.curves.iter().cloned() {
+        let slot = match channel.as_str() {
+            "rgb" => &mut curves.rgb,
+            "r" => &mut curves.r,
+            "g" => &mut curves.g,
+            _ => &mut curves.b,
+        };
+        *slot = Some(curve);
+    }
+
+    let options = ToneOptions {
+        exposure: args.exposure,
+        contrast: args.contrast,
+        highlights: args.highlights,
+        shadows: args.shadows,
+        black_point: args.black_point,
+        white_point: args.white_point,
+        curves,
+    };
+    options.validate()?;
+    Ok(options)
+}
+
+/// Where --resume keeps track of the batch: in --output-dir, or else in -d or
+/// the directory of the first of the `files` given with -f
+fn state_path(args: &Settings, input_dir: Option<&str>, files: &[String]) -> PathBuf {
+    let dir = match (&args.output_dir, input_dir, files.first()) {
+        (Some(dir), _, _) => dir.clone(),
+        (None, Some(dir), _) => PathBuf::from(dir),
+        (None, None, Some(file)) => Path::new(file)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+        (None, None, None) => PathBuf::new(),
+    };
+    dir.join(state::STATE_FILE_NAME)
+}
+
+/// A RAW file, split into frames
+struct RawFile {
+    capture: Capture,
+    /// Size of the decoded image as `[width, height]`, before it was scaled
+    size: [u32; 2],
+    /// Frames, each with the path that its outputs and sidecar are named after
+    frames: Vec<(String, InputImage)>,
+    /// Factor that the frames were scaled by, e.g. for previews
+    scale: f32,
+}
+
+impl RawFile {
+    /// The sidecar of the frame at `frame_path`, scaled like the frame
+    fn sidecar(&self, frame_path: &str) -> Result<Sidecar, Box<dyn std::error::Error>> {
+        let sidecar = Sidecar::load(frame_path)?;
+        Ok(if self.scale == 1.0 {
+            sidecar
+        } else {
+            sidecar.scaled(self.scale)
+        })
+    }
+
+    /// `options`, scaled like the frames
+    fn options<'a>(&self, options: &'a conversion::Options) -> Cow<'a, conversion::Options> {
+        if self.scale == 1.0 {
+            Cow::Borrowed(options)
+        } else {
+            Cow::Owned(options.scaled(self.scale))
+        }
+    }
+}
 
-    let mut image = raw_processor::load_raw_image(&path)?;
+/// Loads a RAW file as sRGB, after evening out the light with the flat field
+/// at `flat_field`
+fn load_image(
+    path: &str,
+    flat_field: Option<&str>,
+) -> Result<(InputImage, Capture), Box<dyn std::error::Error>> {
+    let (mut image, capture) = raw_processor::load_raw_image(&path)?;
+    if let Some(flat_field) = flat_field {
+        FlatField::load(flat_field)?
+            .apply_mut(&mut image)
+            .map_err(|e| format!("Unable to apply flat field {}: {}", flat_field, e))?;
+    }
     image.set_color_space(Cicp::SRGB_LINEAR)?;
     image.apply_color_space(Cicp::SRGB, ConvertColorOptions::default())?;
+    Ok((image, capture))
+}
 
-    if args.debug {
+/// Loads a RAW file and its capture settings, scales it down to
+/// `working_size` on its longest side if it's larger, and splits it into
+/// halves if needed
+fn load_frames(
+    path: &str,
+    args: &Settings,
+    working_size: Option<u32>,
+    debug: bool,
+) -> Result<RawFile, Box<dyn std::error::Error>> {
+    let (image, capture) = load_image(path, args.flat_field.as_deref())?;
+
+    if debug {
         println!(
             "Successfully loaded RAW image: {}x{} pixels",
             image.width(),
@@ -133,50 +1371,333 @@ fn process_file(path: &str, args: &Cli) -> Result<(), Box<dyn std::error::Error>
         )?;
     }
 
-    if !args.half_frame {
-        let aspect_ratio = args.aspect_ratio.unwrap_or(1.5);
-        let debug_file_path = if args.debug { Some(path) } else { None };
-        let converted = conversion::convert(
-            &image,
-            aspect_ratio,
-            args.crop,
-            debug_file_path,
-            &args.output_dir_suffix,
-        )?;
-        io::save_image(
-            &path,
-            &args.output_dir_suffix,
-            &args.output_suffix,
-            &args.output_format.to_string(),
-            converted,
-        )?;
+    let (width, height) = image.dimensions();
+    let (image, scale) = match working_size {
+        Some(size) if size < width.max(height) => {
+            let scale = size as f32 / width.max(height) as f32;
+            let scaled = |length: u32| ((length as f32 * scale).round() as u32).max(1);
+            (
+                imageops::thumbnail(&image, scaled(width), scaled(height)),
+                scale,
+            )
+        }
+        _ => (image, 1.0),
+    };
+
+    let images = if args.half_frame {
+        conversion::split_image(image).into()
     } else {
-        let halves = conversion::split_image(image);
+        vec![image]
+    };
+    Ok(RawFile {
+        capture,
+        size: [width, height],
+        frames: naming::frame_paths(path, args.half_frame)
+            .into_iter()
+            .zip(images)
+            .collect(),
+        scale,
+    })
+}
+
+/// First pass of --roll-levels, which measures the levels of every frame
+/// before any of them are saved
+fn measure_roll_levels(
+    files: &[String],
+    args: &Settings,
+    options: &conversion::Options,
+    working_size: Option<u32>,
+) -> Result<Option<RollLevels>, Box<dyn std::error::Error>> {
+    let mut roll = RollHistogram::new(options.stretch.mode, args.roll_weighting);
+
+    // convert without stretching, and measure what's left
+    let unstretched_options = conversion::Options {
+        stretch: StretchOptions {
+            mode: StretchMode::None,
+            ..options.stretch.clone()
+        },
+        // specks and noise barely affect the histogram, and it has 16-bit bins
+        dust: None,
+        float: false,
+        denoise: DenoiseOptions::default(),
+        sharpen: SharpenOptions::default(),
+        collect_histograms: false,
+        ..options.clone()
+    };
+
+    for file in files {
+        if let Err(e) = measure_file(file, args, &unstretched_options, working_size, &mut roll) {
+            println!("Unable to measure file {}: {}", file, e);
+        }
+    }
+
+    let Some(levels) = roll.levels(&options.stretch) else {
+        return Ok(None);
+    };
+
+    if let Some(path) = &args.save_roll_levels {
+        levels.save(path)?;
+    }
+
+    Ok(Some(RollLevels {
+        levels,
+        max_deviation: args.roll_max_deviation,
+    }))
+}
+
+fn measure_file(
+    path: &str,
+    args: &Settings,
+    options: &conversion::Options,
+    working_size: Option<u32>,
+    roll: &mut RollHistogram,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Measuring file {}...", path);
+
+    let file = load_frames(path, args, working_size, false)?;
+    let options = file.options(options);
+    for (frame_path, image) in &file.frames {
+        let sidecar = file.sidecar(frame_path)?;
+        if !sidecar.steps.stretch {
+            continue;
+        }
+        let converted =
+            conversion::convert(image, &options, &sidecar, None, &args.output_dir_suffix)?;
+        roll.add(&converted.frame_image());
+    }
+
+    Ok(())
+}
+
+/// Samples the conversion of the first frame of `file` into a LUT
+fn export_lut(
+    file: &str,
+    path: &str,
+    args: &Settings,
+    options: &conversion::Options,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some((frame_path, image)) = load_frames(file, args, None, false)?
+        .frames
+        .into_iter()
+        .next()
+    else {
+        return Ok(());
+    };
+    let sidecar = Sidecar::load(&frame_path)?;
+    let converted = conversion::convert(&image, options, &sidecar, None, &args.output_dir_suffix)?;
+
+    let lut = Lut::sample(args.export_lut_size as usize, |img| {
+        conversion::apply_conversion_mut(img, &converted, &sidecar.steps)
+    });
+    lut.save_cube(path, &format!("yancy {}", frame_path))
+}
+
+/// Converts files one after another with the same settings, and keeps track
+/// of them for --resume and --export-stats
+struct Batch<'a> {
+    args: &'a Settings,
+    options: conversion::Options,
+    renditions: Vec<Rendition>,
+    layout: Layout,
+    /// Longest side that inputs are scaled down to before converting them,
+    /// for previews
+    working_size: Option<u32>,
+    /// Everything that outputs depend on besides their input and its
+    /// sidecars, which files were converted with
+    description: String,
+    state_path: Option<PathBuf>,
+    state: BatchState,
+    frame_stats: Vec<FrameStats>,
+    /// Files that were skipped, since they were converted already
+    skipped: usize,
+}
+
+impl<'a> Batch<'a> {
+    fn new(
+        args: &'a Settings,
+        options: conversion::Options,
+        renditions: Vec<Rendition>,
+        layout: Layout,
+        working_size: Option<u32>,
+        state_path: Option<PathBuf>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let state = match &state_path {
+            Some(path) => BatchState::load(path)?,
+            None => BatchState::default(),
+        };
+        let mut batch = Self {
+            args,
+            options,
+            renditions,
+            layout,
+            working_size,
+            description: String::new(),
+            state_path,
+            state,
+            frame_stats: vec![],
+            skipped: 0,
+        };
+        batch.describe();
+        Ok(batch)
+    }
+
+    /// Updates the description after the base color of the roll is measured
+    fn describe(&mut self) {
+        // files that are named in the settings are compared by their contents
+        let files: Vec<String> = [
+            &self.args.base_profile,
+            &self.args.flat_field,
+            &self.args.load_roll_levels,
+            &self.args.lut,
+        ]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+
+        self.description = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "settings": self.args.given,
+            "files": state::settings_hash("", &files),
+            "base_color": self.options.base_color.map(|color| color.0),
+            "working_size": self.working_size,
+        })
+        .to_string();
+    }
+
+    /// Converts the file at `path`, unless --resume finds that it was
+    /// converted already. `index` is its position in the run.
+    fn convert_file(&mut self, path: &str, index: usize) {
+        // sidecars are part of the settings of each file
+        let sidecar_paths: Vec<String> = naming::frame_paths(path, self.args.half_frame)
+            .iter()
+            .map(|frame_path| Sidecar::path_for(frame_path))
+            .collect();
+        let settings_hash = state::settings_hash(&self.description, &sidecar_paths);
+        if self.args.resume && self.state.is_finished(path, &settings_hash) {
+            self.skipped += 1;
+            return;
+        }
 
-        for (image, half_suffix) in halves.into_iter().zip('a'..='b') {
-            let aspect_ratio = args.aspect_ratio.unwrap_or(0.7083);
-            let file_half = format!("{}.{}", path, half_suffix);
-            let debug_path = if args.debug {
-                Some(file_half.as_str())
+        let frames_per_file = if self.args.half_frame {
+            HALVES.len()
+        } else {
+            1
+        };
+        match self.process_file(path, index * frames_per_file + 1) {
+            Ok(()) => self.state.finish(path, &settings_hash),
+            Err(e) => {
+                println!("Unable to process file {}: {}", path, e);
+                self.state.fail(path, &e.to_string());
+            }
+        }
+        if let Some(state_path) = &self.state_path
+            && let Err(e) = self.state.save(state_path)
+        {
+            println!("Warning: unable to save {}: {}", state_path.display(), e);
+        }
+    }
+
+    /// Converts every frame of the file at `path`. `first_sequence` is the
+    /// index of its first frame within the run.
+    fn process_file(
+        &mut self,
+        path: &str,
+        first_sequence: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let args = self.args;
+        println!("Converting file {}...", path);
+
+        let file = load_frames(path, args, self.working_size, args.debug)?;
+        let options = file.options(&self.options);
+        for (i, (frame_path, image)) in file.frames.iter().enumerate() {
+            let frame = FrameInfo {
+                path,
+                index: i + 1,
+                half: args.half_frame.then(|| HALVES[i]),
+                capture: &file.capture,
+                sequence: first_sequence + i,
+            };
+            let debug_file_path = if args.debug {
+                Some(frame_path.as_str())
             } else {
                 None
             };
+            let sidecar_path = Sidecar::path_for(frame_path);
+            let sources = [path, sidecar_path.as_str()];
+            let mut output_paths = vec![];
+            for rendition in &self.renditions {
+                let output_path = rendition.path(&self.layout, &frame)?;
+                if args.overwrite.allows(&output_path, &sources) {
+                    output_paths.push((rendition, output_path));
+                } else {
+                    println!("Skipped {}, which exists", output_path);
+                }
+            }
+            if output_paths.is_empty() {
+                continue;
+            }
+
+            let sidecar = file.sidecar(frame_path)?;
             let converted = conversion::convert(
-                &image,
-                aspect_ratio,
-                args.crop,
-                debug_path,
+                image,
+                &options,
+                &sidecar,
+                debug_file_path,
                 &args.output_dir_suffix,
             )?;
-            io::save_image(
-                &file_half,
-                &args.output_dir_suffix,
-                &args.output_suffix,
-                &args.output_format.to_string(),
-                converted,
-            )?;
+            if args.write_sidecars {
+                sidecar.with_detected(&converted).save(frame_path)?;
+            }
+            self.frame_stats
+                .push(FrameStats::new(frame_path, &converted));
+            // convert once, and encode every rendition from the same image
+            for (rendition, output_path) in output_paths {
+                if rendition.is_float() {
+                    let image = conversion::render_float(
+                        &converted,
+                        rendition.size,
+                        debug_file_path,
+                        &args.output_dir_suffix,
+                    )?;
+                    rendition.save_float(&output_path, &image)?;
+                } else {
+                    let image = conversion::render(
+                        &converted,
+                        rendition.size,
+                        debug_file_path,
+                        &args.output_dir_suffix,
+                    )?;
+                    rendition.save(&output_path, image, &file.capture)?;
+                }
+            }
         }
+
+        Ok(())
     }
 
-    Ok(())
+    /// Reports what was skipped and failed, and exports the stats
+    fn finish(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.skipped > 0 {
+            println!(
+                "Skipped {} file(s) that were converted already",
+                self.skipped
+            );
+        }
+        if let Some(path) = &self.state_path
+            && !self.state.failed.is_empty()
+        {
+            println!(
+                "Warning: {} file(s) failed, and will be retried on the next run. See {}",
+                self.state.failed.len(),
+                path.display()
+            );
+        }
+
+        if let Some(path) = &self.args.export_stats {
+            stats::export(path, &self.frame_stats)?;
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,498 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::raw_processor::Capture;
+
+/// Characters that aren't allowed in file names on some systems, replaced in
+/// the values filled into templates
+const INVALID_CHARS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Suffixes of the halves of a file with --half-frame
+pub const HALVES: [char; 2] = ['a', 'b'];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    /// File name of the input, without its extension
+    Stem,
+    /// Index of the frame within its input, from 1
+    Frame,
+    /// `a` or `b` for half frames, otherwise empty
+    Half,
+    /// Capture date as YYYY-MM-DD
+    Date,
+    Camera,
+    Film,
+    Roll,
+    /// Index of the frame within the run, from 1
+    Seq,
+    Suffix,
+}
+
+impl Field {
+    const NAMES: [(&'static str, Field); 9] = [
+        ("stem", Field::Stem),
+        ("frame", Field::Frame),
+        ("half", Field::Half),
+        ("date", Field::Date),
+        ("camera", Field::Camera),
+        ("film", Field::Film),
+        ("roll", Field::Roll),
+        ("seq", Field::Seq),
+        ("suffix", Field::Suffix),
+    ];
+
+    fn is_number(&self) -> bool {
+        matches!(self, Field::Frame | Field::Seq)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    /// A placeholder, with the width that numbers are padded to with zeros
+    Field(Field, usize),
+}
+
+/// Names of outputs, relative to the output directory and without an
+/// extension, e.g. `{roll}/{date}_{seq:3}`. Placeholders are filled in for
+/// each frame, and `{{` and `}}` are literal braces.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl OutputTemplate {
+    fn uses(&self, field: Field) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Field(f, _) if *f == field))
+    }
+
+    pub fn uses_film(&self) -> bool {
+        self.uses(Field::Film)
+    }
+
+    /// Checks that every frame gets its own name
+    pub fn validate(&self, half_frame: bool) -> Result<(), String> {
+        if !self.uses(Field::Stem) && !self.uses(Field::Seq) {
+            return Err(format!(
+                "output template \"{}\" should contain {{stem}} or {{seq}}, so that frames don't overwrite each other",
+                self.source
+            ));
+        }
+        if half_frame
+            && !self.uses(Field::Seq)
+            && !self.uses(Field::Half)
+            && !self.uses(Field::Frame)
+        {
+            return Err(format!(
+                "output template \"{}\" should contain {{half}}, {{frame}} or {{seq}} for half frames",
+                self.source
+            ));
+        }
+        Ok(())
+    }
+
+    fn render(&self, frame: &FrameInfo, layout: &Layout, suffix: &str) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => name.push_str(text),
+                Part::Field(field, width) => {
+                    let value = match field {
+                        Field::Stem => frame.stem(),
+                        Field::Frame => format!("{:0width$}", frame.index, width = width),
+                        Field::Half => frame.half.map(String::from).unwrap_or_default(),
+                        Field::Date => frame.date().unwrap_or_else(|| String::from("undated")),
+                        Field::Camera => match frame.capture.model.as_str() {
+                            "" => String::from("unknown"),
+                            model => model.to_owned(),
+                        },
+                        Field::Film => layout.film_stock.clone().unwrap_or_default(),
+                        Field::Roll => layout.roll(frame.path),
+                        Field::Seq => format!("{:0width$}", frame.sequence, width = width),
+                        Field::Suffix => suffix.to_owned(),
+                    };
+                    name.push_str(value.trim().replace(INVALID_CHARS, "-").as_str());
+                }
+            }
+        }
+        name
+    }
+}
+
+impl FromStr for OutputTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| format!("invalid output template \"{}\": {}", s, reason);
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) if c != '{' => placeholder.push(c),
+                            // the end, or another {, before the placeholder is closed
+                            _ => {
+                                return Err(invalid(String::from(
+                                    "unmatched {, write {{ for a brace",
+                                )));
+                            }
+                        }
+                    }
+                    let (name, width) = match placeholder.split_once(':') {
+                        Some((name, width)) => (name, Some(width)),
+                        None => (placeholder.as_str(), None),
+                    };
+                    let field = Field::NAMES
+                        .iter()
+                        .find(|(n, _)| *n == name)
+                        .map(|&(_, field)| field)
+                        .ok_or_else(|| {
+                            let names: Vec<_> = Field::NAMES.iter().map(|(n, _)| *n).collect();
+                            invalid(format!(
+                                "unknown placeholder {{{}}}, expected one of {}",
+                                name,
+                                names.join(", ")
+                            ))
+                        })?;
+                    let width = match width {
+                        Some(width) if field.is_number() => width.parse().map_err(|_| {
+                            invalid(format!("width of {{{}}} should be a number", name))
+                        })?,
+                        Some(_) => {
+                            return Err(invalid(format!("{{{}}} can't have a width", name)));
+                        }
+                        None => 0,
+                    };
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Field(field, width));
+                }
+                '}' => return Err(invalid(String::from("unmatched }, write }} for a brace"))),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        let path = Path::new(s);
+        if path.is_absolute() || path.components().any(|c| c.as_os_str() == "..") {
+            return Err(invalid(String::from(
+                "it should be relative to the output directory, without ..",
+            )));
+        }
+
+        Ok(Self {
+            source: s.to_owned(),
+            parts,
+        })
+    }
+}
+
+impl fmt::Display for OutputTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// What is known about a frame when its outputs are named
+pub struct FrameInfo<'a> {
+    /// Path of the RAW file that the frame is from
+    pub path: &'a str,
+    /// Index of the frame within the file, from 1
+    pub index: usize,
+    /// Which half of the file the frame is, for half frames
+    pub half: Option<char>,
+    pub capture: &'a Capture,
+    /// Index of the frame within the run, from 1
+    pub sequence: usize,
+}
+
+impl FrameInfo<'_> {
+    fn stem(&self) -> String {
+        Path::new(self.path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// The capture date, from Exif's `YYYY:MM:DD HH:MM:SS`
+    fn date(&self) -> Option<String> {
+        let date_time = self.capture.date_time.as_ref()?;
+        let date = date_time.split(' ').next()?;
+        Some(date.replace(':', "-"))
+    }
+
+    /// Path that the frame's outputs are named after when there's no template,
+    /// and that its sidecar is read from
+    pub fn frame_path(&self) -> String {
+        frame_path(self.path, self.half)
+    }
+}
+
+/// Paths that the frames of the file at `path` are named after, see
+/// [FrameInfo::frame_path]
+pub fn frame_paths(path: &str, half_frame: bool) -> Vec<String> {
+    if half_frame {
+        HALVES
+            .iter()
+            .map(|&half| frame_path(path, Some(half)))
+            .collect()
+    } else {
+        vec![frame_path(path, None)]
+    }
+}
+
+fn frame_path(path: &str, half: Option<char>) -> String {
+    match half {
+        Some(half) => format!("{}.{}", path, half),
+        None => path.to_owned(),
+    }
+}
+
+/// Where outputs are saved, and what they're named
+#[derive(Clone, Debug, Default)]
+pub struct Layout {
+    /// Directory that every output is saved in, instead of next to its input
+    pub dir: Option<PathBuf>,
+    /// Directory that inputs were found in. The directories of inputs below it
+    /// are recreated under `dir`.
+    pub input_dir: Option<PathBuf>,
+    /// Suffix of a sibling of the input's directory that outputs are saved
+    /// in, if `dir` isn't set
+    pub dir_suffix: Option<String>,
+    pub template: Option<OutputTemplate>,
+    /// Suffix of outputs that don't set their own
+    pub suffix: String,
+    pub film_stock: Option<String>,
+    /// Name of the roll, instead of the name of the input's directory
+    pub roll: Option<String>,
+}
+
+impl Layout {
+    fn roll(&self, path: &str) -> String {
+        if let Some(roll) = &self.roll {
+            return roll.clone();
+        }
+        let parent = match Path::new(path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        parent
+            .canonicalize()
+            .ok()
+            .and_then(|dir| {
+                dir.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            })
+            .unwrap_or_else(|| String::from("roll"))
+    }
+
+    /// Directory that the outputs of `path` are saved in, before the
+    /// output's own `dir`
+    fn base_dir(&self, path: &str, has_own_dir: bool) -> PathBuf {
+        let parent = Path::new(path).parent().unwrap_or(Path::new(""));
+        match (&self.dir, &self.dir_suffix) {
+            (Some(dir), _) => match &self.input_dir {
+                Some(input_dir) => {
+                    dir.join(parent.strip_prefix(input_dir).unwrap_or(Path::new("")))
+                }
+                None => dir.clone(),
+            },
+            // an output's own directory replaces the directory suffix
+            (None, Some(_)) if has_own_dir => parent.to_path_buf(),
+            (None, Some(dir_suffix)) if parent.as_os_str().is_empty() => PathBuf::from(dir_suffix),
+            (None, Some(dir_suffix)) => {
+                let mut dir = parent.to_path_buf();
+                dir.as_mut_os_string().push(format!("_{}", dir_suffix));
+                dir
+            }
+            (None, None) => parent.to_path_buf(),
+        }
+    }
+
+    /// Path of an output of `frame`, creating the directories it's in
+    pub fn output_path(
+        &self,
+        frame: &FrameInfo,
+        dir: Option<&str>,
+        suffix: Option<&str>,
+        extension: &str,
+    ) -> std::io::Result<String> {
+        let mut output_dir = self.base_dir(frame.path, dir.is_some());
+        if let Some(dir) = dir {
+            output_dir.push(dir);
+        }
+
+        let name = match &self.template {
+            Some(template) => {
+                let name = template.render(frame, self, suffix.unwrap_or(&self.suffix));
+                match suffix {
+                    // keep outputs with their own suffix apart, even if the
+                    // template doesn't use it
+                    Some(suffix) if !template.uses(Field::Suffix) => {
+                        format!("{}.{}.{}", name, suffix, extension)
+                    }
+                    _ => format!("{}.{}", name, extension),
+                }
+            }
+            None => {
+                let frame_path = frame.frame_path();
+                let file_name = Path::new(&frame_path)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy();
+                let suffix = suffix.unwrap_or(&self.suffix);
+                format!("{}.{}.{}", file_name, suffix, extension)
+            }
+        };
+
+        let output_path = output_dir.join(name);
+        if let Some(parent) = output_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(output_path.to_string_lossy().into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, frame: &FrameInfo) -> String {
+        let layout = Layout {
+            film_stock: Some(String::from("Portra 400")),
+            roll: Some(String::from("roll-12")),
+            suffix: String::from("positive"),
+            ..Layout::default()
+        };
+        template
+            .parse::<OutputTemplate>()
+            .unwrap()
+            .render(frame, &layout, &layout.suffix)
+    }
+
+    fn capture() -> Capture {
+        Capture {
+            model: String::from("Z 6"),
+            date_time: Some(String::from("2024:05:17 12:30:00")),
+            ..Capture::default()
+        }
+    }
+
+    #[test]
+    fn fills_placeholders() {
+        let capture = capture();
+        let frame = FrameInfo {
+            path: "scans/DSC_0042.NEF",
+            index: 2,
+            half: Some('b'),
+            capture: &capture,
+            sequence: 7,
+        };
+        assert_eq!(
+            render("{roll}/{date}_{seq:3}{half}", &frame),
+            "roll-12/2024-05-17_007b"
+        );
+        assert_eq!(
+            render("{stem}-{frame:2}-{camera}-{film}.{suffix}", &frame),
+            "DSC_0042-02-Z 6-Portra 400.positive"
+        );
+        assert_eq!(render("{seq}", &frame), "7");
+    }
+
+    #[test]
+    fn fills_missing_values() {
+        let capture = Capture::default();
+        let frame = FrameInfo {
+            path: "DSC_0042.NEF",
+            index: 1,
+            half: None,
+            capture: &capture,
+            sequence: 1,
+        };
+        assert_eq!(
+            render("{date}_{camera}{half}_{stem}", &frame),
+            "undated_unknown_DSC_0042"
+        );
+    }
+
+    #[test]
+    fn replaces_invalid_characters_in_values() {
+        let capture = Capture {
+            model: String::from("A/B: C"),
+            ..Capture::default()
+        };
+        let frame = FrameInfo {
+            path: "DSC_0042.NEF",
+            index: 1,
+            half: None,
+            capture: &capture,
+            sequence: 1,
+        };
+        assert_eq!(render("{camera}/{stem}", &frame), "A-B- C/DSC_0042");
+    }
+
+    #[test]
+    fn escapes_braces() {
+        let capture = capture();
+        let frame = FrameInfo {
+            path: "DSC_0042.NEF",
+            index: 1,
+            half: None,
+            capture: &capture,
+            sequence: 1,
+        };
+        assert_eq!(render("{{{stem}}}", &frame), "{DSC_0042}");
+        assert_eq!(render("{{stem}}", &frame), "{stem}");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        let error = |template: &str| template.parse::<OutputTemplate>().unwrap_err();
+        assert!(error("{name}").contains("unknown placeholder {name}"));
+        assert!(error("{seq:x}").contains("width of {seq} should be a number"));
+        assert!(error("{stem:3}").contains("{stem} can't have a width"));
+        assert!(error("{stem}}").contains("unmatched }"));
+        assert!(error("{stem").contains("unmatched {"));
+        assert!(error("{stem_{frame}").contains("unmatched {"));
+        assert!(error("/out/{stem}").contains("relative to the output directory"));
+        assert!(error("../{stem}").contains("relative to the output directory"));
+    }
+
+    #[test]
+    fn validates_that_frames_get_their_own_names() {
+        let template = |template: &str| template.parse::<OutputTemplate>().unwrap();
+        assert!(template("{date}").validate(false).is_err());
+        assert!(template("{stem}").validate(false).is_ok());
+        assert!(template("{stem}").validate(true).is_err());
+        assert!(template("{stem}{half}").validate(true).is_ok());
+        assert!(template("{stem}_{frame}").validate(true).is_ok());
+        assert!(template("{seq}").validate(true).is_ok());
+    }
+
+    #[test]
+    fn names_halves_of_frames() {
+        assert_eq!(frame_paths("a.NEF", false), ["a.NEF"]);
+        assert_eq!(frame_paths("a.NEF", true), ["a.NEF.a", "a.NEF.b"]);
+    }
+}
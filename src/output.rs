@@ -0,0 +1,584 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, Write};
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use image::codecs::avif::AvifEncoder;
+use image::codecs::openexr::OpenExrEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{ImageBuffer, ImageEncoder, Rgb, RgbImage};
+use jpeg_encoder::{ColorType, SamplingFactor};
+use rayon::prelude::*;
+use tiff::encoder::{Compression, DeflateLevel, Predictor, TiffEncoder, colortype};
+
+use crate::conversion::{FloatImage, InputImage, convert_samples};
+use crate::dng;
+use crate::naming::{FrameInfo, Layout};
+use crate::raw_processor::Capture;
+
+/// Defaults of the encoders, used when a rendition doesn't set its own
+const JPEG_QUALITY: u8 = 75;
+const AVIF_QUALITY: u8 = 80;
+const AVIF_SPEED: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Tiff,
+    Avif,
+    /// Linear DNG, for finishing the image in a raw editor
+    Dng,
+    /// OpenEXR with 32-bit floats, which keeps values outside of the range
+    Exr,
+}
+
+impl OutputFormat {
+    /// Bit depth that is saved if a rendition doesn't set one
+    fn max_depth(&self) -> BitDepth {
+        match self {
+            Self::Png | Self::Tiff | Self::Dng => BitDepth::Sixteen,
+            Self::Jpeg | Self::Webp | Self::Avif => BitDepth::Eight,
+            Self::Exr => BitDepth::Float,
+        }
+    }
+
+    fn supports(&self, depth: BitDepth) -> bool {
+        match self {
+            Self::Png => depth != BitDepth::Float,
+            Self::Tiff => true,
+            Self::Jpeg | Self::Webp | Self::Avif => depth == BitDepth::Eight,
+            Self::Dng => depth == BitDepth::Sixteen,
+            Self::Exr => depth == BitDepth::Float,
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Png => write!(f, "png"),
+            Self::Jpeg => write!(f, "jpeg"),
+            Self::Webp => write!(f, "webp"),
+            Self::Tiff => write!(f, "tiff"),
+            Self::Avif => write!(f, "avif"),
+            Self::Dng => write!(f, "dng"),
+            Self::Exr => write!(f, "exr"),
+        }
+    }
+}
+
+/// Whether outputs that already exist are saved again
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Overwrite {
+    Always,
+    Never,
+    /// Only if the input or its sidecar was modified after the output
+    IfNewer,
+}
+
+impl Overwrite {
+    /// Whether the output at `output_path` should be saved, given the files
+    /// that it's made from
+    pub fn allows(&self, output_path: &str, sources: &[&str]) -> bool {
+        let Ok(output) = fs::metadata(output_path) else {
+            return true;
+        };
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::IfNewer => {
+                let newest_source = sources
+                    .iter()
+                    .filter_map(|source| fs::metadata(source).and_then(|m| m.modified()).ok())
+                    .max();
+                match (newest_source, output.modified()) {
+                    (Some(source), Ok(output)) => source > output,
+                    _ => true,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+    /// 32-bit floats
+    Float,
+}
+
+/// Resolution of the color channels of a JPEG, relative to luma
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Subsampling {
+    /// Full resolution
+    #[default]
+    Full,
+    /// Half the horizontal resolution
+    Half,
+    /// Half the horizontal and vertical resolution
+    Quarter,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TiffCompression {
+    #[default]
+    None,
+    Lzw,
+    Deflate,
+}
+
+/// One of the files saved for each frame, given as a format followed by
+/// `key=value` settings, e.g. `jpeg,size=2048,quality=85,suffix=web`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rendition {
+    pub format: OutputFormat,
+    /// Longest side in pixels. Larger images are scaled down, smaller ones are
+    /// kept as they are.
+    pub size: Option<u32>,
+    /// Bit depth of each channel. If unset, 16 bits are saved when the format
+    /// supports them, or floats for EXR.
+    pub depth: Option<BitDepth>,
+    /// Quality from 1 to 100, for JPEG and AVIF
+    pub quality: Option<u8>,
+    /// Chroma subsampling, for JPEG
+    pub subsampling: Subsampling,
+    /// Encoding speed from 1 (smallest files) to 10 (fastest), for AVIF
+    pub speed: Option<u8>,
+    pub compression: TiffCompression,
+    /// File suffix, instead of the one given by `--output-suffix`
+    pub suffix: Option<String>,
+    /// Directory to save to, relative to the input's directory or --output-dir
+    pub dir: Option<String>,
+}
+
+impl Rendition {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+            size: None,
+            depth: None,
+            quality: None,
+            subsampling: Subsampling::default(),
+            speed: None,
+            compression: TiffCompression::default(),
+            suffix: None,
+            dir: None,
+        }
+    }
+
+    /// Where the rendition of `frame` is saved
+    pub fn path(&self, layout: &Layout, frame: &FrameInfo) -> std::io::Result<String> {
+        layout.output_path(
+            frame,
+            self.dir.as_deref(),
+            self.suffix.as_deref(),
+            &self.format.to_string(),
+        )
+    }
+
+    pub fn save(
+        &self,
+        output_path: &str,
+        image: InputImage,
+        capture: &Capture,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.format == OutputFormat::Dng {
+            return dng::save(output_path, &image, capture);
+        }
+        if self.is_float() {
+            return self.save_float(output_path, &convert_samples(&image));
+        }
+
+        let depth = self.depth.unwrap_or(self.format.max_depth());
+        let (width, height) = image.dimensions();
+        if self.format == OutputFormat::Jpeg
+            && (width > u16::MAX as u32 || height > u16::MAX as u32)
+        {
+            return Err(format!(
+                "JPEG images can be at most {} pixels on each side, this one is {}x{}. Set a size for the output",
+                u16::MAX,
+                width,
+                height
+            )
+            .into());
+        }
+
+        write_atomically(output_path, |writer| {
+            match (self.format, depth) {
+                (OutputFormat::Png, BitDepth::Sixteen) => PngEncoder::new(writer).write_image(
+                    bytes(&image),
+                    width,
+                    height,
+                    image::ExtendedColorType::Rgb16,
+                )?,
+                (OutputFormat::Png, BitDepth::Eight) => {
+                    dither(&image).write_with_encoder(PngEncoder::new(writer))?
+                }
+                (OutputFormat::Jpeg, _) => {
+                    let mut encoder =
+                        jpeg_encoder::Encoder::new(writer, self.quality.unwrap_or(JPEG_QUALITY));
+                    encoder.set_sampling_factor(match self.subsampling {
+                        Subsampling::Full => SamplingFactor::R_4_4_4,
+                        Subsampling::Half => SamplingFactor::R_4_2_2,
+                        Subsampling::Quarter => SamplingFactor::R_4_2_0,
+                    });
+                    encoder.encode(
+                        dither(&image).as_raw(),
+                        width as u16,
+                        height as u16,
+                        ColorType::Rgb,
+                    )?;
+                }
+                (OutputFormat::Webp, _) => {
+                    dither(&image).write_with_encoder(WebPEncoder::new_lossless(writer))?
+                }
+                (OutputFormat::Avif, _) => {
+                    dither(&image).write_with_encoder(AvifEncoder::new_with_speed_quality(
+                        writer,
+                        self.speed.unwrap_or(AVIF_SPEED),
+                        self.quality.unwrap_or(AVIF_QUALITY),
+                    ))?
+                }
+                (OutputFormat::Tiff, BitDepth::Sixteen) => {
+                    self.tiff_encoder(writer, true)?
+                        .write_image::<colortype::RGB16>(width, height, image.as_raw())?
+                }
+                (OutputFormat::Tiff, BitDepth::Eight) => {
+                    self.tiff_encoder(writer, true)?
+                        .write_image::<colortype::RGB8>(width, height, dither(&image).as_raw())?
+                }
+                (OutputFormat::Dng | OutputFormat::Exr, _) | (_, BitDepth::Float) => {
+                    unreachable!("DNG and float outputs are saved above")
+                }
+            }
+            Ok(())
+        })?;
+
+        let bits = match depth {
+            BitDepth::Eight => 8,
+            BitDepth::Sixteen => 16,
+            BitDepth::Float => 32,
+        };
+        println!("Saved ({}-bit) {}", bits, output_path);
+        Ok(())
+    }
+
+    /// Whether the rendition is saved with floats, by [Rendition::save_float]
+    pub fn is_float(&self) -> bool {
+        self.depth.unwrap_or(self.format.max_depth()) == BitDepth::Float
+    }
+
+    /// Saves an image that may have values outside of 0 to 1, for EXR and
+    /// 32-bit TIFF
+    pub fn save_float(
+        &self,
+        output_path: &str,
+        image: &FloatImage,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(self.format, OutputFormat::Exr | OutputFormat::Tiff) {
+            return Err(format!("{} doesn't support floats", self.format).into());
+        }
+
+        let (width, height) = image.dimensions();
+        write_atomically(output_path, |writer| {
+            match self.format {
+                OutputFormat::Exr => OpenExrEncoder::new(writer).write_image(
+                    image::EncodableLayout::as_bytes(image.as_raw().as_slice()),
+                    width,
+                    height,
+                    image::ExtendedColorType::Rgb32F,
+                )?,
+                OutputFormat::Tiff => self
+                    .tiff_encoder(writer, false)?
+                    .write_image::<colortype::RGB32Float>(width, height, image.as_raw())?,
+                _ => unreachable!("other formats are rejected above"),
+            }
+            Ok(())
+        })?;
+
+        println!("Saved (32-bit float) {}", output_path);
+        Ok(())
+    }
+
+    /// A TIFF encoder with the rendition's compression. The horizontal
+    /// predictor helps compress integers, but isn't supported for floats.
+    fn tiff_encoder<W: Write + Seek>(
+        &self,
+        writer: W,
+        predict: bool,
+    ) -> Result<TiffEncoder<W>, Box<dyn std::error::Error>> {
+        let compression = match self.compression {
+            TiffCompression::None => Compression::Uncompressed,
+            TiffCompression::Lzw => Compression::Lzw,
+            TiffCompression::Deflate => Compression::Deflate(DeflateLevel::Balanced),
+        };
+        let predictor = match self.compression {
+            TiffCompression::Lzw | TiffCompression::Deflate if predict => Predictor::Horizontal,
+            _ => Predictor::None,
+        };
+        Ok(TiffEncoder::new(writer)?
+            .with_compression(compression)
+            .with_predictor(predictor))
+    }
+}
+
+/// Writes the file at `path` with `write`, through a temporary file next to it
+/// that replaces it once it's complete, so that a failed encode doesn't leave
+/// a broken file behind
+pub(crate) fn write_atomically(
+    path: &str,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let temp_path = format!("{}.tmp", path);
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    let written = write(&mut writer).and_then(|()| Ok(writer.flush()?));
+    drop(writer);
+
+    match written {
+        Ok(()) => Ok(fs::rename(&temp_path, path)?),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+fn bytes(image: &InputImage) -> &[u8] {
+    image::EncodableLayout::as_bytes(image.as_raw().as_slice())
+}
+
+/// Reduces 16-bit values to 8 bits, with a little noise so that smooth
+/// gradients don't turn into bands
+pub(crate) fn dither(image: &InputImage) -> RgbImage {
+    let values = image
+        .as_raw()
+        .par_iter()
+        .enumerate()
+        .map(|(i, &value)| {
+            // triangular noise from -1 to 1, from two hashes of the position
+            let seed = (i as u32).wrapping_mul(2);
+            let noise = hash(seed) + hash(seed.wrapping_add(1)) - 1.0;
+            (value as f32 / 257.0 + noise)
+                .round()
+                .clamp(0.0, u8::MAX as f32) as u8
+        })
+        .collect();
+    ImageBuffer::<Rgb<u8>, Vec<u8>>::from_raw(image.width(), image.height(), values)
+        .expect("dithered image should have as many values as the original")
+}
+
+/// A number from 0 to 1 that looks random, but is the same for every run
+fn hash(mut x: u32) -> f32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x as f32 / u32::MAX as f32
+}
+
+impl FromStr for Rendition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| format!("invalid output \"{}\": {}", s, reason);
+        let mut parts = s.split(',').map(|part| part.trim());
+
+        let format = parts.next().unwrap_or_default();
+        let format = <OutputFormat as ValueEnum>::from_str(format, true).map_err(|_| {
+            invalid(format!(
+                "unknown format \"{}\", expected png, jpeg, webp, tiff, avif, dng or exr",
+                format
+            ))
+        })?;
+        let mut rendition = Self::new(format);
+
+        for part in parts {
+            let Some((key, value)) = part.split_once('=') else {
+                return Err(invalid(format!("expected key=value, found \"{}\"", part)));
+            };
+            let number = |min: u32, max: u32| {
+                value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|n| (min..=max).contains(n))
+                    .ok_or_else(|| invalid(format!("{} should be from {} to {}", key, min, max)))
+            };
+            let only_for = |formats: &[OutputFormat]| {
+                if formats.contains(&format) {
+                    Ok(())
+                } else {
+                    Err(invalid(format!("{} isn't supported for {}", key, format)))
+                }
+            };
+
+            match key {
+                "size" => rendition.size = Some(number(1, u32::MAX)?),
+                "depth" => {
+                    let depth = match value {
+                        "8" => BitDepth::Eight,
+                        "16" => BitDepth::Sixteen,
+                        "32" => BitDepth::Float,
+                        _ => return Err(invalid(String::from("depth should be 8, 16 or 32"))),
+                    };
+                    if !format.supports(depth) {
+                        return Err(invalid(format!(
+                            "{} doesn't support a depth of {}",
+                            format, value
+                        )));
+                    }
+                    rendition.depth = Some(depth);
+                }
+                "quality" => {
+                    only_for(&[OutputFormat::Jpeg, OutputFormat::Avif])?;
+                    rendition.quality = Some(number(1, 100)? as u8);
+                }
+                "subsampling" => {
+                    only_for(&[OutputFormat::Jpeg])?;
+                    rendition.subsampling = match value {
+                        "444" | "4:4:4" => Subsampling::Full,
+                        "422" | "4:2:2" => Subsampling::Half,
+                        "420" | "4:2:0" => Subsampling::Quarter,
+                        _ => {
+                            return Err(invalid(String::from(
+                                "subsampling should be 444, 422 or 420",
+                            )));
+                        }
+                    }
+                }
+                "speed" => {
+                    only_for(&[OutputFormat::Avif])?;
+                    rendition.speed = Some(number(1, 10)? as u8);
+                }
+                "lossless" => {
+                    only_for(&[OutputFormat::Webp])?;
+                    if value != "true" {
+                        return Err(invalid(String::from(
+                            "only lossless WebP can be encoded, so lossless should be true",
+                        )));
+                    }
+                }
+                "compression" => {
+                    only_for(&[OutputFormat::Tiff])?;
+                    rendition.compression = match value {
+                        "none" => TiffCompression::None,
+                        "lzw" => TiffCompression::Lzw,
+                        "deflate" => TiffCompression::Deflate,
+                        _ => {
+                            return Err(invalid(String::from(
+                                "compression should be none, lzw or deflate",
+                            )));
+                        }
+                    }
+                }
+                "suffix" if !value.is_empty() => rendition.suffix = Some(value.to_owned()),
+                "dir" if !value.is_empty() => rendition.dir = Some(value.to_owned()),
+                "suffix" | "dir" => return Err(invalid(format!("{} should not be empty", key))),
+                _ => {
+                    return Err(invalid(format!(
+                        "unknown key \"{}\", expected size, depth, quality, subsampling, speed, lossless, compression, suffix or dir",
+                        key
+                    )));
+                }
+            }
+        }
+
+        Ok(rendition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn parses_renditions() {
+        let rendition: Rendition = "jpeg, size=2048, quality=85, subsampling=4:4:4, suffix=web"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            rendition,
+            Rendition {
+                size: Some(2048),
+                quality: Some(85),
+                subsampling: Subsampling::Full,
+                suffix: Some(String::from("web")),
+                ..Rendition::new(OutputFormat::Jpeg)
+            }
+        );
+
+        let rendition: Rendition = "TIFF,depth=32,compression=deflate,dir=masters"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            rendition,
+            Rendition {
+                depth: Some(BitDepth::Float),
+                compression: TiffCompression::Deflate,
+                dir: Some(String::from("masters")),
+                ..Rendition::new(OutputFormat::Tiff)
+            }
+        );
+
+        assert_eq!(
+            "png".parse::<Rendition>().unwrap(),
+            Rendition::new(OutputFormat::Png)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_renditions() {
+        let error = |rendition: &str| rendition.parse::<Rendition>().unwrap_err();
+        assert!(error("gif").contains("unknown format \"gif\""));
+        assert!(error("jpeg,size").contains("expected key=value"));
+        assert!(error("jpeg,size=0").contains("size should be from 1"));
+        assert!(error("jpeg,quality=101").contains("quality should be from 1 to 100"));
+        assert!(error("jpeg,depth=16").contains("jpeg doesn't support a depth of 16"));
+        assert!(error("png,depth=32").contains("png doesn't support a depth of 32"));
+        assert!(error("png,quality=90").contains("quality isn't supported for png"));
+        assert!(error("avif,subsampling=420").contains("subsampling isn't supported for avif"));
+        assert!(error("webp,lossless=false").contains("lossless should be true"));
+        assert!(error("tiff,compression=zip").contains("compression should be none"));
+        assert!(error("jpeg,suffix=").contains("suffix should not be empty"));
+        assert!(error("jpeg,colour=srgb").contains("unknown key \"colour\""));
+    }
+
+    #[test]
+    fn dithers_to_nearby_values() {
+        let image = InputImage::from_fn(64, 64, |x, y| {
+            let value = ((x + y * 64) * 16) as u16;
+            Rgb([value, value, u16::MAX])
+        });
+        let dithered = dither(&image);
+        for (original, dithered) in image.pixels().zip(dithered.pixels()) {
+            for (&original, &dithered) in original.0.iter().zip(dithered.0.iter()) {
+                assert!((original as f32 / 257.0 - dithered as f32).abs() <= 1.5);
+            }
+        }
+        assert_eq!(dither(&image), dithered);
+    }
+
+    #[test]
+    fn write_atomically_leaves_nothing_behind_on_failure() {
+        let dir = TempDir::new("output-atomic");
+        let path = dir.file("out.bin");
+
+        write_atomically(&path, |writer| Ok(writer.write_all(b"complete")?)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"complete");
+
+        let failed = write_atomically(&path, |writer| {
+            writer.write_all(b"partial")?;
+            Err("encoding failed".into())
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"complete");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+    }
+}
@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+/// Values measured for a film stock or scanning setup, read from a TOML file.
+/// Used when they can't be detected reliably from a frame.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Color of the film backing as 16-bit `[r, g, b]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_color: Option<[u16; 3]>,
+    /// Name of the film stock, for naming outputs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub film_stock: Option<String>,
+}
+
+impl Profile {
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read profile {}: {}", path, e))?;
+        toml::from_str(&contents).map_err(|e| format!("Invalid profile {}: {}", path, e).into())
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, toml::to_string_pretty(self)?)?;
+        println!("Saved {}", path);
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
+#include <ctime>
 #include <memory>
 #include <stdexcept>
 
 #include "yancy/src/raw_processor.h"
+#include "yancy/src/raw_processor.rs.h"
 
 RawProcessor::RawProcessor() : image(nullptr) {}
 
@@ -126,6 +128,30 @@ void RawProcessor::copy_data_to_buffer_u16(rust::Slice<uint16_t> buffer) const {
     ::memcpy(buffer.data(), image->data, image->data_size);
 }
 
+CaptureInfo RawProcessor::get_capture_info() const {
+    const libraw_data_t& data = processor.imgdata;
+
+    CaptureInfo info;
+    info.make = rust::String::lossy(data.idata.make);
+    info.model = rust::String::lossy(data.idata.model);
+    info.lens = rust::String::lossy(data.lens.Lens);
+    info.iso = data.other.iso_speed;
+    info.shutter = data.other.shutter;
+    info.aperture = data.other.aperture;
+    info.focal_length = data.other.focal_len;
+
+    // LibRaw reads the capture time as local time
+    time_t timestamp = data.other.timestamp;
+    struct tm local;
+    char date_time[20];
+    if (timestamp > 0 && localtime_r(&timestamp, &local) &&
+        strftime(date_time, sizeof(date_time), "%Y:%m:%d %H:%M:%S", &local)) {
+        info.date_time = rust::String(date_time);
+    }
+
+    return info;
+}
+
 std::unique_ptr<RawProcessor> new_raw_processor() {
   return std::make_unique<RawProcessor>();
 }
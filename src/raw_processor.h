@@ -5,6 +5,8 @@
 #include "rust/cxx.h"
 #include <libraw/libraw.h>
 
+struct CaptureInfo;
+
 class RawProcessor {
 private:
     LibRaw processor;
@@ -21,6 +23,7 @@ public:
     uint32_t get_data_size() const;
     void copy_data_to_buffer_u8(rust::Slice<uint8_t> buffer) const;
     void copy_data_to_buffer_u16(rust::Slice<uint16_t> buffer) const;
+    CaptureInfo get_capture_info() const;
 };
 
 std::unique_ptr<RawProcessor> new_raw_processor();
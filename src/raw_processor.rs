@@ -1,5 +1,18 @@
 #[cxx::bridge]
 mod ffi {
+    /// Capture settings from the RAW file's metadata. Missing values are empty or 0.
+    struct CaptureInfo {
+        make: String,
+        model: String,
+        lens: String,
+        iso: f32,
+        shutter: f32,
+        aperture: f32,
+        focal_length: f32,
+        /// Local date and time as YYYY:MM:DD HH:MM:SS
+        date_time: String,
+    }
+
     unsafe extern "C++" {
         include!("yancy/src/raw_processor.h");
 
@@ -15,13 +28,49 @@ mod ffi {
         #[allow(dead_code)]
         fn copy_data_to_buffer_u8(&self, buffer: &mut [u8]) -> Result<()>;
         fn copy_data_to_buffer_u16(&self, buffer: &mut [u16]) -> Result<()>;
+        fn get_capture_info(&self) -> CaptureInfo;
     }
 }
 
 use std::path::Path;
 use image::{ImageBuffer, Rgb};
+use serde::Serialize;
+
+/// Capture settings from a RAW file's metadata
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Capture {
+    pub make: String,
+    pub model: String,
+    pub lens: String,
+    pub iso: Option<f32>,
+    /// In seconds
+    pub exposure_time: Option<f32>,
+    pub f_number: Option<f32>,
+    /// In millimeters
+    pub focal_length: Option<f32>,
+    /// Local date and time as `YYYY:MM:DD HH:MM:SS`, like in Exif
+    pub date_time: Option<String>,
+}
+
+impl From<ffi::CaptureInfo> for Capture {
+    fn from(info: ffi::CaptureInfo) -> Self {
+        let positive = |value: f32| (value > 0.0).then_some(value);
+        Self {
+            make: info.make.trim().to_owned(),
+            model: info.model.trim().to_owned(),
+            lens: info.lens.trim().to_owned(),
+            iso: positive(info.iso),
+            exposure_time: positive(info.shutter),
+            f_number: positive(info.aperture),
+            focal_length: positive(info.focal_length),
+            date_time: (!info.date_time.is_empty()).then_some(info.date_time),
+        }
+    }
+}
+
+type RawImage = ImageBuffer<Rgb<u16>, Vec<u16>>;
 
-pub fn load_raw_image<P: AsRef<Path>>(path: P) -> Result<ImageBuffer<Rgb<u16>, Vec<u16>>, Box<dyn std::error::Error>> {
+pub fn load_raw_image<P: AsRef<Path>>(path: P) -> Result<(RawImage, Capture), Box<dyn std::error::Error>> {
     let path_str = path
         .as_ref()
         .to_str()
@@ -46,5 +95,5 @@ pub fn load_raw_image<P: AsRef<Path>>(path: P) -> Result<ImageBuffer<Rgb<u16>, V
         return Err(format!("Unsupported bit depth: {}", bits).into());
     };
 
-    Ok(rgb_image)
+    Ok((rgb_image, processor.get_capture_info().into()))
 }
@@ -0,0 +1,237 @@
+use image::{GrayImage, ImageBuffer, Luma};
+use imageproc::filter::gaussian_blur_f32;
+use imageproc::gradients::sobel_gradients;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::conversion::{RgbBuffer, Sample};
+
+/// Radius for an image of `REFERENCE_SIZE` pixels on its longest side. Other
+/// sizes get a radius in proportion, so that the same detail is sharpened.
+const REFERENCE_RADIUS: f32 = 1.5;
+const REFERENCE_SIZE: f32 = 6000.0;
+const MIN_RADIUS: f32 = 0.5;
+const MAX_RADIUS: f32 = 50.0;
+const MAX_AMOUNT: f32 = 500.0;
+
+/// The edge mask is measured on a blur of this many radii, which smooths grain
+/// away but keeps edges
+const EDGE_BLUR_SCALE: f32 = 2.0;
+/// Gradients (as a share of the largest possible one) below `EDGE_LOW` aren't
+/// sharpened, and ones above `EDGE_HIGH` are fully sharpened
+const EDGE_LOW: f32 = 0.02;
+const EDGE_HIGH: f32 = 0.08;
+/// Largest gradient of the Sobel operator on 8-bit values
+const MAX_GRADIENT: f32 = 4.0 * u8::MAX as f32;
+
+/// Unsharp mask applied to luma at the end of the conversion, for softness
+/// from the lens and demosaicing
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SharpenOptions {
+    /// Percentage of the detail that is added back, from 0 to 500
+    pub amount: f32,
+    /// Standard deviation of the blur in pixels. If unset, it's picked from
+    /// the size of the image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radius: Option<f32>,
+    /// Differences from the blur below this share of the full range aren't
+    /// sharpened, which keeps smooth areas smooth
+    pub threshold: f32,
+    /// Only sharpen edges, so that grain isn't sharpened
+    pub edge_mask: bool,
+}
+
+impl SharpenOptions {
+    pub fn is_identity(&self) -> bool {
+        self.amount <= 0.0
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=MAX_AMOUNT).contains(&self.amount) {
+            return Err(format!(
+                "sharpening amount ({}) should be from 0 to {}",
+                self.amount, MAX_AMOUNT
+            ));
+        }
+        if let Some(radius) = self
+            .radius
+            .filter(|r| !(MIN_RADIUS..=MAX_RADIUS).contains(r))
+        {
+            return Err(format!(
+                "sharpening radius ({}) should be from {} to {}",
+                radius, MIN_RADIUS, MAX_RADIUS
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.threshold) {
+            return Err(format!(
+                "sharpening threshold ({}) should be from 0 to 1",
+                self.threshold
+            ));
+        }
+        Ok(())
+    }
+
+    /// The radius to sharpen an image of `width` by `height` pixels with
+    pub fn radius_for(&self, width: u32, height: u32) -> f32 {
+        self.radius.unwrap_or_else(|| {
+            (REFERENCE_RADIUS * width.max(height) as f32 / REFERENCE_SIZE).max(MIN_RADIUS)
+        })
+    }
+}
+
+/// Luma of (gamma encoded) values, from 0 to 1
+fn luma<S: Sample>(image: &RgbBuffer<S>) -> ImageBuffer<Luma<f32>, Vec<f32>> {
+    let values = image
+        .par_pixels()
+        .map(|p| {
+            let [r, g, b] = S::values(p).map(|v| v.to_unit());
+            0.25 * r + 0.5 * g + 0.25 * b
+        })
+        .collect();
+    ImageBuffer::from_raw(image.width(), image.height(), values)
+        .expect("luma should have one value per pixel")
+}
+
+/// A mask that is white on edges and black on flat or grainy areas, for
+/// sharpening with `radius`
+pub fn edge_mask<S: Sample>(image: &RgbBuffer<S>, radius: f32) -> GrayImage {
+    let blurred = gaussian_blur_f32(&luma(image), radius * EDGE_BLUR_SCALE);
+    let gray = GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        Luma([(blurred.get_pixel(x, y).0[0].clamp(0.0, 1.0) * u8::MAX as f32).round() as u8])
+    });
+
+    let gradients = sobel_gradients(&gray);
+    GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        let gradient = gradients.get_pixel(x, y).0[0] as f32 / MAX_GRADIENT;
+        let t = ((gradient - EDGE_LOW) / (EDGE_HIGH - EDGE_LOW)).clamp(0.0, 1.0);
+        Luma([(t * t * (3.0 - 2.0 * t) * u8::MAX as f32).round() as u8])
+    })
+}
+
+/// Adds the difference between luma and a blur of it to every channel, so
+/// that colors don't fringe. `mask` scales the sharpening of each pixel.
+pub fn apply_sharpen_mut<S: Sample>(
+    image: &mut RgbBuffer<S>,
+    options: &SharpenOptions,
+    mask: Option<&GrayImage>,
+) {
+    let radius = options.radius_for(image.width(), image.height());
+    let luma = luma(image);
+    let blurred = gaussian_blur_f32(&luma, radius);
+    let amount = options.amount / 100.0;
+
+    let detail: Vec<f32> = luma
+        .par_iter()
+        .zip(blurred.par_iter())
+        .enumerate()
+        .map(|(i, (&value, &blur))| {
+            let difference = value - blur;
+            if difference.abs() < options.threshold {
+                return 0.0;
+            }
+            let weight = mask.map_or(1.0, |m| m.as_raw()[i] as f32 / u8::MAX as f32);
+            difference * amount * weight
+        })
+        .collect();
+
+    image
+        .par_pixels_mut()
+        .zip(detail.par_iter())
+        .for_each(|(pixel, &detail)| {
+            *pixel = S::values(pixel)
+                .map(|v| S::from_unit(v.to_unit() + detail))
+                .into();
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+    use crate::conversion::{FloatImage, InputImage};
+
+    fn sharpen(amount: f32) -> SharpenOptions {
+        SharpenOptions {
+            amount,
+            ..SharpenOptions::default()
+        }
+    }
+
+    #[test]
+    fn validates_options() {
+        assert!(sharpen(100.0).validate().is_ok());
+        assert!(sharpen(-1.0).validate().is_err());
+        assert!(sharpen(600.0).validate().is_err());
+        let radius = |radius| SharpenOptions {
+            radius: Some(radius),
+            ..sharpen(100.0)
+        };
+        assert!(radius(2.0).validate().is_ok());
+        assert!(radius(0.1).validate().unwrap_err().contains("radius"));
+        assert!(radius(80.0).validate().unwrap_err().contains("radius"));
+        let threshold = SharpenOptions {
+            threshold: 1.5,
+            ..sharpen(100.0)
+        };
+        assert!(threshold.validate().unwrap_err().contains("threshold"));
+    }
+
+    #[test]
+    fn radius_follows_the_size_of_the_image() {
+        assert_eq!(sharpen(100.0).radius_for(6000, 4000), REFERENCE_RADIUS);
+        assert_eq!(
+            sharpen(100.0).radius_for(2000, 3000),
+            REFERENCE_RADIUS / 2.0
+        );
+        assert_eq!(sharpen(100.0).radius_for(300, 200), MIN_RADIUS);
+        let options = SharpenOptions {
+            radius: Some(3.0),
+            ..sharpen(100.0)
+        };
+        assert_eq!(options.radius_for(300, 200), 3.0);
+    }
+
+    #[test]
+    fn flat_images_stay_flat() {
+        let flat = InputImage::from_pixel(32, 32, Rgb([30000, 20000, 10000]));
+        let mut image = flat.clone();
+        apply_sharpen_mut(&mut image, &sharpen(300.0), None);
+        for (sharpened, original) in image.pixels().zip(flat.pixels()) {
+            for (&a, &b) in sharpened.0.iter().zip(original.0.iter()) {
+                assert!(a.abs_diff(b) <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn edges_get_more_contrast() {
+        let mut image = FloatImage::from_fn(32, 32, |x, _| {
+            if x < 16 {
+                Rgb([0.25; 3])
+            } else {
+                Rgb([0.75; 3])
+            }
+        });
+        apply_sharpen_mut(&mut image, &sharpen(100.0), None);
+        assert!(image.get_pixel(15, 16).0[0] < 0.25);
+        assert!(image.get_pixel(16, 16).0[0] > 0.75);
+        // far from the edge, nothing changes
+        assert!((image.get_pixel(2, 16).0[0] - 0.25).abs() < 1e-4);
+    }
+
+    #[test]
+    fn edge_mask_ignores_flat_areas() {
+        let image = InputImage::from_fn(64, 64, |x, _| {
+            if x < 32 {
+                Rgb([10000; 3])
+            } else {
+                Rgb([50000; 3])
+            }
+        });
+        let mask = edge_mask(&image, 1.0);
+        assert_eq!(mask.get_pixel(4, 32).0[0], 0);
+        assert_eq!(mask.get_pixel(32, 32).0[0], u8::MAX);
+    }
+}
@@ -0,0 +1,266 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::balance::BalanceOptions;
+use crate::base::Region;
+use crate::color::ColorOptions;
+use crate::conversion::Conversion;
+use crate::denoise::DenoiseOptions;
+use crate::sharpen::SharpenOptions;
+use crate::tone::ToneOptions;
+
+/// Per-file overrides, read from `<file>.yancy.toml` next to the RAW file. For
+/// half frames, each half has its own sidecar (`<file>.a.yancy.toml`, etc.).
+///
+/// Any value left unset is detected automatically.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sidecar {
+    /// Crop rectangle as `[min_x, min_y, max_x, max_y]`, in pixels of the
+    /// (rotated) frame. Replaces border detection and the additional crop.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crop: Option<[u32; 4]>,
+
+    /// Color of the film backing as 16-bit `[r, g, b]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_color: Option<[u16; 3]>,
+
+    /// Region to sample the film backing color from, as `[x, y, width, height]`
+    /// in pixels or fractions of the (rotated) frame. Ignored if `base_color`
+    /// is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_region: Option<Region>,
+
+    /// Clockwise rotation in degrees, applied before anything else
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<f32>,
+
+    /// White balance after inversion for this frame, instead of the one given
+    /// on the command line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<BalanceOptions>,
+
+    /// Noise reduction for this frame, instead of the one given on the command
+    /// line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub denoise: Option<DenoiseOptions>,
+
+    /// Tone adjustments for this frame, instead of the ones given on the
+    /// command line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tone: Option<ToneOptions>,
+
+    /// Saturation and hue adjustments for this frame, instead of the ones
+    /// given on the command line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<ColorOptions>,
+
+    /// Sharpening for this frame, instead of the one given on the command line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sharpen: Option<SharpenOptions>,
+
+    pub steps: Steps,
+}
+
+/// Processing steps that can be turned off
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Steps {
+    pub crop: bool,
+    pub dust: bool,
+    pub white_balance: bool,
+    pub invert: bool,
+    pub stretch: bool,
+    pub balance: bool,
+    pub denoise: bool,
+    pub tone: bool,
+    pub color: bool,
+    pub lut: bool,
+    pub sharpen: bool,
+}
+
+impl Default for Steps {
+    fn default() -> Self {
+        Self {
+            crop: true,
+            dust: true,
+            white_balance: true,
+            invert: true,
+            stretch: true,
+            balance: true,
+            denoise: true,
+            tone: true,
+            color: true,
+            lut: true,
+            sharpen: true,
+        }
+    }
+}
+
+impl Sidecar {
+    pub fn path_for(path: &str) -> String {
+        format!("{}.yancy.toml", path)
+    }
+
+    /// Reads the sidecar for `path`, or returns the defaults if there is none.
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let sidecar_path = Self::path_for(path);
+
+        if !Path::new(&sidecar_path).is_file() {
+            return Ok(Self::default());
+        }
+
+        let contents = std::fs::read_to_string(&sidecar_path)?;
+        let sidecar: Self = toml::from_str(&contents)
+            .map_err(|e| format!("Invalid sidecar {}: {}", sidecar_path, e))?;
+        if let Some(tone) = &sidecar.tone {
+            tone.validate()
+                .map_err(|e| format!("Invalid sidecar {}: {}", sidecar_path, e))?;
+        }
+        if let Some(denoise) = &sidecar.denoise {
+            denoise
+                .validate()
+                .map_err(|e| format!("Invalid sidecar {}: {}", sidecar_path, e))?;
+        }
+        if let Some(sharpen) = &sidecar.sharpen {
+            sharpen
+                .validate()
+                .map_err(|e| format!("Invalid sidecar {}: {}", sidecar_path, e))?;
+        }
+        Ok(sidecar)
+    }
+
+    /// Pins the rotation, crop and base color that were used for `conversion`,
+    /// so that they can be adjusted by hand.
+    pub fn with_detected(&self, conversion: &Conversion) -> Self {
+        let (min_x, min_y, max_x, max_y) = conversion.crop;
+        Self {
+            crop: Some([min_x, min_y, max_x, max_y]),
+            base_color: Some(conversion.base_color.0),
+            rotation: conversion.rotation,
+            ..self.clone()
+        }
+    }
+
+    /// The sidecar of the frame scaled by `factor`, e.g. for a preview
+    pub fn scaled(&self, factor: f32) -> Self {
+        let scale = |v: u32| (v as f32 * factor).round() as u32;
+        let mut balance = self.balance.clone();
+        if let Some(balance) = &mut balance {
+            balance.neutral_point = balance.neutral_point.map(|p| p.scaled(factor));
+        }
+        Self {
+            crop: self.crop.map(|crop| crop.map(scale)),
+            base_region: self.base_region.map(|r| r.scaled(factor)),
+            balance,
+            ..self.clone()
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let sidecar_path = Self::path_for(path);
+        std::fs::write(&sidecar_path, toml::to_string_pretty(self)?)?;
+        println!("Saved {}", sidecar_path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::Point;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn parses_sidecars() {
+        let sidecar: Sidecar = toml::from_str(
+            r#"
+            crop = [10, 20, 310, 220]
+            base_region = [0.0, 0.0, 0.1, 1.0]
+            rotation = -1.5
+
+            [balance]
+            neutral_point = [0.5, 0.5]
+
+            [steps]
+            dust = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(sidecar.crop, Some([10, 20, 310, 220]));
+        assert_eq!(
+            sidecar.base_region,
+            Some(Region::from([0.0, 0.0, 0.1, 1.0]))
+        );
+        assert_eq!(sidecar.rotation, Some(-1.5));
+        assert!(sidecar.base_color.is_none());
+        assert!(!sidecar.steps.dust);
+        // steps that aren't given stay on
+        assert!(sidecar.steps.crop && sidecar.steps.tone);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(toml::from_str::<Sidecar>("rotate = 90").is_err());
+        assert!(toml::from_str::<Sidecar>("[steps]\nblur = false").is_err());
+    }
+
+    #[test]
+    fn loads_defaults_without_a_sidecar() {
+        let dir = TempDir::new("sidecar-missing");
+        let sidecar = Sidecar::load(&dir.file("a.NEF")).unwrap();
+        assert!(sidecar.crop.is_none() && sidecar.tone.is_none());
+    }
+
+    #[test]
+    fn validates_loaded_sidecars() {
+        let dir = TempDir::new("sidecar-invalid");
+        let path = dir.file("a.NEF");
+        std::fs::write(
+            Sidecar::path_for(&path),
+            "[tone]\nhighlights = 0.7\nshadows = 0.7\n",
+        )
+        .unwrap();
+        let error = Sidecar::load(&path).unwrap_err().to_string();
+        assert!(error.starts_with("Invalid sidecar"));
+        assert!(error.contains("highlights and shadows"));
+    }
+
+    #[test]
+    fn saves_what_it_loads() {
+        let dir = TempDir::new("sidecar-saved");
+        let path = dir.file("a.NEF");
+        let sidecar = Sidecar {
+            crop: Some([1, 2, 3, 4]),
+            base_color: Some([40000, 20000, 10000]),
+            rotation: Some(90.0),
+            ..Sidecar::default()
+        };
+        sidecar.save(&path).unwrap();
+        let loaded = Sidecar::load(&path).unwrap();
+        assert_eq!(loaded.crop, sidecar.crop);
+        assert_eq!(loaded.base_color, sidecar.base_color);
+        assert_eq!(loaded.rotation, sidecar.rotation);
+    }
+
+    #[test]
+    fn scales_pixels_but_not_fractions() {
+        let sidecar = Sidecar {
+            crop: Some([100, 200, 3001, 2001]),
+            base_region: Some(Region::from([0.0, 0.0, 0.1, 1.0])),
+            balance: Some(BalanceOptions {
+                neutral_point: Some(Point { x: 300.0, y: 400.0 }),
+                ..BalanceOptions::default()
+            }),
+            ..Sidecar::default()
+        };
+        let scaled = sidecar.scaled(0.5);
+        assert_eq!(scaled.crop, Some([50, 100, 1501, 1001]));
+        assert_eq!(scaled.base_region, sidecar.base_region);
+        assert_eq!(
+            scaled.balance.unwrap().neutral_point,
+            Some(Point { x: 150.0, y: 200.0 })
+        );
+    }
+}
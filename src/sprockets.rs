@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+
+use image::{GrayImage, Luma};
+use imageproc::map::map_colors;
+use imageproc::region_labelling::{Connectivity, connected_components};
+
+use crate::conversion::Bounds;
+
+/// Distance between the centers of the two rows of sprocket holes on 135 film,
+/// in mm
+const HOLE_ROWS_DISTANCE_MM: f32 = 26.9;
+const FILM_WIDTH_MM: f32 = 35.0;
+
+const MIN_HOLES_PER_ROW: usize = 3;
+const MIN_HOLE_AREA: u32 = 12;
+/// Minimum share of a hole's bounding box that is filled
+const MIN_HOLE_FILL: f32 = 0.6;
+/// Maximum size of a hole, as a share of the shorter side of the image
+const MAX_HOLE_SIZE_PCT: f32 = 0.15;
+
+/// Direction that the film runs in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Orientation {
+    /// Sprocket holes along the top and bottom
+    Horizontal,
+    /// Sprocket holes along the left and right
+    Vertical,
+}
+
+/// Sprocket holes and film rebate of a scan that shows the full width of 35mm
+/// film. Positions across the film are `y` for horizontal film, and `x` for
+/// vertical film.
+#[derive(Clone, Debug)]
+pub struct Sprockets {
+    pub orientation: Orientation,
+    pub holes: Vec<Bounds>,
+    /// `(min, max)` position of the image area across the film, between the
+    /// inner edges of the sprocket holes
+    pub image_area: (u32, u32),
+    /// `(min, max)` position of the film edges. Anything between these and
+    /// the image area is the rebate.
+    pub film_area: (u32, u32),
+}
+
+impl Sprockets {
+    pub fn scale(&self, scale_x: impl Fn(u32) -> u32, scale_y: impl Fn(u32) -> u32) -> Self {
+        let scale_across = |v: u32| match self.orientation {
+            Orientation::Horizontal => scale_y(v),
+            Orientation::Vertical => scale_x(v),
+        };
+
+        Self {
+            orientation: self.orientation,
+            holes: self
+                .holes
+                .iter()
+                .map(|&(min_x, min_y, max_x, max_y)| {
+                    (
+                        scale_x(min_x),
+                        scale_y(min_y),
+                        scale_x(max_x),
+                        scale_y(max_y),
+                    )
+                })
+                .collect(),
+            image_area: (
+                scale_across(self.image_area.0),
+                scale_across(self.image_area.1),
+            ),
+            film_area: (
+                scale_across(self.film_area.0),
+                scale_across(self.film_area.1),
+            ),
+        }
+    }
+
+    /// Whether `(x, y)` is in the rebate, i.e. on the film but outside of the
+    /// image area
+    pub fn in_rebate(&self, x: u32, y: u32) -> bool {
+        let across = match self.orientation {
+            Orientation::Horizontal => y,
+            Orientation::Vertical => x,
+        };
+        let (film_min, film_max) = self.film_area;
+        let (image_min, image_max) = self.image_area;
+        (film_min..film_max).contains(&across) && !(image_min..=image_max).contains(&across)
+    }
+
+    /// Whether `(x, y)` is outside of the image area
+    pub fn outside_image_area(&self, x: u32, y: u32) -> bool {
+        let across = match self.orientation {
+            Orientation::Horizontal => y,
+            Orientation::Vertical => x,
+        };
+        !(self.image_area.0..=self.image_area.1).contains(&across)
+    }
+}
+
+/// Finds two parallel rows of sprocket holes, which show up as the brightest
+/// areas of the scan. `img` should be a normalized grayscale image.
+pub fn detect(img: &GrayImage, threshold: u8) -> Option<Sprockets> {
+    let bright = map_colors(img, |p| if p.0[0] > threshold { p } else { Luma([0]) });
+    let labels = connected_components(&bright, Connectivity::Eight, Luma([0u8]));
+
+    // bounding box and area of each bright blob
+    let mut blobs: HashMap<u32, (Bounds, u32)> = HashMap::new();
+    for (x, y, label) in labels.enumerate_pixels() {
+        if label.0[0] == 0 {
+            continue;
+        }
+        blobs
+            .entry(label.0[0])
+            .and_modify(|((min_x, min_y, max_x, max_y), area)| {
+                *min_x = (*min_x).min(x);
+                *min_y = (*min_y).min(y);
+                *max_x = (*max_x).max(x);
+                *max_y = (*max_y).max(y);
+                *area += 1;
+            })
+            .or_insert(((x, y, x, y), 1));
+    }
+
+    let max_size = img.width().min(img.height()) as f32 * MAX_HOLE_SIZE_PCT;
+    let holes: Vec<Bounds> = blobs
+        .into_values()
+        .filter(|&((min_x, min_y, max_x, max_y), area)| {
+            let width = max_x - min_x + 1;
+            let height = max_y - min_y + 1;
+            let aspect_ratio = width as f32 / height as f32;
+            area >= MIN_HOLE_AREA
+                && (width.max(height) as f32) <= max_size
+                && area as f32 / (width * height) as f32 >= MIN_HOLE_FILL
+                && (0.33..=3.0).contains(&aspect_ratio)
+        })
+        .map(|(bounds, _)| bounds)
+        .collect();
+
+    let horizontal = find_rows(&holes, Orientation::Horizontal, img.height());
+    let vertical = find_rows(&holes, Orientation::Vertical, img.width());
+
+    match (horizontal, vertical) {
+        (Some(h), Some(v)) if v.holes.len() > h.holes.len() => Some(v),
+        (Some(h), _) => Some(h),
+        (None, v) => v,
+    }
+}
+
+/// Groups holes into rows across the film, and picks the two largest rows
+fn find_rows(holes: &[Bounds], orientation: Orientation, extent: u32) -> Option<Sprockets> {
+    let across = |&(min_x, min_y, max_x, max_y): &Bounds| match orientation {
+        Orientation::Horizontal => (min_y, max_y),
+        Orientation::Vertical => (min_x, max_x),
+    };
+    let center = |hole: &Bounds| {
+        let (min, max) = across(hole);
+        (min + max) as f32 / 2.0
+    };
+
+    let mut sorted = holes.to_vec();
+    sorted.sort_by(|a, b| center(a).total_cmp(&center(b)));
+
+    let mut sizes: Vec<u32> = sorted
+        .iter()
+        .map(|hole| {
+            let (min, max) = across(hole);
+            max - min + 1
+        })
+        .collect();
+    sizes.sort_unstable();
+    let tolerance = *sizes.get(sizes.len() / 2)? as f32 / 2.0;
+
+    let mut rows: Vec<Vec<Bounds>> = vec![];
+    for hole in sorted {
+        match rows.last_mut() {
+            Some(row) if center(&hole) - center(row.last().unwrap()) <= tolerance => row.push(hole),
+            _ => rows.push(vec![hole]),
+        }
+    }
+
+    rows.retain(|row| row.len() >= MIN_HOLES_PER_ROW);
+    rows.sort_by_key(|row| std::cmp::Reverse(row.len()));
+    let mut rows: Vec<Vec<Bounds>> = rows.into_iter().take(2).collect();
+    if rows.len() < 2 {
+        return None;
+    }
+    rows.sort_by(|a, b| center(&a[0]).total_cmp(&center(&b[0])));
+
+    let row_center = |row: &Vec<Bounds>| row.iter().map(center).sum::<f32>() / row.len() as f32;
+    let (first, second) = (row_center(&rows[0]), row_center(&rows[1]));
+
+    // the rows should be on opposite edges of the film
+    if second - first < extent as f32 / 2.0 {
+        return None;
+    }
+
+    let inner_min = rows[0].iter().map(|hole| across(hole).1).max()?;
+    let inner_max = rows[1].iter().map(|hole| across(hole).0).min()?;
+
+    let mid = (first + second) / 2.0;
+    let half_film_width = FILM_WIDTH_MM / 2.0 * (second - first) / HOLE_ROWS_DISTANCE_MM;
+
+    Some(Sprockets {
+        orientation,
+        holes: rows.concat(),
+        image_area: (inner_min + 1, inner_max.saturating_sub(1)),
+        film_area: (
+            (mid - half_film_width).max(0.0) as u32,
+            ((mid + half_film_width) as u32).min(extent),
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A dark scan with bright 10 by 14 pixel holes every 40 pixels, in rows
+    /// starting at each of `rows`
+    fn scan(width: u32, height: u32, rows: &[u32]) -> GrayImage {
+        GrayImage::from_fn(width, height, |x, y| {
+            let in_row = rows.iter().any(|&row| (row..row + 14).contains(&y));
+            Luma([if in_row && x % 40 < 10 { 255 } else { 50 }])
+        })
+    }
+
+    fn transposed(img: &GrayImage) -> GrayImage {
+        GrayImage::from_fn(img.height(), img.width(), |x, y| *img.get_pixel(y, x))
+    }
+
+    #[test]
+    fn finds_two_rows_of_holes() {
+        let sprockets = detect(&scan(400, 300, &[10, 276]), 200).unwrap();
+        assert_eq!(sprockets.orientation, Orientation::Horizontal);
+        assert_eq!(sprockets.holes.len(), 20);
+        assert_eq!(sprockets.image_area, (24, 275));
+        // the film is wider than the scan, which is cut off at its edges
+        assert_eq!(sprockets.film_area, (0, 300));
+        assert!(sprockets.in_rebate(5, 5));
+        assert!(!sprockets.in_rebate(5, 150));
+        assert!(sprockets.outside_image_area(5, 12));
+        assert!(!sprockets.outside_image_area(5, 150));
+    }
+
+    #[test]
+    fn finds_vertical_film() {
+        let sprockets = detect(&transposed(&scan(400, 300, &[10, 276])), 200).unwrap();
+        assert_eq!(sprockets.orientation, Orientation::Vertical);
+        assert_eq!(sprockets.image_area, (24, 275));
+    }
+
+    #[test]
+    fn rejects_rows_that_are_close_together() {
+        assert!(detect(&scan(400, 300, &[100, 160]), 200).is_none());
+    }
+
+    #[test]
+    fn rejects_a_single_row() {
+        assert!(detect(&scan(400, 300, &[10]), 200).is_none());
+        assert!(detect(&scan(400, 300, &[]), 200).is_none());
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+/// Name of the file that the progress of a batch is saved to
+pub const STATE_FILE_NAME: &str = ".yancy-state.json";
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Progress of a batch, saved after each input so that a re-run can skip the
+/// inputs that are done
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BatchState {
+    /// Inputs that were converted, by their canonical path
+    pub finished: BTreeMap<String, Finished>,
+    /// Inputs that failed, by their canonical path, with the error
+    pub failed: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Finished {
+    /// Hash of the settings that the input was converted with
+    pub settings: String,
+    /// Size of the input in bytes, when it was converted
+    pub size: u64,
+    /// Modification time of the input in seconds since the Unix epoch, when
+    /// it was converted
+    pub modified: u64,
+}
+
+impl BatchState {
+    /// Reads the state at `path`, or returns an empty one if there is none
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid state file {}: {}", path.display(), e).into())
+    }
+
+    /// Saves the state, replacing the previous file only once it's written
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Whether `input` was converted with `settings`, and hasn't changed since
+    pub fn is_finished(&self, input: &str, settings: &str) -> bool {
+        match (self.finished.get(&key(input)), fingerprint(input)) {
+            (Some(finished), Some((size, modified))) => {
+                finished.settings == settings
+                    && finished.size == size
+                    && finished.modified == modified
+            }
+            _ => false,
+        }
+    }
+
+    pub fn finish(&mut self, input: &str, settings: &str) {
+        let key = key(input);
+        self.failed.remove(&key);
+        match fingerprint(input) {
+            Some((size, modified)) => {
+                let finished = Finished {
+                    settings: settings.to_owned(),
+                    size,
+                    modified,
+                };
+                self.finished.insert(key, finished);
+            }
+            None => {
+                self.finished.remove(&key);
+            }
+        }
+    }
+
+    pub fn fail(&mut self, input: &str, error: &str) {
+        let key = key(input);
+        self.finished.remove(&key);
+        self.failed.insert(key, error.to_owned());
+    }
+}
+
+/// Hash of `settings` and the contents of those `files` that exist, e.g.
+/// sidecars. It's the same across runs and builds, unlike `DefaultHasher`.
+pub fn settings_hash(settings: &str, files: &[String]) -> String {
+    let mut hash = fnv1a(FNV_OFFSET, settings.as_bytes());
+    for file in files {
+        if let Ok(contents) = fs::read(file) {
+            hash = fnv1a(hash, file.as_bytes());
+            hash = fnv1a(hash, &contents);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Inputs are recorded by their canonical path, so that runs from another
+/// directory find them
+fn key(input: &str) -> String {
+    fs::canonicalize(input)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| input.to_owned())
+}
+
+/// Size and modification time of `input`, which tell whether it changed
+fn fingerprint(input: &str) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(input).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified.as_secs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn write(path: String, contents: &str) -> String {
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn settings_hash_is_stable() {
+        // FNV-1a of nothing is its offset
+        assert_eq!(settings_hash("", &[]), "cbf29ce484222325");
+        assert_eq!(settings_hash("a", &[]), "af63dc4c8601ec8c");
+        assert_ne!(settings_hash("a", &[]), settings_hash("b", &[]));
+    }
+
+    #[test]
+    fn settings_hash_covers_files_that_exist() {
+        let dir = TempDir::new("state-hash");
+        let sidecar = write(dir.file("a.NEF.yancy.toml"), "rotation = 90");
+        let missing = dir.file("b.NEF.yancy.toml");
+
+        let without = settings_hash("{}", &[]);
+        let with = settings_hash("{}", std::slice::from_ref(&sidecar));
+        assert_ne!(without, with);
+        assert_eq!(settings_hash("{}", &[missing]), without);
+
+        let sidecar = write(sidecar, "rotation = 180");
+        assert_ne!(settings_hash("{}", &[sidecar]), with);
+    }
+
+    #[test]
+    fn skips_only_unchanged_inputs_with_the_same_settings() {
+        let dir = TempDir::new("state-finished");
+        let input = write(dir.file("a.NEF"), "raw");
+        let mut state = BatchState::default();
+        assert!(!state.is_finished(&input, "1"));
+
+        state.finish(&input, "1");
+        assert!(state.is_finished(&input, "1"));
+        assert!(!state.is_finished(&input, "2"));
+
+        let input = write(input, "a different raw");
+        assert!(!state.is_finished(&input, "1"));
+    }
+
+    #[test]
+    fn failures_replace_finished_inputs() {
+        let dir = TempDir::new("state-failed");
+        let input = write(dir.file("a.NEF"), "raw");
+        let mut state = BatchState::default();
+
+        state.finish(&input, "1");
+        state.fail(&input, "unable to decode");
+        assert!(!state.is_finished(&input, "1"));
+        assert_eq!(
+            state.failed.values().collect::<Vec<_>>(),
+            ["unable to decode"]
+        );
+
+        state.finish(&input, "1");
+        assert!(state.is_finished(&input, "1"));
+        assert!(state.failed.is_empty());
+    }
+
+    #[test]
+    fn missing_inputs_are_not_finished() {
+        let dir = TempDir::new("state-missing");
+        let input = dir.file("a.NEF");
+        let mut state = BatchState::default();
+        state.finish(&input, "1");
+        assert!(state.finished.is_empty());
+        assert!(!state.is_finished(&input, "1"));
+    }
+
+    #[test]
+    fn saves_what_it_loads() {
+        let dir = TempDir::new("state-saved");
+        let input = write(dir.file("a.NEF"), "raw");
+        let path = dir.path().join("state").join(STATE_FILE_NAME);
+        assert!(BatchState::load(&path).unwrap().finished.is_empty());
+
+        let mut state = BatchState::default();
+        state.finish(&input, "1");
+        state.fail("b.NEF", "unable to decode");
+        state.save(&path).unwrap();
+
+        let loaded = BatchState::load(&path).unwrap();
+        assert!(loaded.is_finished(&input, "1"));
+        assert_eq!(loaded.failed, state.failed);
+        assert!(!path.with_extension("json.tmp").exists());
+
+        fs::write(&path, "not json").unwrap();
+        assert!(BatchState::load(&path).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::conversion::Conversion;
+use crate::histogram::{Cutoffs, HistogramRgb, Levels};
+
+const CHANNELS: [&str; 3] = ["r", "g", "b"];
+const HISTOGRAM_BINS: usize = 256;
+
+/// Measurements of a converted frame, for tracking how the conversion behaves
+/// across a roll
+#[derive(Clone, Debug, Serialize)]
+pub struct FrameStats {
+    /// Path that the frame's outputs are named after
+    pub file: String,
+    /// Color of the film backing as 16-bit `[r, g, b]`
+    pub base_color: [u16; 3],
+    pub base_confidence: Option<f32>,
+    /// Frame bounds as `[min_x, min_y, max_x, max_y]`, in pixels of the
+    /// (rotated) original
+    pub crop: [u32; 4],
+    /// Cutoffs picked by each stretch pass, if measured from this frame
+    pub cutoffs: Option<Cutoffs>,
+    /// Levels that the frame was stretched with
+    pub levels: Option<Levels>,
+    /// 256-bin histograms of each channel of the frame, before stretching
+    pub histogram_before: Option<HistogramRgb>,
+    /// 256-bin histograms of each channel of the frame, after all adjustments
+    pub histogram_after: Option<HistogramRgb>,
+}
+
+impl FrameStats {
+    pub fn new(file: &str, conversion: &Conversion) -> Self {
+        let (min_x, min_y, max_x, max_y) = conversion.crop;
+        let histograms = conversion.histograms.as_ref();
+
+        Self {
+            file: file.to_owned(),
+            base_color: conversion.base_color.0,
+            base_confidence: conversion.base_confidence,
+            crop: [min_x, min_y, max_x, max_y],
+            cutoffs: conversion.cutoffs.clone(),
+            levels: conversion.levels.clone(),
+            histogram_before: histograms.map(|h| h.before.clone()),
+            histogram_after: histograms.map(|h| h.after.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    /// Picks the format from the file extension
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+
+        match extension.as_deref() {
+            Some("json") => Ok(Self::Json),
+            Some("csv") => Ok(Self::Csv),
+            _ => Err(format!(
+                "Unsupported stats file {}: expected a .json or .csv extension",
+                path
+            )),
+        }
+    }
+}
+
+pub fn export(path: &str, stats: &[FrameStats]) -> Result<(), Box<dyn std::error::Error>> {
+    let contents = match Format::from_path(path)? {
+        Format::Json => serde_json::to_string_pretty(stats)?,
+        Format::Csv => to_csv(stats),
+    };
+    std::fs::write(path, contents)?;
+    println!("Saved {}", path);
+    Ok(())
+}
+
+/// One row for each channel of each frame, with the histogram bins as the
+/// last columns. Values that weren't measured are left empty.
+fn to_csv(stats: &[FrameStats]) -> String {
+    let mut csv = String::from(
+        "file,channel,base_color,base_confidence,crop_min_x,crop_min_y,crop_max_x,crop_max_y,\
+         first_black,first_white,second_black,second_white,black,white",
+    );
+    for prefix in ["before", "after"] {
+        for bin in 0..HISTOGRAM_BINS {
+            write!(csv, ",{}_{}", prefix, bin).unwrap();
+        }
+    }
+    csv.push('\n');
+
+    for frame in stats {
+        for (channel, name) in CHANNELS.iter().enumerate() {
+            let [min_x, min_y, max_x, max_y] = frame.crop;
+            let levels = |levels: Option<&Levels>| match levels {
+                Some(l) => format!("{},{}", l.black[channel], l.white[channel]),
+                None => String::from(","),
+            };
+
+            write!(
+                csv,
+                "{},{},{},{},{},{},{},{},{},{},{}",
+                csv_field(&frame.file),
+                name,
+                frame.base_color[channel],
+                optional(frame.base_confidence),
+                min_x,
+                min_y,
+                max_x,
+                max_y,
+                levels(frame.cutoffs.as_ref().map(|c| &c.first)),
+                levels(frame.cutoffs.as_ref().map(|c| &c.second)),
+                levels(frame.levels.as_ref()),
+            )
+            .unwrap();
+
+            for histogram in [&frame.histogram_before, &frame.histogram_after] {
+                match histogram {
+                    Some(hist) => hist[channel]
+                        .iter()
+                        .for_each(|count| write!(csv, ",{}", count).unwrap()),
+                    None => csv.push_str(&",".repeat(HISTOGRAM_BINS)),
+                }
+            }
+            csv.push('\n');
+        }
+    }
+
+    csv
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// Quotes a value if it contains a separator, quote, or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits a CSV line into its fields, unquoting them
+    fn fields(line: &str) -> Vec<String> {
+        let mut fields = vec![String::new()];
+        let mut quoted = false;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    fields.last_mut().unwrap().push('"');
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => fields.push(String::new()),
+                c => fields.last_mut().unwrap().push(c),
+            }
+        }
+        fields
+    }
+
+    fn frame(file: &str) -> FrameStats {
+        FrameStats {
+            file: file.to_owned(),
+            base_color: [40000, 20000, 10000],
+            base_confidence: None,
+            crop: [10, 20, 3010, 2020],
+            cutoffs: None,
+            levels: None,
+            histogram_before: None,
+            histogram_after: None,
+        }
+    }
+
+    #[test]
+    fn picks_the_format_from_the_extension() {
+        assert_eq!(Format::from_path("stats.json"), Ok(Format::Json));
+        assert_eq!(Format::from_path("roll/STATS.CSV"), Ok(Format::Csv));
+        assert!(Format::from_path("stats.txt").is_err());
+        assert!(Format::from_path("stats").is_err());
+    }
+
+    #[test]
+    fn rows_line_up_with_the_header() {
+        let levels = Levels {
+            black: [100.0, 200.0, 300.0],
+            white: [60000.0, 61000.0, 62000.0],
+        };
+        let measured = FrameStats {
+            base_confidence: Some(0.75),
+            cutoffs: Some(Cutoffs {
+                first: levels.clone(),
+                second: levels.clone(),
+            }),
+            levels: Some(levels),
+            histogram_before: Some(vec![vec![1; HISTOGRAM_BINS]; 3]),
+            histogram_after: Some(vec![vec![2; HISTOGRAM_BINS]; 3]),
+            ..frame("a.NEF")
+        };
+        let csv = to_csv(&[measured, frame("roll 1, \"best\".NEF")]);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 1 + 2 * CHANNELS.len());
+
+        let header = fields(lines[0]);
+        assert_eq!(header.len(), 14 + 2 * HISTOGRAM_BINS);
+        for line in &lines[1..] {
+            assert_eq!(fields(line).len(), header.len(), "{}", line);
+        }
+
+        let column = |row: &[String], name: &str| {
+            row[header.iter().position(|h| h == name).unwrap()].clone()
+        };
+        let row = fields(lines[2]);
+        assert_eq!(column(&row, "channel"), "g");
+        assert_eq!(column(&row, "base_color"), "20000");
+        assert_eq!(column(&row, "black"), "200");
+        assert_eq!(column(&row, "after_255"), "2");
+
+        let row = fields(lines[4]);
+        assert_eq!(column(&row, "file"), "roll 1, \"best\".NEF");
+        assert_eq!(column(&row, "base_confidence"), "");
+        assert_eq!(column(&row, "first_white"), "");
+        assert_eq!(column(&row, "crop_max_y"), "2020");
+        assert_eq!(column(&row, "before_0"), "");
+    }
+
+    #[test]
+    fn quotes_fields_that_need_it() {
+        assert_eq!(csv_field("a.NEF"), "a.NEF");
+        assert_eq!(csv_field("a,b.NEF"), "\"a,b.NEF\"");
+        assert_eq!(csv_field("say \"cheese\""), "\"say \"\"cheese\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+}
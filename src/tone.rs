@@ -0,0 +1,374 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::conversion::{RgbBuffer, Sample};
+
+/// Adjustments applied after stretching. 16-bit frames are adjusted through a
+/// lookup table, so that no precision is lost to banding.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToneOptions {
+    /// Exposure compensation in stops, applied to linear values
+    pub exposure: f32,
+    /// Strength of an S-curve around the midtones. Negative values flatten
+    /// the image.
+    pub contrast: f32,
+    /// From 0 to 1. Rolls off values that would clip to white, instead of
+    /// cutting them off, starting this far below white.
+    pub highlights: f32,
+    /// From 0 to 1. Rolls off values that would clip to black, instead of
+    /// cutting them off, starting this far above black.
+    pub shadows: f32,
+    /// Value that becomes black, from 0 to 1
+    pub black_point: f32,
+    /// Value that becomes white, from 0 to 1
+    pub white_point: f32,
+    pub curves: Curves,
+}
+
+impl Default for ToneOptions {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            contrast: 0.0,
+            highlights: 0.0,
+            shadows: 0.0,
+            black_point: 0.0,
+            white_point: 1.0,
+            curves: Curves::default(),
+        }
+    }
+}
+
+impl ToneOptions {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.highlights < 0.0 || self.shadows < 0.0 || self.highlights + self.shadows > 1.0 {
+            return Err(String::from(
+                "highlights and shadows should be at least 0, and add up to at most 1",
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.black_point)
+            || !(0.0..=1.0).contains(&self.white_point)
+            || self.black_point >= self.white_point
+        {
+            return Err(format!(
+                "black point ({}) and white point ({}) should be from 0 to 1, with black below white",
+                self.black_point, self.white_point
+            ));
+        }
+        Ok(())
+    }
+
+    /// Every adjustment, including the curves, for a value of `channel`
+    fn map(&self, channel: usize, value: f32) -> f32 {
+        let value = self.tone(value);
+        let value = match &self.curves.rgb {
+            Some(curve) => curve.eval(value),
+            None => value,
+        };
+        match [&self.curves.r, &self.curves.g, &self.curves.b][channel] {
+            Some(curve) => curve.eval(value),
+            None => value,
+        }
+    }
+
+    /// Everything but the curves, for a value from 0 to 1
+    fn tone(&self, value: f32) -> f32 {
+        let value = (value - self.black_point) / (self.white_point - self.black_point);
+        let value = if self.exposure != 0.0 {
+            srgb_encode(srgb_decode(value) * self.exposure.exp2())
+        } else {
+            value
+        };
+        let value = roll_off(value, self.shadows, self.highlights);
+        s_curve(value, self.contrast)
+    }
+}
+
+/// Custom curves, applied after the other adjustments. `rgb` is applied to
+/// every channel first.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Curves {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rgb: Option<Curve>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r: Option<Curve>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub g: Option<Curve>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub b: Option<Curve>,
+}
+
+/// A smooth curve through control points, given as `x,y/x,y/...` with values
+/// from 0 to 1. Uses monotone cubic interpolation, so that the curve doesn't
+/// overshoot between points.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "Vec<[f32; 2]>", into = "Vec<[f32; 2]>")]
+pub struct Curve {
+    points: Vec<[f32; 2]>,
+    tangents: Vec<f32>,
+}
+
+impl Curve {
+    pub fn new(mut points: Vec<[f32; 2]>) -> Result<Self, String> {
+        if points.len() < 2 {
+            return Err(String::from("a curve needs at least two points"));
+        }
+        if points.iter().flatten().any(|v| !(0.0..=1.0).contains(v)) {
+            return Err(String::from("curve points should be from 0 to 1"));
+        }
+        points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        if points.windows(2).any(|w| w[0][0] == w[1][0]) {
+            return Err(String::from("curve points should have different x values"));
+        }
+
+        let tangents = monotone_tangents(&points);
+        Ok(Self { points, tangents })
+    }
+
+    /// Values before the first point and after the last one are flat
+    pub fn eval(&self, x: f32) -> f32 {
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+        if x <= first[0] {
+            return first[1];
+        }
+        if x >= last[0] {
+            return last[1];
+        }
+
+        let i = self.points.partition_point(|p| p[0] <= x) - 1;
+        let ([x0, y0], [x1, y1]) = (self.points[i], self.points[i + 1]);
+        let h = x1 - x0;
+        let t = (x - x0) / h;
+
+        // cubic Hermite basis
+        let t2 = t * t;
+        let t3 = t2 * t;
+        (2.0 * t3 - 3.0 * t2 + 1.0) * y0
+            + (t3 - 2.0 * t2 + t) * h * self.tangents[i]
+            + (-2.0 * t3 + 3.0 * t2) * y1
+            + (t3 - t2) * h * self.tangents[i + 1]
+    }
+}
+
+impl TryFrom<Vec<[f32; 2]>> for Curve {
+    type Error = String;
+
+    fn try_from(points: Vec<[f32; 2]>) -> Result<Self, Self::Error> {
+        Self::new(points)
+    }
+}
+
+impl From<Curve> for Vec<[f32; 2]> {
+    fn from(curve: Curve) -> Self {
+        curve.points
+    }
+}
+
+impl FromStr for Curve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let points = s
+            .split('/')
+            .map(|point| {
+                let values: Vec<f32> = point
+                    .split(',')
+                    .map(|v| v.trim().parse::<f32>())
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format!("invalid curve point \"{}\": {}", point, e))?;
+                match values[..] {
+                    [x, y] => Ok([x, y]),
+                    _ => Err(format!("invalid curve point \"{}\": expected x,y", point)),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Self::new(points).map_err(|e| format!("invalid curve \"{}\": {}", s, e))
+    }
+}
+
+/// Fritsch-Carlson tangents, so that the curve only rises (or falls) where the
+/// points do
+fn monotone_tangents(points: &[[f32; 2]]) -> Vec<f32> {
+    let slopes: Vec<f32> = points
+        .windows(2)
+        .map(|w| (w[1][1] - w[0][1]) / (w[1][0] - w[0][0]))
+        .collect();
+
+    let mut tangents = Vec::with_capacity(points.len());
+    tangents.push(slopes[0]);
+    for w in slopes.windows(2) {
+        tangents.push(if w[0] * w[1] <= 0.0 {
+            0.0
+        } else {
+            (w[0] + w[1]) / 2.0
+        });
+    }
+    tangents.push(slopes[slopes.len() - 1]);
+
+    for (i, &slope) in slopes.iter().enumerate() {
+        if slope == 0.0 {
+            tangents[i] = 0.0;
+            tangents[i + 1] = 0.0;
+            continue;
+        }
+        let a = tangents[i] / slope;
+        let b = tangents[i + 1] / slope;
+        let length = (a * a + b * b).sqrt();
+        if length > 3.0 {
+            tangents[i] = 3.0 * a / length * slope;
+            tangents[i + 1] = 3.0 * b / length * slope;
+        }
+    }
+
+    tangents
+}
+
+pub fn srgb_decode(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn srgb_encode(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Eases values below `shadows` and above `1 - highlights` into 0 and 1, so
+/// that nothing is clipped abruptly. Each knee starts with a slope of 1 and
+/// flattens out to a slope of 0 at black or white, which stay in place.
+fn roll_off(value: f32, shadows: f32, highlights: f32) -> f32 {
+    let shoulder = 1.0 - highlights;
+    if highlights > 0.0 && value > shoulder {
+        shoulder + highlights * knee((value - shoulder) / highlights)
+    } else if shadows > 0.0 && value < shadows {
+        shadows - shadows * knee((shadows - value) / shadows)
+    } else {
+        value
+    }
+}
+
+/// Cubic Hermite curve from 0 with a slope of 1 to 1 with a slope of 0. Values
+/// past 1 stay at 1.
+fn knee(t: f32) -> f32 {
+    let t = t.min(1.0);
+    t + t * t - t * t * t
+}
+
+/// Symmetric S-curve around 0.5 that keeps 0 and 1 in place. Values outside
+/// of 0 to 1 are kept as they are.
+fn s_curve(value: f32, contrast: f32) -> f32 {
+    if contrast == 0.0 || !(0.0..=1.0).contains(&value) {
+        return value;
+    }
+
+    let power = contrast.exp2();
+    if value < 0.5 {
+        0.5 * (2.0 * value).powf(power)
+    } else {
+        1.0 - 0.5 * (2.0 * (1.0 - value)).powf(power)
+    }
+}
+
+pub fn apply_tone_mut<S: Sample>(image: &mut RgbBuffer<S>, options: &ToneOptions) {
+    S::map_channels_mut(image, |channel, value| options.map(channel, value));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    #[test]
+    fn roll_off_keeps_black_and_white() {
+        for (shadows, highlights) in [(0.0, 0.0), (0.2, 0.0), (0.0, 0.3), (0.25, 0.5)] {
+            assert_eq!(roll_off(0.0, shadows, highlights), 0.0);
+            assert_eq!(roll_off(1.0, shadows, highlights), 1.0);
+        }
+        assert_eq!(roll_off(1.5, 0.2, 0.3), 1.0);
+        assert_eq!(roll_off(-0.5, 0.2, 0.3), 0.0);
+    }
+
+    #[test]
+    fn roll_off_is_continuous_at_the_knees() {
+        let (shadows, highlights) = (0.2, 0.3);
+        for knee in [shadows, 1.0 - highlights] {
+            let below = roll_off(knee - EPSILON, shadows, highlights);
+            let above = roll_off(knee + EPSILON, shadows, highlights);
+            assert!((roll_off(knee, shadows, highlights) - knee).abs() < EPSILON);
+            // slope of 1 on both sides
+            assert!(((above - below) / (2.0 * EPSILON) - 1.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn roll_off_only_rises() {
+        let mut previous = 0.0;
+        for i in 1..=1000 {
+            let value = roll_off(i as f32 / 1000.0, 0.25, 0.5);
+            assert!(value >= previous);
+            previous = value;
+        }
+    }
+
+    #[test]
+    fn s_curve_keeps_endpoints_and_midpoint() {
+        for contrast in [-1.0, 0.0, 0.5, 2.0] {
+            assert_eq!(s_curve(0.0, contrast), 0.0);
+            assert_eq!(s_curve(0.5, contrast), 0.5);
+            assert_eq!(s_curve(1.0, contrast), 1.0);
+        }
+        assert!(s_curve(0.25, 1.0) < 0.25);
+        assert!(s_curve(0.75, 1.0) > 0.75);
+        assert!(s_curve(0.25, -1.0) > 0.25);
+    }
+
+    #[test]
+    fn monotone_tangents_are_flat_at_extrema() {
+        let tangents = monotone_tangents(&[[0.0, 0.0], [0.5, 1.0], [1.0, 0.0]]);
+        assert_eq!(tangents, vec![2.0, 0.0, -2.0]);
+
+        let tangents = monotone_tangents(&[[0.0, 0.0], [0.5, 0.5], [0.6, 0.5], [1.0, 1.0]]);
+        assert_eq!(tangents[1], 0.0);
+        assert_eq!(tangents[2], 0.0);
+    }
+
+    #[test]
+    fn curve_does_not_overshoot() {
+        let curve: Curve = "0,0/0.1,0.8/0.9,0.9/1,1".parse().unwrap();
+        let mut previous = 0.0;
+        for i in 0..=1000 {
+            let value = curve.eval(i as f32 / 1000.0);
+            assert!(value >= previous - EPSILON);
+            assert!(value <= 1.0);
+            previous = value;
+        }
+        assert_eq!(curve.eval(0.1), 0.8);
+    }
+
+    #[test]
+    fn curve_parsing() {
+        let curve: Curve = "1,1/0,0.1".parse().unwrap();
+        assert_eq!(curve.eval(0.0), 0.1);
+        assert_eq!(curve.eval(1.0), 1.0);
+
+        assert!("0,0".parse::<Curve>().is_err());
+        assert!("0,0/0,1".parse::<Curve>().is_err());
+        assert!("0,0/1,2".parse::<Curve>().is_err());
+        assert!("0,0/1".parse::<Curve>().is_err());
+    }
+}
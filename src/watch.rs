@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::io::{is_raw_input, read_dir_raw_files};
+
+/// How long the size of a new file has to stay the same before it's
+/// considered fully written, since tethering software may write it in chunks
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+/// How often files that are being written are checked
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Watches `dir` for RAW files that are added or replaced, and calls
+/// `on_file` with each of them once it's fully written. RAW files that are in
+/// `dir` already are passed first. Runs until watching fails.
+pub fn watch_raw_files(
+    dir: &str,
+    mut on_file: impl FnMut(&str),
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !Path::new(dir).is_dir() {
+        return Err(format!("Directory {} doesn't exist", dir).into());
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(Path::new(dir), RecursiveMode::NonRecursive)?;
+
+    // files that are being written, with their size and when it last changed.
+    // Files from before watching started may still be being written too.
+    let mut pending: HashMap<PathBuf, (u64, Instant)> = read_dir_raw_files(dir, false, &[], &[])?
+        .into_iter()
+        .map(|path| (path, (u64::MAX, Instant::now())))
+        .collect();
+    loop {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => {
+                let event = event?;
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        // every write restarts the wait
+                        pending.insert(path, (u64::MAX, Instant::now()));
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(format!("Stopped watching {}", dir).into());
+            }
+        }
+
+        let now = Instant::now();
+        let mut written = vec![];
+        pending.retain(|path, (size, changed)| {
+            // removed, renamed away, or not a RAW file
+            if !is_raw_input(path) {
+                return false;
+            }
+            let Ok(metadata) = fs::metadata(path) else {
+                return false;
+            };
+            if metadata.len() != *size {
+                *size = metadata.len();
+                *changed = now;
+                return true;
+            }
+            if metadata.len() == 0 || now.duration_since(*changed) < SETTLE_TIME {
+                return true;
+            }
+            written.push(path.clone());
+            false
+        });
+
+        written.sort();
+        for path in written {
+            on_file(
+                path.to_str()
+                    .expect("file path should be a valid UTF-8 sequence"),
+            );
+        }
+    }
+}